@@ -7,65 +7,29 @@ use tracing::warn;
 
 use common::defines::{APP_CONFIG_PATH, TEMP_DIR_IN};
 
-#[cfg(not(feature = "android-proot"))]
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-pub enum NspExtractor {
-    #[default]
-    Hactoolnet,
-    Hactool,
-}
-
-#[cfg(not(feature = "android-proot"))]
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-pub enum NcaExtractor {
-    #[default]
-    Hactoolnet,
-    Hac2l,
-}
-
-const SCHEMA_VERSION: u8 = 2;
+/// Not bumped for removed fields, serde ignores their keys.
+const SCHEMA_VERSION: u8 = 3;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     schema_version: u8,
-    #[cfg(not(feature = "android-proot"))]
-    pub nsp_extractor: NspExtractor,
-    #[cfg(not(feature = "android-proot"))]
-    pub nca_extractor: NcaExtractor,
     pub yanu_dir: Option<PathBuf>,
     pub temp_dir: PathBuf,
+    /// Check the contents of packages against their CNMTs before updating/unpacking them.
+    pub verify_packages: bool,
     #[cfg(unix)]
     pub hacpack_rev: String,
-    #[cfg(unix)]
-    pub hactool_rev: String,
-    #[cfg(unix)]
-    pub hac2l_rev: String,
-    #[cfg(unix)]
-    pub atmosphere_rev: String,
-    #[cfg(unix)]
-    pub four_nxci_rev: String,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             schema_version: SCHEMA_VERSION,
-            #[cfg(not(feature = "android-proot"))]
-            nsp_extractor: Default::default(),
-            #[cfg(not(feature = "android-proot"))]
-            nca_extractor: Default::default(),
             yanu_dir: Default::default(),
             temp_dir: TEMP_DIR_IN.to_owned(),
+            verify_packages: true,
             #[cfg(unix)]
             hacpack_rev: "7845e7be8d03a263c33430f9e8c2512f7c280c88".into(),
-            #[cfg(unix)]
-            hactool_rev: "c2c907430e674614223959f0377f5e71f9e44a4a".into(),
-            #[cfg(unix)]
-            hac2l_rev: "7fc1b3a32c6a870c47d7459b23fd7c7b63014186".into(),
-            #[cfg(unix)]
-            atmosphere_rev: "1afb184c143f4319e5d6d4ea27260e61830c42a0".into(),
-            #[cfg(unix)]
-            four_nxci_rev: "33044e650ad58a72d231d9793383f5f279b73884".into(),
         }
     }
 }
@@ -0,0 +1,105 @@
+//! Crypto primitives used by the Switch file formats.
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128,
+};
+
+pub type AesKey = [u8; 0x10];
+pub type XtsKey = [u8; 0x20];
+
+pub const AES_BLOCK_SIZE: usize = 0x10;
+
+/// AES-128-XTS with the Nintendo tweak, i.e. the sector index is in big-endian.
+#[derive(Clone)]
+pub struct AesXts {
+    data: Aes128,
+    tweak: Aes128,
+}
+
+impl AesXts {
+    pub fn new(key: &XtsKey) -> Self {
+        Self {
+            data: Aes128::new(GenericArray::from_slice(&key[..0x10])),
+            tweak: Aes128::new(GenericArray::from_slice(&key[0x10..])),
+        }
+    }
+    /// `buf` is processed in chunks of `sector_size`, starting from the `sector` index.
+    pub fn decrypt(&self, buf: &mut [u8], sector: u128, sector_size: usize) {
+        self.process(buf, sector, sector_size, false);
+    }
+    pub fn encrypt(&self, buf: &mut [u8], sector: u128, sector_size: usize) {
+        self.process(buf, sector, sector_size, true);
+    }
+    fn process(&self, buf: &mut [u8], sector: u128, sector_size: usize, encrypt: bool) {
+        for (i, chunk) in buf.chunks_mut(sector_size).enumerate() {
+            let mut tweak = GenericArray::from((sector + i as u128).to_be_bytes());
+            self.tweak.encrypt_block(&mut tweak);
+            for block in chunk.chunks_exact_mut(AES_BLOCK_SIZE) {
+                xor_in_place(block, &tweak);
+                let block = GenericArray::from_mut_slice(block);
+                if encrypt {
+                    self.data.encrypt_block(block);
+                } else {
+                    self.data.decrypt_block(block);
+                }
+                xor_in_place(block, &tweak);
+                gf128_mul_x(tweak.as_mut());
+            }
+        }
+    }
+}
+
+/// AES-128-CTR with a big-endian counter.
+#[derive(Clone)]
+pub struct AesCtr {
+    cipher: Aes128,
+}
+
+impl AesCtr {
+    pub fn new(key: &AesKey) -> Self {
+        Self {
+            cipher: Aes128::new(GenericArray::from_slice(key)),
+        }
+    }
+    /// Encrypts/decrypts `buf` in-place, `counter` is the counter of the first block.
+    pub fn apply(&self, buf: &mut [u8], counter: u128) {
+        for (i, chunk) in buf.chunks_mut(AES_BLOCK_SIZE).enumerate() {
+            let mut keystream = GenericArray::from(counter.wrapping_add(i as u128).to_be_bytes());
+            self.cipher.encrypt_block(&mut keystream);
+            xor_in_place(chunk, &keystream);
+        }
+    }
+}
+
+/// Decrypts `buf` in-place with AES-128-ECB, `buf` must be block aligned.
+pub fn aes_ecb_decrypt(key: &AesKey, buf: &mut [u8]) {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    for block in buf.chunks_exact_mut(AES_BLOCK_SIZE) {
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+}
+
+/// Encrypts `buf` in-place with AES-128-ECB, `buf` must be block aligned.
+pub fn aes_ecb_encrypt(key: &AesKey, buf: &mut [u8]) {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    for block in buf.chunks_exact_mut(AES_BLOCK_SIZE) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+}
+
+fn xor_in_place(buf: &mut [u8], other: &[u8]) {
+    buf.iter_mut().zip(other).for_each(|(a, b)| *a ^= b);
+}
+
+/// Multiplies the XTS tweak by `x` in GF(2^128).
+fn gf128_mul_x(tweak: &mut [u8]) {
+    let carry = tweak[0xf] >> 7;
+    for i in (1..0x10).rev() {
+        tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
+    }
+    tweak[0] <<= 1;
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
@@ -0,0 +1,276 @@
+//! https://switchbrew.org/wiki/Cryptosystem
+//!
+//! Keys parsed from a `prod.keys` file.
+
+use common::{defines::DEFAULT_PRODKEYS_PATH, utils::ext_matches};
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use rsa::{BigUint, RsaPrivateKey, RsaPublicKey};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::Path,
+};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+
+use crate::{
+    crypto::{aes_ecb_decrypt, AesKey, XtsKey},
+    vfs::ticket::TitleKey,
+};
+
+/// Max no. of key generations, i.e. `master_key_00` to `master_key_1f`.
+pub const KEY_GENERATION_COUNT: usize = 0x20;
+
+type KeyGenerations = [Option<AesKey>; KEY_GENERATION_COUNT];
+
+#[derive(Clone)]
+pub struct Keyset {
+    pub header_key: XtsKey,
+    pub key_area_key_application: KeyGenerations,
+    pub key_area_key_ocean: KeyGenerations,
+    pub key_area_key_system: KeyGenerations,
+    pub titlekek: KeyGenerations,
+    pub master_key: KeyGenerations,
+    /// Encrypted TitleKeys by their RightsID.
+    pub title_keys: HashMap<[u8; 0x10], AesKey>,
+    /// Console-unique key, needed for personalized tickets.
+    pub eticket_rsa_keypair: Option<RsaPrivateKey>,
+    /// Public keys of the NCA header signature, by the signature key generation.
+    pub nca_hdr_fixed_key_modulus: [Option<RsaPublicKey>; 2],
+}
+
+// Keys shouldn't end up in the logs
+impl fmt::Debug for Keyset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyset")
+            .field("highest_key_revision", &self.highest_key_revision())
+            .field(
+                "has_eticket_rsa_keypair",
+                &self.eticket_rsa_keypair.is_some(),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl Keyset {
+    pub fn try_new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        info!(keyfile = %path.as_ref().display(), "Reading keys");
+
+        let mut header_key = None;
+        let mut keyset = Self {
+            header_key: XtsKey::default(),
+            key_area_key_application: KeyGenerations::default(),
+            key_area_key_ocean: KeyGenerations::default(),
+            key_area_key_system: KeyGenerations::default(),
+            titlekek: KeyGenerations::default(),
+            master_key: KeyGenerations::default(),
+            title_keys: HashMap::new(),
+            eticket_rsa_keypair: None,
+            nca_hdr_fixed_key_modulus: Default::default(),
+        };
+        let mut malformed = vec![];
+        for (name, value) in parse_keyfile(&fs::read_to_string(path.as_ref())?) {
+            let res = match name.as_str() {
+                "header_key" => decode_key(&value).map(|key| header_key = Some(key)),
+                "eticket_rsa_keypair" => decode_key(&value)
+                    .and_then(|key| rsa_keypair(&key))
+                    .map(|key| keyset.eticket_rsa_keypair = Some(key)),
+                "nca_hdr_fixed_key_modulus" | "nca_hdr_fixed_key_modulus_00" => decode_key(&value)
+                    .and_then(|key| rsa_public_key(&key))
+                    .map(|key| keyset.nca_hdr_fixed_key_modulus[0] = Some(key)),
+                "nca_hdr_fixed_key_modulus_01" => decode_key(&value)
+                    .and_then(|key| rsa_public_key(&key))
+                    .map(|key| keyset.nca_hdr_fixed_key_modulus[1] = Some(key)),
+                _ => match keyset.generation_slot(&name) {
+                    Some(slot) => decode_key(&value).map(|key| *slot = Some(key)),
+                    None => Ok(()), // Not needed
+                },
+            };
+            match res {
+                // Only needed for personalized Tickets and deep verification
+                Err(err)
+                    if name == "eticket_rsa_keypair"
+                        || name.starts_with("nca_hdr_fixed_key_modulus") =>
+                {
+                    warn!(key = %name, %err, "Ignoring malformed optional key");
+                }
+                Err(err) => malformed.push(format!("'{}' ({})", name, err)),
+                Ok(()) => {}
+            }
+        }
+        if !malformed.is_empty() {
+            bail!(
+                "Malformed keys in '{}': {}",
+                path.as_ref().display(),
+                malformed.join(", ")
+            );
+        }
+        keyset.header_key = header_key
+            .ok_or_else(|| eyre!("'header_key' is missing in '{}'", path.as_ref().display()))?;
+
+        match keyset.highest_key_revision() {
+            Some(revision) => {
+                let missing = (0..revision)
+                    .filter(|&revision| !keyset.has_key_revision(revision))
+                    .map(|revision| format!("{:02x}", revision))
+                    .collect::<Vec<_>>();
+                if !missing.is_empty() {
+                    warn!(?missing, "Some key generations are incomplete");
+                }
+                info!(highest_key_revision = %format!("{:#04x}", revision), "Parsed keys");
+            }
+            None => warn!("No key generations found, only NCA headers can be decrypted"),
+        }
+        debug!(?keyset);
+
+        Ok(keyset)
+    }
+    /// Reads the keys from [`DEFAULT_PRODKEYS_PATH`].
+    pub fn from_default_path() -> Result<Self> {
+        if !DEFAULT_PRODKEYS_PATH.is_file() {
+            bail!(
+                "Failed to find keyfile at '{}', import a 'prod.keys' keyfile first",
+                DEFAULT_PRODKEYS_PATH.display()
+            );
+        }
+        Self::try_new(DEFAULT_PRODKEYS_PATH.as_path())
+    }
+    /// Whether the keys needed to decrypt NCAs of the master key `revision` are present.
+    pub fn has_key_revision(&self, revision: u8) -> bool {
+        let revision = revision as usize;
+        revision < KEY_GENERATION_COUNT
+            && self.key_area_key_application[revision].is_some()
+            && self.titlekek[revision].is_some()
+    }
+    /// Highest master key revision that can be decrypted.
+    pub fn highest_key_revision(&self) -> Option<u8> {
+        (0..KEY_GENERATION_COUNT as u8)
+            .rev()
+            .find(|&revision| self.has_key_revision(revision))
+    }
+    /// `index` is the key area encryption key index in the NCA header.
+    pub fn key_area_key(&self, index: u8, revision: u8) -> Result<AesKey> {
+        let (name, keys) = match index {
+            0x00 => ("key_area_key_application", &self.key_area_key_application),
+            0x01 => ("key_area_key_ocean", &self.key_area_key_ocean),
+            0x02 => ("key_area_key_system", &self.key_area_key_system),
+            _ => bail!("Unknown key area encryption key index {:#x}", index),
+        };
+        get_key(name, keys, revision)
+    }
+    pub fn titlekek(&self, revision: u8) -> Result<AesKey> {
+        get_key("titlekek", &self.titlekek, revision)
+    }
+    pub fn add_title_key(&mut self, title_key: &TitleKey) {
+        self.title_keys
+            .insert(title_key.rights_id, title_key.title_key);
+    }
+    /// Adds the TitleKeys of all the Tickets under `dir`, one per RightsID.
+    pub fn add_title_keys_from_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
+        for entry in WalkDir::new(dir.as_ref())
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| ext_matches(e.path(), "tik"))
+        {
+            let title_key = TitleKey::try_new(self, entry.path())?;
+            self.add_title_key(&title_key);
+        }
+        Ok(())
+    }
+    /// Fails with a helpful message if the keys of master key `revision` are missing.
+    pub fn ensure_key_revision(&self, revision: u8) -> Result<()> {
+        if !self.has_key_revision(revision) {
+            bail!(
+                "This title needs master key {:#04x} which your keyfile lacks{}, \
+                it needs to be dumped from a newer firmware",
+                revision,
+                self.highest_key_revision()
+                    .map(|highest| format!(" (highest is {:#04x})", highest))
+                    .unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+    /// Decrypts the TitleKey of `rights_id` with the titlekek of its key generation.
+    pub fn title_key(&self, rights_id: &[u8; 0x10]) -> Result<AesKey> {
+        let revision = rights_id_key_revision(rights_id);
+        let mut title_key = *self.title_keys.get(rights_id).ok_or_else(|| {
+            eyre!(
+                "TitleKey for RightsID '{}' is missing, a Ticket is needed",
+                hex::encode(rights_id)
+            )
+        })?;
+        aes_ecb_decrypt(&self.titlekek(revision)?, &mut title_key);
+        Ok(title_key)
+    }
+    fn generation_slot(&mut self, name: &str) -> Option<&mut Option<AesKey>> {
+        let (prefix, revision) = name.rsplit_once('_')?;
+        if revision.len() != 2 {
+            return None;
+        }
+        let revision = u8::from_str_radix(revision, 16).ok()? as usize;
+        let keys = match prefix {
+            "key_area_key_application" => &mut self.key_area_key_application,
+            "key_area_key_ocean" => &mut self.key_area_key_ocean,
+            "key_area_key_system" => &mut self.key_area_key_system,
+            "titlekek" => &mut self.titlekek,
+            "master_key" => &mut self.master_key,
+            _ => return None,
+        };
+        keys.get_mut(revision)
+    }
+}
+
+/// Master key revision of a RightsID, its last byte is the key generation.
+pub fn rights_id_key_revision(rights_id: &[u8; 0x10]) -> u8 {
+    rights_id[0xF].saturating_sub(1)
+}
+
+fn get_key(name: &str, keys: &KeyGenerations, revision: u8) -> Result<AesKey> {
+    keys.get(revision as usize)
+        .copied()
+        .flatten()
+        .ok_or_else(|| {
+            eyre!(
+                "'{}_{:02x}' is missing, the keyfile needs to be dumped from a newer firmware",
+                name,
+                revision
+            )
+        })
+}
+
+/// Private exponent (0x100), modulus (0x100) and public exponent (0x4, big-endian),
+/// followed by 0xC bytes of padding.
+fn rsa_keypair(key: &[u8; 0x210]) -> Result<RsaPrivateKey> {
+    Ok(RsaPrivateKey::from_components(
+        BigUint::from_bytes_be(&key[0x100..0x200]),
+        BigUint::from_bytes_be(&key[0x200..0x204]),
+        BigUint::from_bytes_be(&key[..0x100]),
+        vec![],
+    )?)
+}
+
+/// Modulus (0x100) with the usual public exponent of 65537.
+fn rsa_public_key(modulus: &[u8; 0x100]) -> Result<RsaPublicKey> {
+    Ok(RsaPublicKey::new(
+        BigUint::from_bytes_be(modulus),
+        BigUint::from(65537u32),
+    )?)
+}
+
+fn decode_key<const N: usize>(value: &str) -> Result<[u8; N]> {
+    let mut key = [0u8; N];
+    hex::decode_to_slice(value, &mut key)?;
+    Ok(key)
+}
+
+/// Returns the `name = value` pairs, with the names lowercased.
+fn parse_keyfile(content: &str) -> BTreeMap<String, String> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_owned()))
+        .collect()
+}
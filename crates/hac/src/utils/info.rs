@@ -0,0 +1,227 @@
+use std::{
+    fmt,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use common::utils::ext_matches;
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    keys::Keyset,
+    vfs::{
+        cnmt::{Cnmt, ContentMetaType},
+        nacp::NacpData,
+        nca::{ContentType, Nca, NcaHeader},
+        nsp::{extract_entry, Nsp},
+        pfs0::Pfs0Entry,
+        storage::SubStorage,
+        ticket::{Ticket, TitleKeyType},
+        xci::Xci,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PackageKind {
+    Nsp,
+    Nsz,
+    Xci,
+    Xcz,
+}
+
+impl fmt::Display for PackageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            PackageKind::Nsp => "NSP",
+            PackageKind::Nsz => "NSZ",
+            PackageKind::Xci => "XCI",
+            PackageKind::Xcz => "XCZ",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+/// An NCA (or NCZ) of a package, as described by its header.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContentInfo {
+    pub name: String,
+    pub content_type: ContentType,
+    pub title_id: String,
+    pub key_generation: u8,
+    /// Size of the NCA, NCZs are smaller than this.
+    pub size: u64,
+    /// Set if the NCA uses titlekey crypto.
+    pub rights_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TicketInfo {
+    pub name: String,
+    pub rights_id: String,
+    pub key_generation: u8,
+    pub title_key_type: TitleKeyType,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MetaInfo {
+    pub meta_type: ContentMetaType,
+    pub title_id: String,
+    pub version: u32,
+    pub application_id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NacpInfo {
+    pub name: String,
+    pub publisher: String,
+    pub display_version: String,
+}
+
+/// What's inside a NSP/NSZ/XCI/XCZ package, read by [`PackageInfo::read`].
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageInfo {
+    pub path: PathBuf,
+    pub kind: PackageKind,
+    pub size: u64,
+    pub contents: Vec<ContentInfo>,
+    pub tickets: Vec<TicketInfo>,
+    pub metas: Vec<MetaInfo>,
+    /// From the main Control NCA, DLCs don't have one.
+    pub nacp: Option<NacpInfo>,
+}
+
+impl PackageInfo {
+    /// Reads the NCA headers and Tickets of the package at `path`, only the Meta
+    /// and main Control NCAs get extracted.
+    ///
+    /// The CNMTs and the NACP are left out with a warning if they can't be read.
+    pub fn read<P, Q>(path: P, keyset: &Keyset, tempdir_in: Q) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        info!(package = %path.display(), "Reading package info");
+        let (kind, entries) = if ext_matches(path, "nsp") || ext_matches(path, "nsz") {
+            let nsp = Nsp::try_new(path)?;
+            let kind = if nsp.is_compressed() {
+                PackageKind::Nsz
+            } else {
+                PackageKind::Nsp
+            };
+            (kind, nsp.entries()?)
+        } else if ext_matches(path, "xci") || ext_matches(path, "xcz") {
+            let xci = Xci::try_new(path)?;
+            let kind = if ext_matches(path, "xcz") {
+                PackageKind::Xcz
+            } else {
+                PackageKind::Xci
+            };
+            (kind, xci.secure_entries()?)
+        } else {
+            bail!("'{}' is not a NSP/NSZ/XCI/XCZ file", path.display());
+        };
+
+        let mut reader = BufReader::new(fs::File::open(path)?);
+        let size = fs::metadata(path)?.len();
+        let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+
+        let mut keyset = keyset.clone();
+        let mut tickets = vec![];
+        for entry in entries.iter().filter(|entry| entry.name.ends_with(".tik")) {
+            let ticket =
+                Ticket::from_reader(&mut SubStorage::new(&mut reader, entry.offset, entry.size))
+                    .map_err(|err| eyre!("Failed to read '{}': {}", entry.name, err))?;
+            // Only needed for the Control NCA, personalized ones might not be decryptable
+            match ticket.title_key(&keyset) {
+                Ok(title_key) => keyset.add_title_key(&title_key),
+                Err(err) => warn!(ticket = %entry.name, %err, "Failed to get the TitleKey"),
+            }
+            tickets.push(TicketInfo {
+                name: entry.name.clone(),
+                rights_id: hex::encode(ticket.rights_id),
+                key_generation: ticket.key_generation,
+                title_key_type: ticket.title_key_type,
+            });
+        }
+
+        let mut contents = vec![];
+        let mut metas = vec![];
+        let mut nacp = None;
+        // NCZs keep the NCA header as is
+        for entry in entries
+            .iter()
+            .filter(|entry| entry.name.ends_with(".nca") || entry.name.ends_with(".ncz"))
+        {
+            let header = NcaHeader::from_reader(
+                &keyset,
+                &mut SubStorage::new(&mut reader, entry.offset, entry.size),
+            )
+            .map_err(|err| eyre!("Failed to read header of '{}': {}", entry.name, err))?;
+            match header.content_type {
+                ContentType::Meta => {
+                    match read_entry_nca(&mut reader, entry, &keyset, temp_dir.path())
+                        .and_then(|meta| Cnmt::from_nca(&keyset, &meta))
+                    {
+                        Ok(cnmt) => metas.push(MetaInfo {
+                            meta_type: cnmt.meta_type,
+                            title_id: cnmt.get_title_id(),
+                            version: cnmt.version,
+                            application_id: format!("{:016x}", cnmt.application_id()),
+                        }),
+                        Err(err) => warn!(nca = %entry.name, %err, "Failed to read the CNMT"),
+                    }
+                }
+                ContentType::Control if nacp.is_none() && header.id_offset() == 0 => {
+                    match read_entry_nca(&mut reader, entry, &keyset, temp_dir.path())
+                        .and_then(|control| NacpData::from_nca(&keyset, &control))
+                    {
+                        Ok(data) => {
+                            nacp = Some(NacpInfo {
+                                name: data.get_application_name(),
+                                publisher: data.get_application_publisher(),
+                                display_version: data.get_application_version(),
+                            })
+                        }
+                        Err(err) => warn!(nca = %entry.name, %err, "Failed to read the NACP"),
+                    }
+                }
+                _ => {}
+            }
+            contents.push(ContentInfo {
+                name: entry.name.clone(),
+                content_type: header.content_type,
+                title_id: hex::encode(header.program_id),
+                key_generation: header.key_generation,
+                size: header.content_size,
+                rights_id: header
+                    .has_rights_id()
+                    .then(|| hex::encode(header.rights_id)),
+            });
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            kind,
+            size,
+            contents,
+            tickets,
+            metas,
+            nacp,
+        })
+    }
+}
+
+/// Extracts `entry` to `to` and opens it as an NCA.
+fn read_entry_nca(
+    reader: &mut BufReader<fs::File>,
+    entry: &Pfs0Entry,
+    keyset: &Keyset,
+    to: &Path,
+) -> Result<Nca> {
+    let nca_path = extract_entry(reader, entry, to)?;
+    Nca::try_new(keyset, nca_path)
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use common::utils::ext_matches;
+use eyre::{eyre, Result};
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+
+use crate::{
+    keys::Keyset,
+    vfs::{cnmt::ContentMetaType, nsp::Nsp, xci::Xci},
+};
+
+const SCHEMA_VERSION: u8 = 1;
+
+/// A package identified by [`Library::scan`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LibraryEntry {
+    pub path: PathBuf,
+    pub meta_type: ContentMetaType,
+    pub title_id: String,
+    /// TitleID of the application this belongs to, same as `title_id` for applications.
+    pub application_id: String,
+    pub version: u32,
+    /// Application name from the NACP, DLCs don't have one.
+    pub name: Option<String>,
+    /// Version shown to users from the NACP, i.e. "1.0.2".
+    pub display_version: Option<String>,
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch, a package is only identified again if this or
+    /// its size changes.
+    pub mtime: u64,
+}
+
+/// Counts of what a [`Library::scan`] did, along with the packages that couldn't be identified.
+#[derive(Debug, Default, Clone)]
+pub struct ScanSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Index of the packages in a directory, stored as JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Library {
+    schema_version: u8,
+    pub dir: Option<PathBuf>,
+    /// Sorted by path.
+    pub entries: Vec<LibraryEntry>,
+}
+
+impl Default for Library {
+    fn default() -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            dir: None,
+            entries: vec![],
+        }
+    }
+}
+
+impl Library {
+    /// Loads the index at `path`, an empty one is returned if it doesn't exist or is outdated.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        if !path.as_ref().is_file() {
+            return Ok(Self::default());
+        }
+        match serde_json::from_slice::<Self>(&fs::read(path.as_ref())?) {
+            Ok(library) if library.schema_version == SCHEMA_VERSION => Ok(library),
+            Ok(_) => {
+                warn!("Schema version doesn't match! Rebuilding library index...");
+                Ok(Self::default())
+            }
+            Err(err) => {
+                warn!(%err, "Bad library index! Rebuilding it...");
+                Ok(Self::default())
+            }
+        }
+    }
+    pub fn store<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        if let Some(parent) = path.as_ref().parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path.as_ref(), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+    /// Walks `dir` for NSP/NSZ/XCI/XCZ packages and identifies the new or changed ones,
+    /// the rest are kept as is.
+    ///
+    /// Entries of packages no longer in `dir` are dropped, so scanning another dir
+    /// replaces the index.
+    pub fn scan<P, Q>(&mut self, dir: P, keyset: &Keyset, tempdir_in: Q) -> Result<ScanSummary>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        info!(dir = %dir.as_ref().display(), "Scanning library");
+        let dir = dir.as_ref().canonicalize()?;
+        let mut known = self
+            .entries
+            .drain(..)
+            .map(|entry| (entry.path.clone(), entry))
+            .collect::<HashMap<_, _>>();
+        let mut summary = ScanSummary::default();
+
+        for path in WalkDir::new(&dir)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .filter(|path| {
+                ["nsp", "nsz", "xci", "xcz"]
+                    .iter()
+                    .any(|ext| ext_matches(path, ext))
+            })
+        {
+            let (size, mtime) = match file_stamp(&path) {
+                Ok(stamp) => stamp,
+                Err(err) => {
+                    summary.failed.push((path, err.to_string()));
+                    continue;
+                }
+            };
+            let previous = known.remove(&path);
+            if let Some(entry) = previous
+                .as_ref()
+                .filter(|entry| entry.size == size && entry.mtime == mtime)
+            {
+                debug!(package = %path.display(), "Unchanged");
+                summary.unchanged += 1;
+                self.entries.push(entry.clone());
+                continue;
+            }
+            match identify(&path, size, mtime, keyset, tempdir_in.as_ref()) {
+                Ok(entry) => {
+                    info!(package = %path.display(), title_id = %entry.title_id, "Identified");
+                    if previous.is_some() {
+                        summary.updated += 1;
+                    } else {
+                        summary.added += 1;
+                    }
+                    self.entries.push(entry);
+                }
+                Err(err) => {
+                    warn!(package = %path.display(), %err, "Failed to identify");
+                    summary.failed.push((path, err.to_string()));
+                }
+            }
+        }
+        summary.removed = known.len();
+
+        self.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        self.dir = Some(dir);
+        info!(?summary, "Scanned library");
+        Ok(summary)
+    }
+}
+
+impl LibraryEntry {
+    /// Display version if there's one, the CNMT version otherwise.
+    pub fn version_label(&self) -> String {
+        match &self.display_version {
+            Some(display_version) if !display_version.is_empty() => {
+                format!("v{}", display_version)
+            }
+            _ => format!("v{}", self.version),
+        }
+    }
+}
+
+/// A base package along with the updates and DLCs of its application.
+#[derive(Debug, Clone)]
+pub struct ApplicationGroup {
+    pub application_id: String,
+    pub base: LibraryEntry,
+    /// Sorted by version, the newest one being the last.
+    pub updates: Vec<LibraryEntry>,
+    pub dlcs: Vec<LibraryEntry>,
+}
+
+impl ApplicationGroup {
+    pub fn newest_update(&self) -> Option<&LibraryEntry> {
+        self.updates.last()
+    }
+    /// Name from the base, falls back to the updates and then to the application id.
+    pub fn name(&self) -> &str {
+        std::iter::once(&self.base)
+            .chain(self.updates.iter().rev())
+            .filter_map(|entry| entry.name.as_deref())
+            .find(|name| !name.is_empty())
+            .unwrap_or(&self.application_id)
+    }
+}
+
+/// i.e. "Game Name v1.0.0 → v1.3.2"
+impl fmt::Display for ApplicationGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.name(), self.base.version_label())?;
+        if let Some(update) = self.newest_update() {
+            write!(f, " → {}", update.version_label())?;
+        }
+        if !self.dlcs.is_empty() {
+            write!(f, " (+{} DLC)", self.dlcs.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Groups `entries` by their application, ordered by name.
+///
+/// Updates and DLCs without a base are left out, as are the duplicate bases.
+/// Only NSP/NSZ packages are grouped since XCI/XCZs need converting before they can be updated.
+pub fn group_by_application(entries: &[LibraryEntry]) -> Vec<ApplicationGroup> {
+    let entries = entries
+        .iter()
+        .filter(|entry| {
+            let is_nsp = ext_matches(&entry.path, "nsp") || ext_matches(&entry.path, "nsz");
+            if !is_nsp {
+                debug!(package = %entry.path.display(), "Skipping gamecard image");
+            }
+            is_nsp
+        })
+        .collect::<Vec<_>>();
+    let mut groups = BTreeMap::new();
+    for entry in entries
+        .iter()
+        .copied()
+        .filter(|entry| entry.meta_type == ContentMetaType::Application)
+    {
+        if groups.contains_key(&entry.application_id) {
+            debug!(package = %entry.path.display(), "Skipping duplicate base");
+            continue;
+        }
+        groups.insert(
+            entry.application_id.clone(),
+            ApplicationGroup {
+                application_id: entry.application_id.clone(),
+                base: entry.clone(),
+                updates: vec![],
+                dlcs: vec![],
+            },
+        );
+    }
+    for entry in entries {
+        if let Some(group) = groups.get_mut(&entry.application_id) {
+            match entry.meta_type {
+                ContentMetaType::Patch => group.updates.push(entry.clone()),
+                ContentMetaType::AddOnContent => group.dlcs.push(entry.clone()),
+                _ => {}
+            }
+        }
+    }
+
+    let mut groups = groups.into_values().collect::<Vec<_>>();
+    for group in &mut groups {
+        group.updates.sort_by_key(|update| update.version);
+    }
+    groups.sort_by(|a, b| a.name().cmp(b.name()));
+    groups
+}
+
+/// Size and modification time of the file at `path`.
+fn file_stamp(path: &Path) -> Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+    Ok((metadata.len(), mtime as u64))
+}
+
+/// Gamecard images can hold several titles, the application is preferred over its
+/// updates and DLCs.
+fn identify(
+    path: &Path,
+    size: u64,
+    mtime: u64,
+    keyset: &Keyset,
+    tempdir_in: &Path,
+) -> Result<LibraryEntry> {
+    let is_xci = ext_matches(path, "xci") || ext_matches(path, "xcz");
+    let cnmts = if is_xci {
+        Xci::try_new(path)?.cnmts(keyset, tempdir_in)?
+    } else {
+        Nsp::try_new(path)?.cnmts(keyset, tempdir_in)?
+    };
+    let cnmt = cnmts
+        .into_iter()
+        .filter_map(|cnmt| {
+            let rank = match cnmt.meta_type {
+                ContentMetaType::Application => 0,
+                ContentMetaType::Patch => 1,
+                ContentMetaType::AddOnContent => 2,
+                _ => return None,
+            };
+            Some((rank, cnmt))
+        })
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, cnmt)| cnmt)
+        .ok_or_else(|| eyre!("No Application, Patch or AddOnContent CNMT in it"))?;
+    let nacp = match cnmt.meta_type {
+        ContentMetaType::AddOnContent => None,
+        _ => {
+            let nacp = if is_xci {
+                Xci::try_new(path).and_then(|xci| xci.nacp(keyset, tempdir_in))
+            } else {
+                Nsp::try_new(path).and_then(|nsp| nsp.nacp(keyset, tempdir_in))
+            };
+            match nacp {
+                Ok(nacp) => Some(nacp),
+                Err(err) => {
+                    warn!(package = %path.display(), %err, "Failed to read the NACP");
+                    None
+                }
+            }
+        }
+    };
+
+    Ok(LibraryEntry {
+        path: path.to_owned(),
+        meta_type: cnmt.meta_type,
+        title_id: cnmt.get_title_id(),
+        application_id: format!("{:016x}", cnmt.application_id()),
+        version: cnmt.version,
+        name: nacp.as_ref().map(|nacp| nacp.get_application_name()),
+        display_version: nacp.as_ref().map(|nacp| nacp.get_application_version()),
+        size,
+        mtime,
+    })
+}
@@ -1,48 +1,16 @@
+pub mod info;
+pub mod library;
 pub mod pack;
 pub mod unpack;
 pub mod update;
 
-use crate::vfs::{nacp::NacpData, ticket::TitleKey};
-use common::{
-    defines::{DEFAULT_TITLEKEYS_PATH, SWITCH_DIR},
-    error::MultiReport,
-    utils::move_file,
-};
+use crate::vfs::nacp::NacpData;
+use common::{error::MultiReport, utils::move_file};
 use eyre::{bail, eyre, Result};
 use fs_err as fs;
-use std::{
-    io::{self, ErrorKind},
-    path::PathBuf,
-};
+use std::{io, path::PathBuf};
 use tracing::{info, warn};
 
-pub fn clear_titlekeys() -> Result<()> {
-    match fs::remove_file(DEFAULT_TITLEKEYS_PATH.as_path()) {
-        Ok(_) => Ok(()),
-        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
-        Err(err) => {
-            bail!(err)
-        }
-    }
-}
-
-/// Store TitleKeys to `DEFAULT_TITLEKEYS_PATH`.
-pub fn store_titlekeys<'a, I>(keys: I) -> Result<()>
-where
-    I: Iterator<Item = &'a TitleKey>,
-{
-    info!(keyfile = ?SWITCH_DIR, "Storing TitleKeys");
-    fs::create_dir_all(SWITCH_DIR.as_path())?;
-    fs::write(
-        DEFAULT_TITLEKEYS_PATH.as_path(),
-        keys.map(|key| key.to_string())
-            .collect::<Vec<_>>()
-            .join("\n")
-            + "\n",
-    )
-    .map_err(|err| eyre!(err))
-}
-
 #[derive(Debug, Default, Clone)]
 pub struct CleanupDirsOnDrop {
     dirs: Vec<PathBuf>,
@@ -105,11 +73,16 @@ pub fn formatted_nsp_rename(
     program_id: &str,
     suffix: &str,
 ) -> Result<()> {
+    // Keeping the extension, as it could be a NSZ
+    let ext = nsp_path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_string())
+        .unwrap_or_else(|| "nsp".into());
     let dest = nsp_path
         .parent()
         .ok_or_else(|| eyre!("Failed to get parent"))?
         .join(format!(
-            "{} [{}][v{}]{suffix}.nsp",
+            "{} [{}][v{}]{suffix}.{ext}",
             nacp_data.get_application_name(),
             program_id,
             nacp_data.get_application_version()
@@ -7,22 +7,26 @@ use tracing::debug;
 
 use crate::{
     backend::{Backend, BackendKind},
+    keys::Keyset,
     utils::hacpack_cleanup_install,
     vfs::{
-        nacp::{get_nacp_file, NacpData},
+        cnmt::{Cnmt, CnmtContentType},
+        nacp::NacpData,
         nca::{self, Nca},
+        ncz::NczOptions,
         nsp::Nsp,
-        PROGRAMID_LEN,
+        parse_program_id, PROGRAMID_LEN,
     },
 };
 
-/// Pack romfs/exefs back to NSP.
+/// Pack romfs/exefs back to NSP, or NSZ if `compression` is given.
 pub fn pack_fs_data<N, E, R, O>(
     control_path: N,
     mut program_id: String,
     romfs_dir: R,
     exefs_dir: E,
     outdir: O,
+    compression: Option<&NczOptions>,
     cfg: &Config,
 ) -> Result<(Nsp, NacpData)>
 where
@@ -34,28 +38,13 @@ where
     let curr_dir = std::env::current_dir()?;
     let _hacpack_cleanup_bind = hacpack_cleanup_install!(curr_dir);
 
-    #[cfg(all(
-        target_arch = "x86_64",
-        any(target_os = "windows", target_os = "linux")
-    ))]
-    let readers = vec![
-        Backend::try_new(BackendKind::Hactoolnet)?,
-        Backend::try_new(BackendKind::Hac2l)?,
-    ];
-    #[cfg(feature = "android-proot")]
-    let readers = vec![Backend::try_new(BackendKind::Hac2l)?];
-    #[cfg(not(feature = "android-proot"))]
-    let nca_extractor = Backend::try_new(BackendKind::from(cfg.nca_extractor))?;
-    #[cfg(feature = "android-proot")]
-    let nca_extractor = Backend::try_new(BackendKind::Hac2l)?;
+    let keyset = Keyset::from_default_path()?;
     let packer = Backend::try_new(BackendKind::Hacpack)?;
 
     // Validating NCA as Control Type
-    let control_nca = readers
-        .iter()
-        .map(|reader| Nca::try_new(reader, control_path.as_ref()).ok())
-        .find(|nca| matches!(nca, Some(nca) if nca.content_type == nca::ContentType::Control))
-        .flatten()
+    let control_nca = Nca::try_new(&keyset, control_path.as_ref())
+        .ok()
+        .filter(|nca| nca.content_type == nca::ContentType::Control)
         .ok_or_else(|| {
             eyre!(
                 "'{}' is not a Control Type NCA",
@@ -67,18 +56,13 @@ where
     debug!(?program_id, "Selected ProgramID for packing");
 
     // Getting Nacp data
-    let control_romfs_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
-    control_nca.unpack_romfs(&nca_extractor, control_romfs_dir.path())?;
-    let nacp_data =
-        NacpData::try_new(get_nacp_file(control_romfs_dir.path()).ok_or_else(|| {
-            eyre!("Couldn't find NACP file, should be due to improper extraction")
-        })?)?;
+    let nacp_data = NacpData::from_nca(&keyset, &control_nca)?;
 
     let temp_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
 
     // !Packing fs files to NCA
     let patched_nca = Nca::pack_program(
-        readers.iter(),
+        &keyset,
         &packer,
         &program_id,
         DEFAULT_PRODKEYS_PATH.as_path(),
@@ -88,15 +72,10 @@ where
     )?;
 
     // !Generating Meta NCA
-    Nca::create_meta(
-        &packer,
-        &program_id,
-        DEFAULT_PRODKEYS_PATH.as_path(),
-        &patched_nca,
-        &control_nca,
-        temp_dir.path(),
-        &cfg.temp_dir,
-    )?;
+    let mut cnmt = Cnmt::new_application(parse_program_id(&program_id)?, 0, 0);
+    cnmt.add_content(&patched_nca, CnmtContentType::Program, 0)?;
+    cnmt.add_content(&control_nca, CnmtContentType::Control, 0)?;
+    Nca::create_meta(&keyset, &cnmt, &patched_nca, temp_dir.path(), &cfg.temp_dir)?;
 
     // !Copying Control NCA
     let control_filename = control_nca
@@ -106,13 +85,16 @@ where
     fs::copy(&control_nca.path, temp_dir.path().join(control_filename))?;
 
     // !Packing NCAs to NSP
-    let packed_nsp = Nsp::pack(
-        &packer,
-        &program_id,
-        DEFAULT_PRODKEYS_PATH.as_path(),
-        temp_dir.path(),
-        outdir.as_ref(),
-    )?;
+    let packed_nsp = match compression {
+        Some(options) => Nsp::pack_nsz(
+            &keyset,
+            &program_id,
+            temp_dir.path(),
+            outdir.as_ref(),
+            options,
+        )?,
+        None => Nsp::pack(&program_id, temp_dir.path(), outdir.as_ref())?,
+    };
 
     Ok((packed_nsp, nacp_data))
 }
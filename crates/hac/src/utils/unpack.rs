@@ -1,45 +1,33 @@
 use crate::{
-    backend::{Backend, BackendKind},
-    utils::{clear_titlekeys, store_titlekeys},
+    keys::Keyset,
     vfs::{
-        nca::{self, nca_with_kind},
+        nca::{self, nca_with_kind, ncas_by_id_offset},
         nsp::Nsp,
     },
 };
 use config::Config;
 use eyre::{eyre, Result};
 use std::path::{Path, PathBuf};
-use tracing::{debug, info, warn};
+use tracing::{debug, info};
 
 /// It corresponds to **(ProgramID, BaseUnpacked, UpdateUnpacked, MainRomFS, MainExeFS)**.
 type UnpackedNSPData = (String, PathBuf, PathBuf, PathBuf, PathBuf);
 
 /// Unpack NSPs to RomFS/ExeFS.\
 /// **Note:** Whether `BaseUnpacked` path is valid depends on the `update` value.
+///
+/// `id_offset` selects the program of a multi-program application, 0 being the main one.
 pub fn unpack_nsp<O>(
     base: &mut Nsp,
     mut update: Option<&mut Nsp>,
+    id_offset: u8,
     outdir: O,
     cfg: &Config,
 ) -> Result<UnpackedNSPData>
 where
     O: AsRef<Path>,
 {
-    #[cfg(not(feature = "android-proot"))]
-    let readers = vec![
-        Backend::try_new(BackendKind::Hactoolnet)?,
-        Backend::try_new(BackendKind::Hac2l)?,
-    ];
-    #[cfg(feature = "android-proot")]
-    let readers = vec![Backend::try_new(BackendKind::Hac2l)?];
-    #[cfg(not(feature = "android-proot"))]
-    let nsp_extractor = Backend::try_new(BackendKind::from(cfg.nsp_extractor))?;
-    #[cfg(feature = "android-proot")]
-    let nsp_extractor = Backend::try_new(BackendKind::Hactool)?;
-    #[cfg(not(feature = "android-proot"))]
-    let nca_extractor = Backend::try_new(BackendKind::from(cfg.nca_extractor))?;
-    #[cfg(feature = "android-proot")]
-    let nca_extractor = Backend::try_new(BackendKind::Hac2l)?;
+    let mut keyset = Keyset::from_default_path()?;
 
     let base_data_dir = outdir.as_ref().join("basedata");
     let update_data_dir = outdir.as_ref().join("updatedata");
@@ -47,65 +35,78 @@ where
     let exefs_dir = outdir.as_ref().join("exefs");
 
     // Important to do before any sort of unpacking
-    // to avoid them being used when they were not supposed to
-    clear_titlekeys()?;
+    // to fail early if the keys are outdated
+    base.check_keys(&keyset)?;
+    if let Some(update) = update.as_deref() {
+        update.check_keys(&keyset)?;
+    }
+    // Same for damaged packages
+    if cfg.verify_packages {
+        for nsp in [Some(&*base), update.as_deref()].into_iter().flatten() {
+            nsp.ensure_intact(&keyset, &cfg.temp_dir)?;
+        }
+    }
 
     // !Extracting pfs0
-    base.unpack(&nsp_extractor, &base_data_dir)?;
+    base.unpack(&base_data_dir)?;
     // Setting TitleKeys
-    if let Err(err) = base.derive_title_key(&base_data_dir) {
-        warn!(?err);
-    }
+    base.derive_title_key(&keyset, &base_data_dir)?;
 
     // If update is also to be extracted
     if let Some(update) = update.as_deref_mut() {
         // !Extracting pfs0
-        update.unpack(&nsp_extractor, &update_data_dir)?;
+        update.unpack(&update_data_dir)?;
         // Setting TitleKeys
-        if let Err(err) = update.derive_title_key(&update_data_dir) {
-            warn!(?err);
-        }
+        update.derive_title_key(&keyset, &update_data_dir)?;
     }
 
-    // !Storing TitleKeys file
-    if let Some(update) = update.as_deref_mut() {
-        store_titlekeys(
-            [&base.title_key, &update.title_key]
-                .iter()
-                .filter_map(|key| key.as_ref()),
-        )?;
-    } else {
-        store_titlekeys([&base.title_key].iter().filter_map(|key| key.as_ref()))?;
+    for title_key in [Some(&*base), update.as_deref()]
+        .into_iter()
+        .flatten()
+        .filter_map(|nsp| nsp.title_key.as_ref())
+    {
+        keyset.add_title_key(title_key);
     }
 
     // !Getting Base NCA
-    let base_nca = readers
-        .iter()
-        .inspect(|reader| info!("Using {:?} as reader", reader.kind()))
-        .map(|reader| nca_with_kind(reader, &base_data_dir, nca::ContentType::Program))
-        .find(|filtered| filtered.is_some())
-        .flatten()
-        .ok_or_else(|| eyre!("Failed to find Base NCA in '{}'", base.path.display()))?
-        .remove(0);
+    let base_ncas = ncas_by_id_offset(
+        nca_with_kind(&keyset, &base_data_dir, nca::ContentType::Program)
+            .ok_or_else(|| eyre!("Failed to find Base NCA in '{}'", base.path.display()))?,
+    );
+    if base_ncas.len() > 1 {
+        info!(id_offsets = ?base_ncas.keys().collect::<Vec<_>>(), %id_offset, "Multi-program application");
+    }
+    let base_nca = base_ncas.get(&id_offset).ok_or_else(|| {
+        eyre!(
+            "Failed to find Base NCA of id offset {} in '{}', available ones are {:?}",
+            id_offset,
+            base.path.display(),
+            base_ncas.keys().collect::<Vec<_>>()
+        )
+    })?;
     debug!(?base_nca);
 
     if let Some(patch) = update.as_deref() {
         // !Getting Patch NCA
-        let patch_nca = readers
-            .iter()
-            .inspect(|reader| info!("Using {:?} as reader", reader.kind()))
-            .map(|reader| nca_with_kind(reader, &update_data_dir, nca::ContentType::Program))
-            .find(|filtered| filtered.is_some())
-            .flatten()
-            .ok_or_else(|| eyre!("Failed to find Patch NCA in '{}'", patch.path.display()))?
-            .remove(0);
+        let patch_nca = ncas_by_id_offset(
+            nca_with_kind(&keyset, &update_data_dir, nca::ContentType::Program)
+                .ok_or_else(|| eyre!("Failed to find Patch NCA in '{}'", patch.path.display()))?,
+        )
+        .remove(&id_offset)
+        .ok_or_else(|| {
+            eyre!(
+                "Failed to find Patch NCA of id offset {} in '{}'",
+                id_offset,
+                patch.path.display()
+            )
+        })?;
         debug!(?patch_nca);
 
         // !Unpacking FS files from NCAs
-        _ = base_nca.unpack_all(&nca_extractor, &patch_nca, &romfs_dir, &exefs_dir);
+        base_nca.unpack_all(&keyset, &patch_nca, &romfs_dir, &exefs_dir)?;
     } else {
         // !Unpacking FS files from NCAs
-        _ = base_nca.unpack_all(&nca_extractor, &base_nca, &romfs_dir, &exefs_dir);
+        base_nca.unpack_all(&keyset, base_nca, &romfs_dir, &exefs_dir)?;
     }
 
     Ok((
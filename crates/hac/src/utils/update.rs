@@ -1,29 +1,136 @@
 use std::{collections::HashSet, path::Path};
 
-use common::defines::DEFAULT_PRODKEYS_PATH;
+use common::{defines::DEFAULT_PRODKEYS_PATH, utils::ext_matches};
 use config::Config;
-use eyre::{eyre, Result};
+use eyre::{bail, eyre, Result};
 use fs_err as fs;
 use tracing::{debug, info, warn};
+use walkdir::WalkDir;
 
 use crate::{
     backend::{Backend, BackendKind},
-    utils::{clear_titlekeys, store_titlekeys},
+    keys::Keyset,
     vfs::{
-        nacp::{get_nacp_file, NacpData},
-        nca::{self, nca_with_filters, nca_with_kind, Nca},
+        cnmt::{Cnmt, CnmtContentType, ContentMetaType},
+        nacp::NacpData,
+        nca::{self, nca_with_filters, nca_with_kind, ncas_by_id_offset, Nca},
+        ncz::NczOptions,
         nsp::Nsp,
+        parse_program_id,
     },
 };
 
 use super::hacpack_cleanup_install;
 
+/// An update package that [`newest_update`] passed over.
+#[derive(Debug, Clone)]
+pub struct SkippedUpdate {
+    pub nsp: Nsp,
+    pub reason: String,
+}
+
+/// Picks the newest update of `base` out of `updates`, going by their CNMT version.
+///
+/// Packages that aren't updates of `base` are skipped along with the older ones.
+pub fn newest_update(
+    base: &Nsp,
+    updates: Vec<Nsp>,
+    cfg: &Config,
+) -> Result<(Nsp, Vec<SkippedUpdate>)> {
+    let keyset = Keyset::from_default_path()?;
+
+    let application_id = base
+        .cnmts(&keyset, &cfg.temp_dir)?
+        .into_iter()
+        .find(|cnmt| cnmt.meta_type == ContentMetaType::Application)
+        .map(|cnmt| cnmt.title_id)
+        .ok_or_else(|| {
+            eyre!(
+                "Failed to find Application CNMT in '{}'",
+                base.path.display()
+            )
+        })?;
+    info!(application_id = %format!("{:016x}", application_id), "Selecting newest update");
+
+    let mut skipped = vec![];
+    let mut candidates = vec![];
+    for update in updates {
+        let cnmts = match update.cnmts(&keyset, &cfg.temp_dir) {
+            Ok(cnmts) => cnmts,
+            Err(err) => {
+                warn!(update = %update.path.display(), %err, "Skipping update");
+                skipped.push(SkippedUpdate {
+                    nsp: update,
+                    reason: format!("Failed to read its CNMT: {}", err),
+                });
+                continue;
+            }
+        };
+        let reason = match cnmts
+            .iter()
+            .find(|cnmt| cnmt.meta_type == ContentMetaType::Patch)
+        {
+            Some(cnmt) if cnmt.is_patch_of(application_id) => {
+                candidates.push((cnmt.version, update));
+                continue;
+            }
+            Some(cnmt) => format!(
+                "It's an update of another title, its TitleID is {} while the base is {:016x}",
+                cnmt.get_title_id(),
+                application_id
+            ),
+            None => "It's not an update, it has no Patch CNMT".into(),
+        };
+        warn!(update = %update.path.display(), %reason, "Skipping update");
+        skipped.push(SkippedUpdate {
+            nsp: update,
+            reason,
+        });
+    }
+
+    // Stable sort, so the first one given wins among the same versions
+    candidates.sort_by_key(|(version, _)| std::cmp::Reverse(*version));
+    let mut candidates = candidates.into_iter();
+    let (version, newest) = candidates.next().ok_or_else(|| {
+        eyre!(
+            "None of the given packages are updates of '{}':\n{}",
+            base.path.display(),
+            skipped
+                .iter()
+                .map(|skipped| format!("- '{}': {}", skipped.nsp.path.display(), skipped.reason))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    })?;
+    for (other_version, update) in candidates {
+        let reason = if other_version == version {
+            format!("Same version (v{}) as the selected update", version)
+        } else {
+            format!("Older version (v{}) than v{}", other_version, version)
+        };
+        info!(update = %update.path.display(), %reason, "Skipping update");
+        skipped.push(SkippedUpdate {
+            nsp: update,
+            reason,
+        });
+    }
+    info!(update = %newest.path.display(), version, "Selected newest update");
+
+    Ok((newest, skipped))
+}
+
 /// Apply update NSP to the base NSP.
+///
+/// Each program of a multi-program application is patched, and packed with its id offset.
+/// Contents of the `dlcs` are carried over as is to the patched package,
+/// which is a NSZ if `compression` is given.
 pub fn update_nsp<O>(
     base: &mut Nsp,
     update: &mut Nsp,
+    dlcs: &mut [Nsp],
     program_id: Option<&str>,
     outdir: O,
+    compression: Option<&NczOptions>,
     cfg: &Config,
 ) -> Result<(Nsp, NacpData, String)>
 where
@@ -32,21 +139,7 @@ where
     let curr_dir = std::env::current_dir()?;
     let _hacpack_cleanup_bind = hacpack_cleanup_install!(curr_dir);
 
-    #[cfg(not(feature = "android-proot"))]
-    let readers = vec![
-        Backend::try_new(BackendKind::Hactoolnet)?,
-        Backend::try_new(BackendKind::Hac2l)?,
-    ];
-    #[cfg(feature = "android-proot")]
-    let readers = vec![Backend::try_new(BackendKind::Hac2l)?];
-    #[cfg(not(feature = "android-proot"))]
-    let nsp_extractor = Backend::try_new(BackendKind::from(cfg.nsp_extractor))?;
-    #[cfg(feature = "android-proot")]
-    let nsp_extractor = Backend::try_new(BackendKind::Hactool)?;
-    #[cfg(not(feature = "android-proot"))]
-    let nca_extractor = Backend::try_new(BackendKind::from(cfg.nca_extractor))?;
-    #[cfg(feature = "android-proot")]
-    let nca_extractor = Backend::try_new(BackendKind::Hac2l)?;
+    let mut keyset = Keyset::from_default_path()?;
     let packer = Backend::try_new(BackendKind::Hacpack)?;
 
     let base_data_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
@@ -54,83 +147,86 @@ where
     fs::create_dir_all(base_data_dir.path())?;
     fs::create_dir_all(update_data_dir.path())?;
 
-    clear_titlekeys()?;
+    // Failing early if the keys are outdated
+    base.check_keys(&keyset)?;
+    update.check_keys(&keyset)?;
+    for dlc in dlcs.iter() {
+        dlc.check_keys(&keyset)?;
+    }
+    // Same for damaged packages, rather than failing midway through
+    if cfg.verify_packages {
+        for nsp in [&*base, &*update].into_iter().chain(dlcs.iter()) {
+            nsp.ensure_intact(&keyset, &cfg.temp_dir)?;
+        }
+    }
 
     // !Extracting pfs0
-    base.unpack(&nsp_extractor, base_data_dir.path())?;
-    update.unpack(&nsp_extractor, update_data_dir.path())?;
+    base.unpack(base_data_dir.path())?;
+    update.unpack(update_data_dir.path())?;
 
     // Setting TitleKeys
-    if let Err(err) = base.derive_title_key(base_data_dir.path()) {
-        warn!(?err);
-    }
-    if let Err(err) = update.derive_title_key(update_data_dir.path()) {
-        warn!(?err);
+    base.derive_title_key(&keyset, base_data_dir.path())?;
+    update.derive_title_key(&keyset, update_data_dir.path())?;
+
+    for title_key in [&base.title_key, &update.title_key].into_iter().flatten() {
+        keyset.add_title_key(title_key);
     }
 
-    // !Storing TitleKeys file
-    store_titlekeys(
-        [&base.title_key, &update.title_key]
-            .iter()
-            .filter_map(|key| key.as_ref()),
-    )?;
-
-    // !Getting Base NCA
-    let base_nca = readers
-        .iter()
-        .inspect(|reader| info!("Using {:?} as reader", reader.kind()))
-        .map(|reader| nca_with_kind(reader, base_data_dir.path(), nca::ContentType::Program))
-        .find(|filtered| filtered.is_some())
-        .flatten()
-        .ok_or_else(|| eyre!("Failed to find Base NCA in '{}'", base.path.display()))?
-        .remove(0);
-    debug!(?base_nca);
-
-    // !Getting Update and Control NCA
+    // !Getting Base NCAs, one for each program of the application
+    let base_ncas = ncas_by_id_offset(
+        nca_with_kind(&keyset, base_data_dir.path(), nca::ContentType::Program)
+            .ok_or_else(|| eyre!("Failed to find Base NCA in '{}'", base.path.display()))?,
+    );
+    let base_nca = base_ncas
+        .get(&0)
+        .ok_or_else(|| eyre!("Failed to find main Base NCA in '{}'", base.path.display()))?;
+    debug!(?base_ncas);
+
+    // !Getting Update and Control NCAs
     let filters = HashSet::from([nca::ContentType::Program, nca::ContentType::Control]);
-    let mut filtered_ncas = readers
-        .iter()
-        .inspect(|reader| info!("Using {:?} as reader", reader.kind()))
-        .map(|reader| nca_with_filters(reader, update_data_dir.path(), &filters))
-        .find(|filtered| {
-            filters
-                .iter()
-                .map(|kind| filtered.get(kind))
-                .all(|ncas| ncas.is_some())
-        })
-        .ok_or_else(|| {
-            eyre!(
-                "Failed to find Update and/or Control NCA in '{}'",
-                update.path.display()
-            )
-        })?;
-    let update_nca = filtered_ncas
-        .remove(&nca::ContentType::Program)
-        .expect("Should be Some due the all() check")
-        .remove(0);
-    let mut control_nca = filtered_ncas
-        .remove(&nca::ContentType::Control)
-        .expect("Should be Some due the all() check")
-        .remove(0);
-    debug!(?update_nca);
-    debug!(?control_nca);
+    let mut filtered_ncas = nca_with_filters(
+        &keyset,
+        update_data_dir.path(),
+        &filters
+            .iter()
+            .copied()
+            .chain([nca::ContentType::Meta])
+            .collect(),
+    );
+    if !filters.iter().all(|kind| filtered_ncas.contains_key(kind)) {
+        bail!(
+            "Failed to find Update and/or Control NCA in '{}'",
+            update.path.display()
+        );
+    }
+    let mut update_ncas = ncas_by_id_offset(
+        filtered_ncas
+            .remove(&nca::ContentType::Program)
+            .expect("Should be Some due the all() check"),
+    );
+    let mut control_ncas = ncas_by_id_offset(
+        filtered_ncas
+            .remove(&nca::ContentType::Control)
+            .expect("Should be Some due the all() check"),
+    );
+    debug!(?update_ncas);
+    debug!(?control_ncas);
+    if !control_ncas.contains_key(&0) {
+        bail!(
+            "Failed to find main Control NCA in '{}'",
+            update.path.display()
+        );
+    }
+    let update_cnmt = match filtered_ncas.remove(&nca::ContentType::Meta) {
+        Some(meta_ncas) => Some(Cnmt::from_nca(&keyset, &meta_ncas[0])?),
+        None => {
+            warn!(update = %update.path.display(), "Update has no Meta NCA, version won't be carried over");
+            None
+        }
+    };
 
     // Getting Nacp data
-    let control_romfs_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
-    control_nca.unpack_romfs(&nca_extractor, control_romfs_dir.path())?;
-    let nacp_data =
-        NacpData::try_new(get_nacp_file(control_romfs_dir.path()).ok_or_else(|| {
-            eyre!("Couldn't find NACP file, should be due to improper extraction")
-        })?)?;
-    if let Err(err) = control_romfs_dir.close() {
-        warn!(%err);
-    }
-
-    let fs_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
-    let romfs_dir = fs_dir.path().join("romfs");
-    let exefs_dir = fs_dir.path().join("exefs");
-    // !Unpacking FS files from NCAs
-    _ = base_nca.unpack_all(&nca_extractor, &update_nca, &romfs_dir, &exefs_dir); // !Ignoring err
+    let nacp_data = NacpData::from_nca(&keyset, &control_ncas[&0])?;
 
     let program_id = match program_id {
         Some(program_id) => program_id.into(),
@@ -138,15 +234,103 @@ where
     };
     debug!(?program_id, "Selected TitleID for packing");
 
-    // !Moving Control NCA
     let nca_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
     fs::create_dir_all(nca_dir.path())?;
-    let control_nca_filename = control_nca
-        .path
-        .file_name()
-        .expect("File should've a filename");
-    fs::rename(&control_nca.path, nca_dir.path().join(control_nca_filename))?;
-    control_nca.path = nca_dir.path().join(control_nca_filename);
+
+    // !Patching each program of the application
+    let mut patched_ncas = vec![];
+    for (&id_offset, base_nca) in &base_ncas {
+        let update_nca = match update_ncas.remove(&id_offset) {
+            Some(update_nca) => update_nca,
+            None => {
+                warn!(
+                    id_offset,
+                    "Update has no Program NCA for this program, repacking the base as is"
+                );
+                base_nca.clone()
+            }
+        };
+        let offset_program_id =
+            format!("{:016x}", parse_program_id(&program_id)? + id_offset as u64);
+        info!(id_offset, program_id = %offset_program_id, "Patching program");
+
+        let fs_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
+        let romfs_dir = fs_dir.path().join("romfs");
+        let exefs_dir = fs_dir.path().join("exefs");
+        // !Unpacking FS files from NCAs
+        base_nca.unpack_all(&keyset, &update_nca, &romfs_dir, &exefs_dir)?;
+
+        // !Packing fs files to NCA
+        // Packed separately, as the packed NCA is looked up by its type
+        let pack_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
+        let mut patched_nca = Nca::pack_program(
+            &keyset,
+            &packer,
+            &offset_program_id,
+            DEFAULT_PRODKEYS_PATH.as_path(),
+            &romfs_dir,
+            &exefs_dir,
+            pack_dir.path(),
+        )?;
+        let dest = nca_dir.path().join(
+            patched_nca
+                .path
+                .file_name()
+                .expect("File should've a filename"),
+        );
+        fs::rename(&patched_nca.path, &dest)?;
+        patched_nca.path = dest;
+        patched_ncas.push((id_offset, patched_nca));
+
+        // Cleaning up extracted FS files
+        if let Err(err) = fs_dir.close() {
+            warn!(?err);
+        }
+    }
+    for (id_offset, update_nca) in update_ncas {
+        warn!(id_offset, nca = %update_nca.path.display(), "Base has no Program NCA for this program, skipping");
+    }
+
+    // !Moving Control NCAs
+    for control_nca in control_ncas.values_mut() {
+        let control_nca_filename = control_nca
+            .path
+            .file_name()
+            .expect("File should've a filename")
+            .to_owned();
+        fs::rename(
+            &control_nca.path,
+            nca_dir.path().join(&control_nca_filename),
+        )?;
+        control_nca.path = nca_dir.path().join(control_nca_filename);
+    }
+
+    // !Moving the other contents of the update, i.e. manuals and data
+    let mut other_contents = vec![];
+    for record in update_cnmt.iter().flat_map(|cnmt| &cnmt.content_records) {
+        if !matches!(
+            record.content_type,
+            CnmtContentType::HtmlDocument
+                | CnmtContentType::LegalInformation
+                | CnmtContentType::Data
+        ) {
+            continue;
+        }
+        let src = update_data_dir.path().join(record.nca_filename());
+        if !src.is_file() {
+            warn!(content_id = %record.get_content_id(), content_type = ?record.content_type, "Couldn't find the NCA of a CNMT content record");
+            continue;
+        }
+        let dest = nca_dir.path().join(record.nca_filename());
+        fs::rename(src, &dest)?;
+        other_contents.push((
+            Nca::try_new(&keyset, dest)?,
+            record.content_type,
+            record.id_offset,
+        ));
+    }
+
+    let application_id = parse_program_id(&base_nca.get_program_id())?;
 
     // Early cleanup
     if let Err(err) = base_data_dir.close() {
@@ -156,41 +340,120 @@ where
         warn!(?err);
     }
 
-    // !Packing fs files to NCA
-    let patched_nca = Nca::pack_program(
-        readers.iter(),
-        &packer,
-        &program_id,
-        DEFAULT_PRODKEYS_PATH.as_path(),
-        &romfs_dir,
-        &exefs_dir,
-        nca_dir.path(),
-    )?;
-
-    // Cleaning up extracted FS files
-    if let Err(err) = fs_dir.close() {
-        warn!(?err);
+    // !Generating Meta NCA
+    let mut cnmt = match &update_cnmt {
+        Some(update_cnmt) => Cnmt::new_application(
+            parse_program_id(&program_id)?,
+            update_cnmt.version,
+            update_cnmt.required_system_version.unwrap_or_default(),
+        ),
+        None => Cnmt::new_application(parse_program_id(&program_id)?, 0, 0),
+    };
+    for (id_offset, patched_nca) in &patched_ncas {
+        cnmt.add_content(patched_nca, CnmtContentType::Program, *id_offset)?;
+    }
+    for (id_offset, control_nca) in &control_ncas {
+        cnmt.add_content(control_nca, CnmtContentType::Control, *id_offset)?;
     }
+    for (nca, content_type, id_offset) in &other_contents {
+        cnmt.add_content(nca, *content_type, *id_offset)?;
+    }
+    // Main program is the first, it's checked to be there above
+    let (_, main_nca) = &patched_ncas[0];
+    Nca::create_meta(&keyset, &cnmt, main_nca, nca_dir.path(), &cfg.temp_dir)?;
 
-    // !Generating Meta NCA
-    Nca::create_meta(
-        &packer,
-        &program_id,
-        DEFAULT_PRODKEYS_PATH.as_path(),
-        &patched_nca,
-        &control_nca,
-        nca_dir.path(),
-        &cfg.temp_dir,
-    )?;
+    // !Merging DLCs
+    for dlc in dlcs.iter() {
+        merge_dlc(&mut keyset, dlc, application_id, nca_dir.path(), cfg)?;
+    }
 
     // !Packing NCAs to NSP
-    let patched_nsp = Nsp::pack(
-        &packer,
-        &program_id,
-        DEFAULT_PRODKEYS_PATH.as_path(),
-        nca_dir.path(),
-        outdir.as_ref(),
-    )?;
+    let patched_nsp = match compression {
+        Some(options) => Nsp::pack_nsz(
+            &keyset,
+            &program_id,
+            nca_dir.path(),
+            outdir.as_ref(),
+            options,
+        )?,
+        None => Nsp::pack(&program_id, nca_dir.path(), outdir.as_ref())?,
+    };
 
     Ok((patched_nsp, nacp_data, program_id))
 }
+
+/// Moves the contents of the `dlc` of `application_id` to `nca_dir`, along with its Ticket.
+fn merge_dlc(
+    keyset: &mut Keyset,
+    dlc: &Nsp,
+    application_id: u64,
+    nca_dir: &Path,
+    cfg: &Config,
+) -> Result<()> {
+    info!(dlc = %dlc.path.display(), "Merging DLC");
+    let data_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
+    dlc.unpack(data_dir.path())?;
+    // There's a Ticket per AddOnContent
+    keyset.add_title_keys_from_dir(data_dir.path())?;
+
+    let metas = nca_with_kind(keyset, data_dir.path(), nca::ContentType::Meta)
+        .ok_or_else(|| eyre!("Failed to find Meta NCA in '{}'", dlc.path.display()))?;
+    let mut merged = 0;
+    for meta in metas {
+        let cnmt = Cnmt::from_nca(keyset, &meta)?;
+        if cnmt.meta_type != ContentMetaType::AddOnContent {
+            warn!(dlc = %dlc.path.display(), meta_type = %cnmt.meta_type, "Skipping non DLC content");
+            continue;
+        }
+        if !cnmt.is_add_on_content_of(application_id) {
+            bail!(
+                "'{}' is a DLC of another title, its TitleID is {} while the base is {:016x}",
+                dlc.path.display(),
+                cnmt.get_title_id(),
+                application_id
+            );
+        }
+
+        for record in &cnmt.content_records {
+            let src = data_dir.path().join(record.nca_filename());
+            if !src.is_file() {
+                bail!(
+                    "Failed to find NCA '{}' of DLC {} in '{}'",
+                    record.nca_filename(),
+                    cnmt.get_title_id(),
+                    dlc.path.display()
+                );
+            }
+            fs::rename(src, nca_dir.join(record.nca_filename()))?;
+        }
+        fs::rename(
+            &meta.path,
+            nca_dir.join(meta.path.file_name().expect("File should've a filename")),
+        )?;
+        info!(title_id = %cnmt.get_title_id(), version = cnmt.version, "Merged DLC");
+        merged += 1;
+    }
+    if merged == 0 {
+        bail!(
+            "'{}' isn't a DLC, it has no AddOnContent CNMT",
+            dlc.path.display()
+        );
+    }
+
+    // Tickets and certs are needed for the NCAs with a RightsID
+    for entry in WalkDir::new(data_dir.path())
+        .min_depth(1)
+        .max_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|entry| ext_matches(entry.path(), "tik") || ext_matches(entry.path(), "cert"))
+    {
+        let dest = nca_dir.join(entry.file_name());
+        if dest.exists() {
+            continue;
+        }
+        fs::rename(entry.path(), dest)?;
+    }
+
+    Ok(())
+}
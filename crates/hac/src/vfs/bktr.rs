@@ -0,0 +1,308 @@
+//! https://switchbrew.org/wiki/NCA#Bucket_Tree
+//!
+//! Storages used by patch NCAs, where the patched section is made up of
+//! regions from the base NCA and the patch NCA (Indirect), and the patch data
+//! is encrypted with a per-region generation (AesCtrEx).
+
+use eyre::{bail, eyre, Result};
+use std::io::{self, Read, Seek, SeekFrom};
+use tracing::debug;
+
+use super::{
+    le_u32, le_u64,
+    storage::{seek_offset, AesCtrStorage, ReadSeek},
+};
+
+const BKTR_MAGIC: &[u8; 4] = b"BKTR";
+const NODE_SIZE: u64 = 0x4000;
+const NODE_HEADER_SIZE: u64 = 0x10;
+const INDIRECT_ENTRY_SIZE: u64 = 0x14;
+const AES_CTR_EX_ENTRY_SIZE: u64 = 0x10;
+
+/// https://switchbrew.org/wiki/NCA#PatchInfo
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PatchInfo {
+    pub indirect: BucketTreeInfo,
+    pub aes_ctr_ex: BucketTreeInfo,
+}
+
+/// Location of a bucket tree table within the section and its header.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BucketTreeInfo {
+    pub offset: u64,
+    pub size: u64,
+    pub entry_count: u32,
+}
+
+impl PatchInfo {
+    /// Parses the PatchInfo of a FsHeader.
+    pub fn from_fs_header(fs_header: &[u8]) -> Result<Self> {
+        Ok(Self {
+            indirect: BucketTreeInfo::parse(&fs_header[0x100..0x120])?,
+            aes_ctr_ex: BucketTreeInfo::parse(&fs_header[0x120..0x140])?,
+        })
+    }
+    pub fn is_empty(&self) -> bool {
+        self.indirect.size == 0 && self.aes_ctr_ex.size == 0
+    }
+}
+
+impl BucketTreeInfo {
+    fn parse(buf: &[u8]) -> Result<Self> {
+        let info = Self {
+            offset: le_u64(buf, 0x0),
+            size: le_u64(buf, 0x8),
+            entry_count: le_u32(buf, 0x18),
+        };
+        if info.size != 0 && &buf[0x10..0x14] != BKTR_MAGIC {
+            bail!("Invalid BKTR magic");
+        }
+        Ok(info)
+    }
+    /// Reads all the entries of the tree, returns them along with the end offset of the last entry.
+    fn read_entries<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        entry_size: u64,
+    ) -> Result<(Vec<Vec<u8>>, u64)> {
+        if self.entry_count == 0 {
+            return Ok((vec![], 0));
+        }
+        let entries_per_set = (NODE_SIZE - NODE_HEADER_SIZE) / entry_size;
+        let set_count = (self.entry_count as u64 + entries_per_set - 1) / entries_per_set;
+        let entry_sets_offset = self.offset + node_storage_size(set_count);
+        if entry_sets_offset + set_count * NODE_SIZE > self.offset + self.size {
+            bail!("BKTR table is out of bounds");
+        }
+
+        let mut entries = Vec::with_capacity(self.entry_count as _);
+        let mut end_offset = 0;
+        let mut node = vec![0u8; NODE_SIZE as usize];
+        for set in 0..set_count {
+            reader.seek(SeekFrom::Start(entry_sets_offset + set * NODE_SIZE))?;
+            reader.read_exact(&mut node)?;
+            let count = le_u32(&node, 0x4) as u64;
+            if le_u32(&node, 0x0) as u64 != set || count > entries_per_set {
+                bail!("BKTR entry set {} is malformed", set);
+            }
+            end_offset = le_u64(&node, 0x8);
+            entries.extend(
+                node[NODE_HEADER_SIZE as usize..]
+                    .chunks_exact(entry_size as usize)
+                    .take(count as usize)
+                    .map(|entry| entry.to_vec()),
+            );
+        }
+        if entries.len() != self.entry_count as usize {
+            bail!(
+                "Expected {} BKTR entries, found {}",
+                self.entry_count,
+                entries.len()
+            );
+        }
+
+        Ok((entries, end_offset))
+    }
+}
+
+/// Size of the L1 + L2 nodes, which precede the entry sets.
+///
+/// Based on LibHac's `BucketTree.QueryNodeStorageSize()`.
+fn node_storage_size(set_count: u64) -> u64 {
+    let offsets_per_node = (NODE_SIZE - NODE_HEADER_SIZE) / 0x8;
+    let l2_count = if set_count <= offsets_per_node {
+        0
+    } else {
+        let l2_nodes = (set_count + offsets_per_node - 1) / offsets_per_node;
+        let remaining = set_count - (offsets_per_node - (l2_nodes - 1));
+        (remaining + offsets_per_node - 1) / offsets_per_node
+    };
+    (1 + l2_count) * NODE_SIZE
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IndirectEntry {
+    virtual_offset: u64,
+    physical_offset: u64,
+    storage_index: u32,
+}
+
+/// Maps the virtual (patched) section to either the base or the patch storage.
+pub struct IndirectStorage {
+    storages: [Box<dyn ReadSeek>; 2],
+    entries: Vec<IndirectEntry>,
+    size: u64,
+    pos: u64,
+}
+
+impl IndirectStorage {
+    /// `table_reader` should be the decrypted patch section.
+    pub fn new<R: Read + Seek>(
+        info: &BucketTreeInfo,
+        table_reader: &mut R,
+        base: Box<dyn ReadSeek>,
+        patch: Box<dyn ReadSeek>,
+    ) -> Result<Self> {
+        let (raw_entries, size) = info
+            .read_entries(table_reader, INDIRECT_ENTRY_SIZE)
+            .map_err(|err| eyre!("Failed to read Indirect table: {}", err))?;
+        let entries = raw_entries
+            .iter()
+            .map(|entry| IndirectEntry {
+                virtual_offset: le_u64(entry, 0x0),
+                physical_offset: le_u64(entry, 0x8),
+                storage_index: le_u32(entry, 0x10),
+            })
+            .collect::<Vec<_>>();
+        if entries.iter().any(|entry| entry.storage_index > 1)
+            || entries
+                .windows(2)
+                .any(|pair| pair[0].virtual_offset >= pair[1].virtual_offset)
+        {
+            bail!("Indirect table is malformed");
+        }
+        debug!(entries = entries.len(), size, "Parsed Indirect table");
+
+        Ok(Self {
+            storages: [base, patch],
+            entries,
+            size,
+            pos: 0,
+        })
+    }
+    /// Size of the patched section.
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+}
+
+impl Read for IndirectStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = match self
+            .entries
+            .partition_point(|entry| entry.virtual_offset <= self.pos)
+        {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Unmapped region in Indirect storage",
+                ))
+            }
+            index => index - 1,
+        };
+        let entry = self.entries[index];
+        let region_end = self
+            .entries
+            .get(index + 1)
+            .map_or(self.size, |next| next.virtual_offset);
+        let len = (region_end - self.pos).min(buf.len() as u64) as usize;
+
+        let storage = &mut self.storages[entry.storage_index as usize];
+        storage.seek(SeekFrom::Start(
+            entry.physical_offset + (self.pos - entry.virtual_offset),
+        ))?;
+        let read = storage.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for IndirectStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::End(_) => seek_offset(self.size, pos)?,
+            _ => seek_offset(self.pos, pos)?,
+        };
+        Ok(self.pos)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AesCtrExEntry {
+    offset: u64,
+    encrypted: bool,
+    generation: u32,
+}
+
+/// Patch section data, where the AES-CTR generation differs for each region.
+pub struct AesCtrExStorage<R> {
+    inner: AesCtrStorage<R>,
+    upper_iv: u64,
+    entries: Vec<AesCtrExEntry>,
+    /// Regions after this use the section's own IV, i.e. the BKTR tables.
+    end: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> AesCtrExStorage<R> {
+    /// `inner` should be the patch section decrypted with the section's own IV.
+    pub fn new(info: &BucketTreeInfo, mut inner: AesCtrStorage<R>, upper_iv: u64) -> Result<Self> {
+        let (raw_entries, end) = info
+            .read_entries(&mut inner, AES_CTR_EX_ENTRY_SIZE)
+            .map_err(|err| eyre!("Failed to read AesCtrEx table: {}", err))?;
+        let entries = raw_entries
+            .iter()
+            .map(|entry| AesCtrExEntry {
+                offset: le_u64(entry, 0x0),
+                encrypted: entry[0x8] == 0,
+                generation: le_u32(entry, 0xC),
+            })
+            .collect::<Vec<_>>();
+        debug!(entries = entries.len(), end, "Parsed AesCtrEx table");
+
+        Ok(Self {
+            inner,
+            upper_iv,
+            entries,
+            end,
+            pos: 0,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for AesCtrExStorage<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (upper_iv, encrypted, region_end) = match self
+            .entries
+            .partition_point(|entry| entry.offset <= self.pos)
+        {
+            index if index > 0 && self.pos < self.end => {
+                let entry = self.entries[index - 1];
+                let region_end = self.entries.get(index).map_or(self.end, |next| next.offset);
+                // Generation replaces the lower half of the upper IV
+                let upper_iv = (self.upper_iv & 0xFFFFFFFF00000000) | entry.generation as u64;
+                (upper_iv, entry.encrypted, region_end)
+            }
+            _ => (self.upper_iv, true, u64::MAX),
+        };
+        let len = (region_end - self.pos).min(buf.len() as u64) as usize;
+
+        let read = if encrypted {
+            self.inner.set_upper_iv(upper_iv);
+            self.inner.seek(SeekFrom::Start(self.pos))?;
+            self.inner.read(&mut buf[..len])?
+        } else {
+            let inner = self.inner.get_mut();
+            inner.seek(SeekFrom::Start(self.pos))?;
+            inner.read(&mut buf[..len])?
+        };
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for AesCtrExStorage<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,384 @@
+//! https://switchbrew.org/wiki/CNMT
+//!
+//! Reader for the packaged content meta stored within Meta NCAs.
+
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{self, Read, Seek, SeekFrom},
+};
+use strum_macros::FromRepr;
+use tracing::{debug, info};
+
+use super::{
+    le_u32, le_u64,
+    nca::{ContentType, FsType, Nca},
+    pfs0::Pfs0,
+};
+use crate::keys::Keyset;
+
+const HEADER_SIZE: usize = 0x20;
+const CONTENT_RECORD_SIZE: usize = 0x38;
+const META_RECORD_SIZE: usize = 0x10;
+const DIGEST_SIZE: usize = 0x20;
+/// Patch id is the application id with these bits set.
+const PATCH_ID_MASK: u64 = 0x800;
+/// AddOnContent ids start from the application id plus this.
+const ADD_ON_CONTENT_ID_OFFSET: u64 = 0x1000;
+/// Bits of the AddOnContent id used for the DLC index.
+const ADD_ON_CONTENT_INDEX_MASK: u64 = 0xFFF;
+
+#[derive(Debug, Clone, Copy, FromRepr, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum ContentMetaType {
+    SystemProgram = 0x01,
+    SystemData = 0x02,
+    SystemUpdate = 0x03,
+    BootImagePackage = 0x04,
+    BootImagePackageSafe = 0x05,
+    Application = 0x80,
+    Patch = 0x81,
+    AddOnContent = 0x82,
+    Delta = 0x83,
+    DataPatch = 0x84,
+}
+
+impl fmt::Display for ContentMetaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Type of a content record, which differs from the [`ContentType`] in the NCA header.
+#[derive(Debug, Clone, Copy, FromRepr, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum CnmtContentType {
+    Meta = 0x00,
+    Program = 0x01,
+    Data = 0x02,
+    Control = 0x03,
+    HtmlDocument = 0x04,
+    LegalInformation = 0x05,
+    DeltaFragment = 0x06,
+}
+
+impl CnmtContentType {
+    /// Content type of the NCA that the record refers to.
+    pub fn nca_content_type(&self) -> ContentType {
+        match self {
+            CnmtContentType::Meta => ContentType::Meta,
+            CnmtContentType::Program => ContentType::Program,
+            CnmtContentType::Data | CnmtContentType::DeltaFragment => ContentType::Data,
+            CnmtContentType::Control => ContentType::Control,
+            CnmtContentType::HtmlDocument | CnmtContentType::LegalInformation => {
+                ContentType::Manual
+            }
+        }
+    }
+}
+
+/// https://switchbrew.org/wiki/CNMT#PackagedContentInfo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentRecord {
+    /// SHA-256 of the whole NCA.
+    pub hash: [u8; 0x20],
+    /// First half of the hash, also the NCA filename.
+    pub content_id: [u8; 0x10],
+    pub size: u64,
+    pub content_type: CnmtContentType,
+    /// Added to the title id of the CNMT, for eg- for multi-program applications.
+    pub id_offset: u8,
+}
+
+impl ContentRecord {
+    pub fn get_content_id(&self) -> String {
+        hex::encode(self.content_id)
+    }
+    /// Filename of the NCA in a NSP.
+    pub fn nca_filename(&self) -> String {
+        format!("{}.nca", self.get_content_id())
+    }
+}
+
+/// https://switchbrew.org/wiki/CNMT
+#[derive(Debug, Clone)]
+pub struct Cnmt {
+    pub title_id: u64,
+    pub version: u32,
+    pub meta_type: ContentMetaType,
+    pub attributes: u8,
+    pub required_download_system_version: u32,
+    /// Patch id for applications, application id for patches and add-on contents.
+    pub other_id: Option<u64>,
+    pub required_system_version: Option<u32>,
+    pub extended_header: Vec<u8>,
+    pub content_records: Vec<ContentRecord>,
+    /// Only used by SystemUpdate metas.
+    pub meta_records: Vec<[u8; META_RECORD_SIZE]>,
+    pub extended_data: Vec<u8>,
+    pub digest: [u8; DIGEST_SIZE],
+}
+
+impl Cnmt {
+    /// Reads the CNMT from the PFS0 of a Meta NCA.
+    pub fn from_nca(keyset: &Keyset, meta: &Nca) -> Result<Self> {
+        if meta.content_type != ContentType::Meta {
+            bail!("'{}' is not a Meta Type NCA", meta.path.display());
+        }
+        let mut reader = meta.open_fs(keyset, FsType::PartitionFs)?;
+        let pfs0 = Pfs0::from_reader(&mut reader)?;
+        let entry = pfs0
+            .entries
+            .iter()
+            .find(|entry| entry.name.ends_with(".cnmt"))
+            .ok_or_else(|| eyre!("Couldn't find a CNMT in '{}'", meta.path.display()))?;
+        let mut buf = Vec::with_capacity(entry.size as _);
+        Pfs0::copy_entry(&mut reader, entry, &mut buf)?;
+
+        Cnmt::from_reader(&mut io::Cursor::new(buf))
+            .map_err(|err| eyre!("Failed to read CNMT of '{}': {}", meta.path.display(), err))
+    }
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let mut buf = vec![];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_to_end(&mut buf)?;
+        if buf.len() < HEADER_SIZE {
+            bail!("CNMT is too small");
+        }
+
+        let meta_type = ContentMetaType::from_repr(buf[0xC])
+            .ok_or_else(|| eyre!("Unknown content meta type {:#x}", buf[0xC]))?;
+        let extended_header_size = u16::from_le_bytes([buf[0xE], buf[0xF]]) as usize;
+        let content_count = u16::from_le_bytes([buf[0x10], buf[0x11]]) as usize;
+        let meta_count = u16::from_le_bytes([buf[0x12], buf[0x13]]) as usize;
+
+        let content_offset = HEADER_SIZE + extended_header_size;
+        let meta_offset = content_offset + content_count * CONTENT_RECORD_SIZE;
+        let extended_data_offset = meta_offset + meta_count * META_RECORD_SIZE;
+        if extended_data_offset + DIGEST_SIZE > buf.len() {
+            bail!("CNMT records are out of bounds");
+        }
+        let extended_header = buf[HEADER_SIZE..content_offset].to_vec();
+
+        let (other_id, required_system_version) = match meta_type {
+            ContentMetaType::Application | ContentMetaType::Patch
+                if extended_header.len() >= 0xC =>
+            {
+                (
+                    Some(le_u64(&extended_header, 0x0)),
+                    Some(le_u32(&extended_header, 0x8)),
+                )
+            }
+            ContentMetaType::AddOnContent | ContentMetaType::Delta
+                if extended_header.len() >= 0x8 =>
+            {
+                (Some(le_u64(&extended_header, 0x0)), None)
+            }
+            _ => (None, None),
+        };
+
+        let content_records = buf[content_offset..meta_offset]
+            .chunks_exact(CONTENT_RECORD_SIZE)
+            .map(|record| {
+                // Size is a u48
+                let mut size = [0u8; 8];
+                size[..0x6].copy_from_slice(&record[0x30..0x36]);
+                Ok(ContentRecord {
+                    hash: record[..0x20].try_into()?,
+                    content_id: record[0x20..0x30].try_into()?,
+                    size: u64::from_le_bytes(size),
+                    content_type: CnmtContentType::from_repr(record[0x36])
+                        .ok_or_else(|| eyre!("Unknown content record type {:#x}", record[0x36]))?,
+                    id_offset: record[0x37],
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let meta_records = buf[meta_offset..extended_data_offset]
+            .chunks_exact(META_RECORD_SIZE)
+            .map(|record| record.try_into())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let cnmt = Self {
+            title_id: le_u64(&buf, 0x0),
+            version: le_u32(&buf, 0x8),
+            meta_type,
+            attributes: buf[0x14],
+            required_download_system_version: le_u32(&buf, 0x18),
+            other_id,
+            required_system_version,
+            extended_header,
+            content_records,
+            meta_records,
+            extended_data: buf[extended_data_offset..buf.len() - DIGEST_SIZE].to_vec(),
+            digest: buf[buf.len() - DIGEST_SIZE..].try_into()?,
+        };
+        info!(
+            title_id = %cnmt.get_title_id(),
+            version = cnmt.version,
+            meta_type = %cnmt.meta_type,
+            "Parsed CNMT"
+        );
+        debug!(?cnmt);
+
+        Ok(cnmt)
+    }
+    /// New Application CNMT without any content records.
+    pub fn new_application(title_id: u64, version: u32, required_system_version: u32) -> Self {
+        let mut extended_header = vec![0u8; 0x10];
+        extended_header[..0x8].copy_from_slice(&(title_id | PATCH_ID_MASK).to_le_bytes());
+        extended_header[0x8..0xC].copy_from_slice(&required_system_version.to_le_bytes());
+        Self {
+            title_id,
+            version,
+            meta_type: ContentMetaType::Application,
+            attributes: 0,
+            required_download_system_version: 0,
+            other_id: Some(title_id | PATCH_ID_MASK),
+            required_system_version: Some(required_system_version),
+            extended_header,
+            content_records: vec![],
+            meta_records: vec![],
+            extended_data: vec![],
+            digest: [0u8; DIGEST_SIZE],
+        }
+    }
+    /// Adds a content record for `nca`, its hash is calculated here.
+    pub fn add_content(
+        &mut self,
+        nca: &Nca,
+        content_type: CnmtContentType,
+        id_offset: u8,
+    ) -> Result<&ContentRecord> {
+        let hash = nca.hash()?;
+        self.content_records.push(ContentRecord {
+            hash,
+            content_id: hash[..0x10].try_into().expect("Slice is of len 0x10"),
+            size: fs::metadata(&nca.path)?.len(),
+            content_type,
+            id_offset,
+        });
+        Ok(self.content_records.last().expect("Record was just pushed"))
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(
+            HEADER_SIZE
+                + self.extended_header.len()
+                + self.content_records.len() * CONTENT_RECORD_SIZE
+                + self.meta_records.len() * META_RECORD_SIZE
+                + self.extended_data.len()
+                + DIGEST_SIZE,
+        );
+        buf.extend(self.title_id.to_le_bytes());
+        buf.extend(self.version.to_le_bytes());
+        buf.push(self.meta_type as u8);
+        buf.push(0);
+        buf.extend((self.extended_header.len() as u16).to_le_bytes());
+        buf.extend((self.content_records.len() as u16).to_le_bytes());
+        buf.extend((self.meta_records.len() as u16).to_le_bytes());
+        buf.push(self.attributes);
+        buf.extend([0u8; 0x3]);
+        buf.extend(self.required_download_system_version.to_le_bytes());
+        buf.extend([0u8; 0x4]);
+        buf.extend(&self.extended_header);
+        for record in &self.content_records {
+            buf.extend(record.hash);
+            buf.extend(record.content_id);
+            buf.extend(&record.size.to_le_bytes()[..0x6]);
+            buf.push(record.content_type as u8);
+            buf.push(record.id_offset);
+        }
+        for record in &self.meta_records {
+            buf.extend(record);
+        }
+        buf.extend(&self.extended_data);
+        buf.extend(self.digest);
+        buf
+    }
+    /// Whether this is the CNMT of an update of `application_id`.
+    pub fn is_patch_of(&self, application_id: u64) -> bool {
+        self.meta_type == ContentMetaType::Patch
+            && self.other_id.map_or(true, |id| id == application_id)
+            && self.title_id == application_id | PATCH_ID_MASK
+    }
+    /// Whether this is the CNMT of a DLC of `application_id`.
+    ///
+    /// Both the application id in the extended header and the TitleID have to agree.
+    pub fn is_add_on_content_of(&self, application_id: u64) -> bool {
+        self.meta_type == ContentMetaType::AddOnContent
+            && self.other_id.map_or(true, |id| id == application_id)
+            && self.title_id & !ADD_ON_CONTENT_INDEX_MASK
+                == (application_id & !ADD_ON_CONTENT_INDEX_MASK) + ADD_ON_CONTENT_ID_OFFSET
+    }
+    /// Id of the application this belongs to, derived from the TitleID if there's no other id.
+    pub fn application_id(&self) -> u64 {
+        match (self.meta_type, self.other_id) {
+            (ContentMetaType::Application, _) => self.title_id,
+            (ContentMetaType::Patch | ContentMetaType::AddOnContent, Some(id)) => id,
+            (ContentMetaType::Patch, None) => self.title_id & !PATCH_ID_MASK,
+            (ContentMetaType::AddOnContent, None) => (self.title_id & !ADD_ON_CONTENT_INDEX_MASK)
+                .saturating_sub(ADD_ON_CONTENT_ID_OFFSET),
+            _ => self.title_id,
+        }
+    }
+    /// Name of the CNMT file within the PFS0 of the Meta NCA.
+    pub fn filename(&self) -> String {
+        format!("{}_{}.cnmt", self.meta_type, self.get_title_id())
+    }
+    pub fn get_title_id(&self) -> String {
+        format!("{:016x}", self.title_id)
+    }
+    /// Content records of `content_type`, ordered by their id offset.
+    pub fn records_of(&self, content_type: CnmtContentType) -> Vec<&ContentRecord> {
+        let mut records = self
+            .content_records
+            .iter()
+            .filter(|record| record.content_type == content_type)
+            .collect::<Vec<_>>();
+        records.sort_by_key(|record| record.id_offset);
+        records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seed: u8, size: u64, content_type: CnmtContentType, id_offset: u8) -> ContentRecord {
+        let hash = [seed; 0x20];
+        ContentRecord {
+            hash,
+            content_id: hash[..0x10].try_into().unwrap(),
+            size,
+            content_type,
+            id_offset,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        // `add_content` needs a real NCA, so the records are pushed as it would
+        let mut cnmt = Cnmt::new_application(0x0100_0000_0001_0000, 0x10000, 0x0C00_0000);
+        cnmt.content_records = vec![
+            record(0x11, 0x1234, CnmtContentType::Program, 0),
+            // Sizes are 6 bytes long
+            record(0x22, (1 << 40) + 0x5678, CnmtContentType::Data, 0),
+            record(0x33, (1 << 48) - 1, CnmtContentType::Program, 1),
+            record(0x44, 0x4000, CnmtContentType::Control, 0),
+        ];
+        cnmt.digest = [0xDD; DIGEST_SIZE];
+
+        let bytes = cnmt.to_bytes();
+        let parsed = Cnmt::from_reader(&mut io::Cursor::new(&bytes)).unwrap();
+        assert_eq!(parsed.title_id, cnmt.title_id);
+        assert_eq!(parsed.version, cnmt.version);
+        assert_eq!(parsed.meta_type, ContentMetaType::Application);
+        assert_eq!(parsed.other_id, Some(0x0100_0000_0001_0800));
+        assert_eq!(parsed.required_system_version, Some(0x0C00_0000));
+        assert_eq!(parsed.extended_header, cnmt.extended_header);
+        assert_eq!(parsed.content_records, cnmt.content_records);
+        assert_eq!(parsed.digest, cnmt.digest);
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+}
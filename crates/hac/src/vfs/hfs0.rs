@@ -0,0 +1,86 @@
+//! https://switchbrew.org/wiki/XCI#HFS0
+//!
+//! Reader for HFS0, the hashed partition file system used by gamecard images.
+
+use eyre::{bail, eyre, Result};
+use std::io::{Read, Seek, SeekFrom};
+use tracing::debug;
+
+use super::{le_u32, le_u64};
+
+const HFS0_MAGIC: &[u8; 4] = b"HFS0";
+const HEADER_SIZE: usize = 0x10;
+const ENTRY_SIZE: usize = 0x40;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hfs0Entry {
+    pub name: String,
+    /// Offset of the file data relative to the start of the HFS0.
+    pub offset: u64,
+    pub size: u64,
+    /// Size of the region at the start of the file covered by `hash`.
+    pub hashed_region_size: u32,
+    pub hash: [u8; 0x20],
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Hfs0 {
+    pub entries: Vec<Hfs0Entry>,
+}
+
+impl Hfs0 {
+    /// Parses the HFS0 header, the `reader` should start at the beginning of the HFS0.
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if &header[..0x4] != HFS0_MAGIC {
+            bail!("Invalid HFS0 magic");
+        }
+        let entry_count = le_u32(&header, 0x4) as usize;
+        let string_table_size = le_u32(&header, 0x8) as usize;
+        // Don't trust the counts of a corrupt header for allocating the tables
+        let header_size =
+            (HEADER_SIZE + entry_count * ENTRY_SIZE) as u64 + string_table_size as u64;
+        let len = reader.seek(SeekFrom::End(0))?;
+        if header_size > len {
+            bail!(
+                "HFS0 header size {:#x} exceeds the HFS0 size {:#x}",
+                header_size,
+                len
+            );
+        }
+        reader.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+
+        let mut entry_table = vec![0u8; entry_count * ENTRY_SIZE];
+        reader.read_exact(&mut entry_table)?;
+        let mut string_table = vec![0u8; string_table_size];
+        reader.read_exact(&mut string_table)?;
+
+        let data_offset = (HEADER_SIZE + entry_table.len() + string_table.len()) as u64;
+        let entries = entry_table
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| {
+                let name_offset = le_u32(entry, 0x10) as usize;
+                let name = string_table
+                    .get(name_offset..)
+                    .and_then(|s| s.split(|ch| *ch == 0).next())
+                    .ok_or_else(|| eyre!("HFS0 entry name is out of bounds"))?;
+                Ok(Hfs0Entry {
+                    name: String::from_utf8_lossy(name).into(),
+                    offset: data_offset + le_u64(entry, 0x0),
+                    size: le_u64(entry, 0x8),
+                    hashed_region_size: le_u32(entry, 0x14),
+                    hash: entry[0x20..0x40].try_into().expect("Slice is of len 0x20"),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        debug!(?entries, "Parsed HFS0");
+
+        Ok(Self { entries })
+    }
+    pub fn find(&self, name: &str) -> Option<&Hfs0Entry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+}
@@ -1,9 +1,17 @@
-use eyre::{bail, Result};
+use eyre::{bail, eyre, Result};
 use itertools::Itertools;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
+pub mod bktr;
+pub mod cnmt;
+pub mod hfs0;
 pub mod nacp;
 pub mod nca;
+pub mod ncz;
 pub mod nsp;
+pub mod pfs0;
+pub mod romfs;
+pub mod storage;
 pub mod ticket;
 pub mod xci;
 
@@ -13,7 +21,7 @@ pub mod xci;
 pub const PROGRAMID_LEN: u8 = 16;
 
 pub fn validate_program_id(program_id: &str) -> Result<()> {
-    if program_id.len() == PROGRAMID_LEN as _ {
+    if program_id.len() == PROGRAMID_LEN as usize {
         Ok(())
     } else {
         bail!(
@@ -25,6 +33,17 @@ pub fn validate_program_id(program_id: &str) -> Result<()> {
     }
 }
 
+/// Parses a TitleID displayed in hexadecimal.
+pub fn parse_program_id(program_id: &str) -> Result<u64> {
+    validate_program_id(program_id)?;
+    u64::from_str_radix(program_id, 16).map_err(|_| {
+        eyre!(
+            "'{}' is invalid TitleID, it should be in hexadecimal",
+            program_id
+        )
+    })
+}
+
 pub fn filter_out_lines(pat: &str, buf: &[u8]) -> String {
     let buf_str = String::from_utf8_lossy(buf);
     buf_str.lines().filter(|s| !s.contains(pat)).join("\n")
@@ -33,3 +52,44 @@ pub fn filter_out_lines(pat: &str, buf: &[u8]) -> String {
 pub fn filter_out_key_mismatches(buf: &[u8]) -> String {
     filter_out_lines("Failed to match key", buf)
 }
+
+pub(crate) fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(
+        buf[offset..offset + 0x4]
+            .try_into()
+            .expect("Slice is of len 4"),
+    )
+}
+
+pub(crate) fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(
+        buf[offset..offset + 0x8]
+            .try_into()
+            .expect("Slice is of len 8"),
+    )
+}
+
+/// Copies exactly `size` bytes at `offset` from `reader` to `writer`.
+pub(crate) fn copy_exact<R, W>(
+    reader: &mut R,
+    offset: u64,
+    size: u64,
+    writer: &mut W,
+) -> Result<u64>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    reader.seek(SeekFrom::Start(offset))?;
+    let copied = io::copy(&mut reader.take(size), writer)?;
+    if copied != size {
+        bail!(
+            "Unexpected EOF, read {} of {} bytes at offset {:#x}",
+            copied,
+            size,
+            offset
+        );
+    }
+    writer.flush()?;
+    Ok(copied)
+}
@@ -1,76 +1,225 @@
 use common::filename::{self, UNICODE_REPLACEMENT_CHAR};
 use eyre::{bail, Result};
 use fs_err as fs;
+use serde::{Deserialize, Serialize};
 use std::{
     io::{self, Read, Seek},
     path::{Path, PathBuf},
 };
-use tracing::info;
-use walkdir::WalkDir;
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+use tracing::{debug, info};
+
+use crate::keys::Keyset;
+
+use super::{
+    le_u32, le_u64,
+    nca::{ContentType, Nca},
+    romfs::RomFs,
+};
 
 const NACP_FILENAME: &str = "control.nacp";
+const NACP_SIZE: usize = 0x4000;
+const TITLE_ENTRY_SIZE: usize = 0x300;
 
-/// https://switchbrew.org/wiki/NACP_Format
-///
-/// Provides access to some of the data contained within a NACP file.
-#[derive(Debug, Default, Clone)]
-pub struct NacpData {
-    pub title_entry: ApplicationTitle, // Only using the "AmericanEnglish" entry
-    pub application_version: [u8; 0x10],
+/// Languages in the order of the title entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumIter, Serialize, Deserialize)]
+pub enum Language {
+    AmericanEnglish,
+    BritishEnglish,
+    Japanese,
+    French,
+    German,
+    LatinAmericanSpanish,
+    Spanish,
+    Italian,
+    Dutch,
+    CanadianFrench,
+    Portuguese,
+    Russian,
+    Korean,
+    TraditionalChinese,
+    SimplifiedChinese,
+    BrazilianPortuguese,
 }
 
-#[derive(Debug, Clone)]
+/// https://switchbrew.org/wiki/NACP#ApplicationTitle
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ApplicationTitle {
-    pub application_name: [u8; 0x200],
-    pub application_publisher: [u8; 0x100],
+    pub language: Language,
+    pub name: String,
+    pub publisher: String,
 }
 
-impl Default for ApplicationTitle {
-    fn default() -> Self {
-        Self {
-            application_name: std::array::from_fn(|_| Default::default()),
-            application_publisher: std::array::from_fn(|_| Default::default()),
-        }
-    }
+/// https://switchbrew.org/wiki/NACP_Format
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NacpData {
+    /// One entry per [`Language`], empty for the unsupported ones.
+    pub titles: Vec<ApplicationTitle>,
+    pub isbn: String,
+    pub startup_user_account: u8,
+    pub user_account_switch_lock: u8,
+    pub add_on_content_registration_type: u8,
+    pub attribute_flag: u32,
+    /// Bitmask of [`Language`], see [`NacpData::supported_languages`].
+    pub supported_language_flag: u32,
+    pub parental_control_flag: u32,
+    pub screenshot: u8,
+    pub video_capture: u8,
+    pub data_loss_confirmation: u8,
+    pub play_log_policy: u8,
+    pub presence_group_id: u64,
+    /// Minimum age per rating organization, -1 if unrated.
+    pub rating_age: [i8; 0x20],
+    pub display_version: String,
+    pub add_on_content_base_id: u64,
+    pub save_data_owner_id: u64,
+    pub user_account_save_data_size: i64,
+    pub user_account_save_data_journal_size: i64,
+    pub device_save_data_size: i64,
+    pub device_save_data_journal_size: i64,
+    pub bcat_delivery_cache_storage_size: i64,
+    pub application_error_code_category: String,
+    pub local_communication_id: [u64; 0x8],
+    pub logo_type: u8,
+    pub logo_handling: u8,
+    pub runtime_add_on_content_install: u8,
+    pub runtime_parameter_delivery: u8,
+    pub crash_report: u8,
+    pub hdcp: u8,
+    pub seed_for_pseudo_device_id: u64,
+    pub bcat_passphrase: String,
+    pub startup_user_account_option: u8,
+    pub user_account_save_data_size_max: i64,
+    pub user_account_save_data_journal_size_max: i64,
+    pub device_save_data_size_max: i64,
+    pub device_save_data_journal_size_max: i64,
+    pub temporary_storage_size: i64,
+    pub cache_storage_size: i64,
+    pub cache_storage_journal_size: i64,
+    pub cache_storage_data_and_journal_size_max: i64,
+    pub cache_storage_index_max: u16,
+    pub play_log_queryable_application_id: [u64; 0x10],
+    pub play_log_query_capability: u8,
+    pub repair_flag: u8,
+    pub program_index: u8,
+    pub required_network_service_license_on_launch: u8,
 }
 
 impl NacpData {
-    const TITLE_ENTRY_OFFSET: usize = 0x0;
-    const APPLICATION_VERSION_OFFSET: usize = 0x3060;
-
     pub fn try_new<P: AsRef<Path>>(nacp_path: P) -> Result<Self> {
         if !nacp_path.as_ref().is_file() || !is_nacp(nacp_path.as_ref()) {
             bail!("'{}' is not a NACP file", nacp_path.as_ref().display());
         }
 
+        NacpData::from_reader(&mut fs::File::open(nacp_path.as_ref())?)
+    }
+    /// Reads the NACP directly from the RomFS of a Control NCA.
+    pub fn from_nca(keyset: &Keyset, control: &Nca) -> Result<Self> {
+        if control.content_type != ContentType::Control {
+            bail!("'{}' is not a Control Type NCA", control.path.display());
+        }
+        NacpData::from_reader(&mut io::Cursor::new(
+            control.read_romfs_file(keyset, NACP_FILENAME)?,
+        ))
+    }
+    pub fn from_reader<R: Read + Seek>(nacp: &mut R) -> Result<Self> {
         info!("Reading NACP data");
 
-        let mut nacp_data = NacpData::default();
-        let mut nacp = fs::File::open(nacp_path.as_ref())?;
+        let mut buf = vec![0u8; NACP_SIZE];
+        nacp.seek(io::SeekFrom::Start(0))?;
+        nacp.read_exact(&mut buf)?;
 
-        nacp.seek(io::SeekFrom::Start(NacpData::TITLE_ENTRY_OFFSET as _))?;
-        nacp.read_exact(nacp_data.title_entry.application_name.as_mut())?;
-        nacp.read_exact(nacp_data.title_entry.application_publisher.as_mut())?;
-
-        nacp.seek(io::SeekFrom::Start(
-            NacpData::APPLICATION_VERSION_OFFSET as _,
-        ))?;
-        nacp.read_exact(nacp_data.application_version.as_mut())?;
+        let titles = Language::iter()
+            .zip(buf[..0x3000].chunks_exact(TITLE_ENTRY_SIZE))
+            .map(|(language, entry)| ApplicationTitle {
+                language,
+                name: c_str(&entry[..0x200]),
+                publisher: c_str(&entry[0x200..]),
+            })
+            .collect();
+        let le_i64 = |offset| le_u64(&buf, offset) as i64;
+        let nacp_data = NacpData {
+            titles,
+            isbn: c_str(&buf[0x3000..0x3025]),
+            startup_user_account: buf[0x3025],
+            user_account_switch_lock: buf[0x3026],
+            add_on_content_registration_type: buf[0x3027],
+            attribute_flag: le_u32(&buf, 0x3028),
+            supported_language_flag: le_u32(&buf, 0x302C),
+            parental_control_flag: le_u32(&buf, 0x3030),
+            screenshot: buf[0x3034],
+            video_capture: buf[0x3035],
+            data_loss_confirmation: buf[0x3036],
+            play_log_policy: buf[0x3037],
+            presence_group_id: le_u64(&buf, 0x3038),
+            rating_age: std::array::from_fn(|i| buf[0x3040 + i] as i8),
+            display_version: c_str(&buf[0x3060..0x3070]),
+            add_on_content_base_id: le_u64(&buf, 0x3070),
+            save_data_owner_id: le_u64(&buf, 0x3078),
+            user_account_save_data_size: le_i64(0x3080),
+            user_account_save_data_journal_size: le_i64(0x3088),
+            device_save_data_size: le_i64(0x3090),
+            device_save_data_journal_size: le_i64(0x3098),
+            bcat_delivery_cache_storage_size: le_i64(0x30A0),
+            application_error_code_category: c_str(&buf[0x30A8..0x30B0]),
+            local_communication_id: std::array::from_fn(|i| le_u64(&buf, 0x30B0 + i * 0x8)),
+            logo_type: buf[0x30F0],
+            logo_handling: buf[0x30F1],
+            runtime_add_on_content_install: buf[0x30F2],
+            runtime_parameter_delivery: buf[0x30F3],
+            crash_report: buf[0x30F6],
+            hdcp: buf[0x30F7],
+            seed_for_pseudo_device_id: le_u64(&buf, 0x30F8),
+            bcat_passphrase: c_str(&buf[0x3100..0x3141]),
+            startup_user_account_option: buf[0x3141],
+            user_account_save_data_size_max: le_i64(0x3148),
+            user_account_save_data_journal_size_max: le_i64(0x3150),
+            device_save_data_size_max: le_i64(0x3158),
+            device_save_data_journal_size_max: le_i64(0x3160),
+            temporary_storage_size: le_i64(0x3168),
+            cache_storage_size: le_i64(0x3170),
+            cache_storage_journal_size: le_i64(0x3178),
+            cache_storage_data_and_journal_size_max: le_i64(0x3180),
+            cache_storage_index_max: u16::from_le_bytes([buf[0x3188], buf[0x3189]]),
+            play_log_queryable_application_id: std::array::from_fn(|i| {
+                le_u64(&buf, 0x3190 + i * 0x8)
+            }),
+            play_log_query_capability: buf[0x3210],
+            repair_flag: buf[0x3211],
+            program_index: buf[0x3212],
+            required_network_service_license_on_launch: buf[0x3213],
+        };
+        debug!(?nacp_data);
 
         info!("Successfully read NACP data");
 
         Ok(nacp_data)
     }
+    pub fn title(&self, language: Language) -> Option<&ApplicationTitle> {
+        self.titles.iter().find(|title| title.language == language)
+    }
+    pub fn supported_languages(&self) -> Vec<Language> {
+        Language::iter()
+            .enumerate()
+            .filter(|(i, _)| self.supported_language_flag & (1 << i) != 0)
+            .map(|(_, language)| language)
+            .collect()
+    }
+    /// AmericanEnglish title entry, or the first non-empty one if it's empty.
+    pub fn main_title(&self) -> Option<&ApplicationTitle> {
+        self.title(Language::AmericanEnglish)
+            .filter(|title| !title.name.is_empty())
+            .or_else(|| self.titles.iter().find(|title| !title.name.is_empty()))
+    }
     pub fn get_application_name(&self) -> String {
-        NacpData::sanitize(&String::from_utf8_lossy(&self.title_entry.application_name))
+        NacpData::sanitize(self.main_title().map_or("", |title| &title.name))
     }
     pub fn get_application_publisher(&self) -> String {
-        NacpData::sanitize(&String::from_utf8_lossy(
-            &self.title_entry.application_publisher,
-        ))
+        NacpData::sanitize(self.main_title().map_or("", |title| &title.publisher))
     }
     pub fn get_application_version(&self) -> String {
-        NacpData::sanitize(&String::from_utf8_lossy(&self.application_version))
+        NacpData::sanitize(&self.display_version)
     }
     fn sanitize(s: &str) -> String {
         s.chars()
@@ -79,20 +228,65 @@ impl NacpData {
     }
 }
 
-fn is_nacp<P: AsRef<Path>>(path: P) -> bool {
-    path.as_ref().file_name() == Some(NACP_FILENAME.as_ref())
+/// https://switchbrew.org/wiki/Control.nacp#Icon
+///
+/// A JPEG stored as `icon_<Language>.dat` next to the NACP, one for each supported language.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Icon {
+    pub language: Language,
+    pub data: Vec<u8>,
 }
 
-/// Returns the first NACP file in a dir.
-pub fn get_nacp_file<P: AsRef<Path>>(from: P) -> Option<PathBuf> {
-    for entry in WalkDir::new(from.as_ref())
-        .min_depth(1)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        if entry.path().is_file() && is_nacp(entry.path()) {
-            return Some(entry.into_path());
+impl Icon {
+    /// Reads all the icons from the RomFS of a Control NCA.
+    pub fn from_nca(keyset: &Keyset, control: &Nca) -> Result<Vec<Self>> {
+        if control.content_type != ContentType::Control {
+            bail!("'{}' is not a Control Type NCA", control.path.display());
         }
+        let (romfs, mut reader) = control.open_romfs(keyset)?;
+        let mut icons = vec![];
+        for language in Language::iter() {
+            if let Some(entry) = romfs.find(&Icon::filename(language)) {
+                let mut data = Vec::with_capacity(entry.size as _);
+                RomFs::copy_entry(&mut reader, entry, &mut data)?;
+                icons.push(Icon { language, data });
+            }
+        }
+        info!(nca = %control.path.display(), count = icons.len(), "Read icons");
+        Ok(icons)
+    }
+    /// Icon of `language`, falls back to AmericanEnglish and then to the first one.
+    pub fn pick(icons: &[Icon], language: Language) -> Option<&Icon> {
+        icons
+            .iter()
+            .find(|icon| icon.language == language)
+            .or_else(|| {
+                icons
+                    .iter()
+                    .find(|icon| icon.language == Language::AmericanEnglish)
+            })
+            .or_else(|| icons.first())
     }
-    None
+    /// Name of the icon file in the RomFS.
+    pub fn filename(language: Language) -> String {
+        format!("icon_{:?}.dat", language)
+    }
+    /// Writes the icon as `icon_<Language>.jpg` to `outdir`.
+    pub fn export<P: AsRef<Path>>(&self, outdir: P) -> Result<PathBuf> {
+        let path = outdir
+            .as_ref()
+            .join(format!("icon_{:?}.jpg", self.language));
+        fs::write(&path, &self.data)?;
+        Ok(path)
+    }
+}
+
+/// Null-terminated UTF-8 string.
+fn c_str(buf: &[u8]) -> String {
+    let len = buf.iter().position(|ch| *ch == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn is_nacp<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().file_name() == Some(NACP_FILENAME.as_ref())
 }
@@ -1,24 +1,38 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
     process::{Command, Stdio},
-    str::FromStr,
 };
 
-use common::utils::{ext_matches, get_fmt_size, move_file};
+use common::utils::{ext_matches, get_fmt_size};
 use derivative::Derivative;
 use eyre::{bail, eyre, Result};
-use strum_macros::EnumString;
-use tracing::{debug, error, info, warn};
+use fs_err as fs;
+use rsa::Pss;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use strum_macros::{EnumString, FromRepr};
+use tracing::{debug, info, warn};
 use walkdir::WalkDir;
 
 use crate::{
-    backend::{Backend, BackendKind},
-    vfs::filter_out_key_mismatches,
+    backend::Backend,
+    crypto::{aes_ecb_decrypt, aes_ecb_encrypt, AesCtr, AesKey, AesXts},
+    keys::{rights_id_key_revision, Keyset},
+    vfs::{
+        bktr::{AesCtrExStorage, IndirectStorage, PatchInfo},
+        cnmt::Cnmt,
+        filter_out_key_mismatches, le_u32, le_u64,
+        pfs0::{Pfs0, Pfs0Builder},
+        romfs::RomFs,
+        storage::{AesCtrStorage, ReadSeek, SubStorage},
+    },
 };
 
-#[derive(Debug, Clone, Copy, EnumString, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, EnumString, FromRepr, PartialEq, Eq, Hash, Serialize)]
+#[repr(u8)]
 pub enum ContentType {
     Program = 0x00,
     Meta = 0x01,
@@ -34,7 +48,291 @@ impl fmt::Display for ContentType {
     }
 }
 
+#[derive(Debug, Clone, Copy, FromRepr, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DistributionType {
+    Download = 0x00,
+    GameCard = 0x01,
+}
+
+#[derive(Debug, Clone, Copy, FromRepr, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FsType {
+    RomFs = 0x00,
+    PartitionFs = 0x01,
+}
+
+#[derive(Debug, Clone, Copy, FromRepr, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HashType {
+    Auto = 0x00,
+    None = 0x01,
+    HierarchicalSha256 = 0x02,
+    HierarchicalIntegrity = 0x03,
+    AutoSha3 = 0x04,
+    HierarchicalSha3256 = 0x05,
+    HierarchicalIntegritySha3 = 0x06,
+}
+
+#[derive(Debug, Clone, Copy, FromRepr, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EncryptionType {
+    Auto = 0x00,
+    None = 0x01,
+    AesXts = 0x02,
+    AesCtr = 0x03,
+    AesCtrEx = 0x04,
+    AesCtrSkipLayerHash = 0x05,
+    AesCtrExSkipLayerHash = 0x06,
+}
+
 type ProgramID = [u8; 8];
+type RightsID = [u8; 0x10];
+
+pub const NCA_HEADER_SIZE: usize = 0xC00;
+/// Bits of the ProgramID which hold the id offset of a program in a multi-program application.
+const ID_OFFSET_MASK: u64 = 0xFF;
+pub const MEDIA_UNIT_SIZE: u64 = 0x200;
+const SECTOR_SIZE: usize = 0x200;
+const FS_HEADER_OFFSET: usize = 0x400;
+const FS_HEADER_SIZE: usize = 0x200;
+const SECTION_COUNT: usize = 4;
+const META_HASH_BLOCK_SIZE: usize = 0x1000;
+
+/// https://switchbrew.org/wiki/NCA#FsHeader
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct NcaFsHeader {
+    pub version: u16,
+    pub fs_type: FsType,
+    pub hash_type: HashType,
+    pub encryption_type: EncryptionType,
+    /// Upper 8 bytes of the AES-CTR IV (generation and secure value).
+    pub upper_iv: u64,
+    #[derivative(Debug = "ignore")]
+    pub raw: Box<[u8; FS_HEADER_SIZE]>,
+}
+
+impl NcaFsHeader {
+    /// Region of the actual FS data within a section of `section_size`, as (offset, size).
+    pub fn data_region(&self, section_size: u64) -> Result<(u64, u64)> {
+        if self.hash_type == HashType::None {
+            return Ok((0, section_size));
+        }
+        let (offset, size, _) = *self
+            .hash_levels()?
+            .last()
+            .expect("There's at least one level");
+        if offset
+            .checked_add(size)
+            .map_or(true, |end| end > section_size)
+        {
+            bail!("FS data region is out of the section bounds");
+        }
+        Ok((offset, size))
+    }
+    /// Whether the section is hashed with IVFC levels rather than HierarchicalSha256 layers.
+    fn is_ivfc(&self) -> bool {
+        match self.hash_type {
+            HashType::HierarchicalIntegrity | HashType::HierarchicalIntegritySha3 => true,
+            HashType::Auto | HashType::AutoSha3 => self.fs_type == FsType::RomFs,
+            _ => false,
+        }
+    }
+    /// Hash levels as (offset, size, block size), the last one being the FS data.
+    fn hash_levels(&self) -> Result<Vec<(u64, u64, u64)>> {
+        let hash_data = &self.raw[0x8..0x100];
+        if self.is_ivfc() {
+            // https://switchbrew.org/wiki/NCA#HierarchicalIntegrity
+            if &hash_data[..0x4] != b"IVFC" {
+                bail!("Invalid IVFC magic");
+            }
+            let level_count = le_u32(hash_data, 0xC) as usize;
+            if !(2..=7).contains(&level_count) {
+                bail!("Invalid IVFC level count {}", level_count);
+            }
+            // `level_count` includes the master hash
+            Ok((0..level_count - 1)
+                .map(|i| {
+                    let level = 0x10 + i * 0x18;
+                    (
+                        le_u64(hash_data, level),
+                        le_u64(hash_data, level + 0x8),
+                        1u64 << le_u32(hash_data, level + 0x10).min(63),
+                    )
+                })
+                .collect())
+        } else {
+            // https://switchbrew.org/wiki/NCA#HierarchicalSha256Data
+            let block_size = le_u32(hash_data, 0x20) as u64;
+            let layer_count = le_u32(hash_data, 0x24) as usize;
+            if !(1..=5).contains(&layer_count) {
+                bail!("Invalid HierarchicalSha256 layer count {}", layer_count);
+            }
+            Ok((0..layer_count)
+                .map(|i| {
+                    let layer = 0x28 + i * 0x10;
+                    let size = le_u64(hash_data, layer + 0x8);
+                    // The master hash covers the whole first layer
+                    let block_size = if i == 0 { size } else { block_size };
+                    (le_u64(hash_data, layer), size, block_size)
+                })
+                .collect())
+        }
+    }
+    /// SHA-256 of the first hash level.
+    fn master_hash(&self) -> &[u8] {
+        if self.is_ivfc() {
+            &self.raw[0x8 + 0xC0..0x8 + 0xE0]
+        } else {
+            &self.raw[0x8..0x28]
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NcaSection {
+    pub index: usize,
+    /// Offset of the section relative to the start of the NCA.
+    pub offset: u64,
+    pub size: u64,
+    pub fs_header: NcaFsHeader,
+}
+
+/// https://switchbrew.org/wiki/NCA#NCA_Header
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct NcaHeader {
+    /// NCA format version, i.e. 3 for "NCA3".
+    pub version: u8,
+    pub distribution_type: DistributionType,
+    pub content_type: ContentType,
+    /// The greater of the two key generation fields.
+    pub key_generation: u8,
+    pub key_area_key_index: u8,
+    pub content_size: u64,
+    #[derivative(Debug(format_with = "program_id_fmt"))]
+    pub program_id: ProgramID,
+    pub content_index: u32,
+    pub sdk_addon_version: u32,
+    #[derivative(Debug(format_with = "rights_id_fmt"))]
+    pub rights_id: RightsID,
+    pub sections: Vec<NcaSection>,
+    #[derivative(Debug = "ignore")]
+    pub encrypted_key_area: [[u8; 0x10]; 4],
+    /// Decrypted header.
+    #[derivative(Debug = "ignore")]
+    pub raw: Box<[u8; NCA_HEADER_SIZE]>,
+}
+
+impl NcaHeader {
+    /// Decrypts and parses the header, the `reader` should start at the beginning of the NCA.
+    pub fn from_reader<R: Read + Seek>(keyset: &Keyset, reader: &mut R) -> Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut encrypted = Box::new([0u8; NCA_HEADER_SIZE]);
+        reader.read_exact(encrypted.as_mut())?;
+
+        let xts = AesXts::new(&keyset.header_key);
+        let mut raw = encrypted.clone();
+        xts.decrypt(raw.as_mut(), 0, SECTOR_SIZE);
+
+        let version = match &raw[0x200..0x204] {
+            b"NCA3" => 3,
+            b"NCA2" => {
+                // Each FsHeader is encrypted on its own as sector 0
+                for i in 0..SECTION_COUNT {
+                    let start = FS_HEADER_OFFSET + i * FS_HEADER_SIZE;
+                    let fs_header = &mut raw[start..start + FS_HEADER_SIZE];
+                    fs_header.copy_from_slice(&encrypted[start..start + FS_HEADER_SIZE]);
+                    xts.decrypt(fs_header, 0, SECTOR_SIZE);
+                }
+                2
+            }
+            b"NCA0" => bail!("NCA0 is not supported"),
+            _ => bail!("Invalid NCA magic, the 'header_key' might be incorrect"),
+        };
+
+        let distribution_type = DistributionType::from_repr(raw[0x204])
+            .ok_or_else(|| eyre!("Unknown DistributionType {:#x}", raw[0x204]))?;
+        let content_type = ContentType::from_repr(raw[0x205])
+            .ok_or_else(|| eyre!("Unknown ContentType {:#x}", raw[0x205]))?;
+
+        let mut sections = vec![];
+        for index in 0..SECTION_COUNT {
+            let entry = 0x240 + index * 0x10;
+            let start = le_u32(raw.as_ref(), entry) as u64 * MEDIA_UNIT_SIZE;
+            let end = le_u32(raw.as_ref(), entry + 0x4) as u64 * MEDIA_UNIT_SIZE;
+            if end <= start {
+                continue;
+            }
+            let fs_header_offset = FS_HEADER_OFFSET + index * FS_HEADER_SIZE;
+            let fs_header: Box<[u8; FS_HEADER_SIZE]> = Box::new(
+                raw[fs_header_offset..fs_header_offset + FS_HEADER_SIZE]
+                    .try_into()
+                    .expect("Slice is of len 0x200"),
+            );
+            sections.push(NcaSection {
+                index,
+                offset: start,
+                size: end - start,
+                fs_header: NcaFsHeader {
+                    version: u16::from_le_bytes([fs_header[0x0], fs_header[0x1]]),
+                    fs_type: FsType::from_repr(fs_header[0x2])
+                        .ok_or_else(|| eyre!("Unknown FsType {:#x}", fs_header[0x2]))?,
+                    hash_type: HashType::from_repr(fs_header[0x3])
+                        .ok_or_else(|| eyre!("Unknown HashType {:#x}", fs_header[0x3]))?,
+                    encryption_type: EncryptionType::from_repr(fs_header[0x4])
+                        .ok_or_else(|| eyre!("Unknown EncryptionType {:#x}", fs_header[0x4]))?,
+                    upper_iv: le_u64(fs_header.as_ref(), 0x140),
+                    raw: fs_header,
+                },
+            });
+        }
+
+        let mut encrypted_key_area = [[0u8; 0x10]; 4];
+        for (i, key) in encrypted_key_area.iter_mut().enumerate() {
+            key.copy_from_slice(&raw[0x300 + i * 0x10..0x310 + i * 0x10]);
+        }
+
+        Ok(Self {
+            version,
+            distribution_type,
+            content_type,
+            key_generation: raw[0x206].max(raw[0x220]),
+            key_area_key_index: raw[0x207],
+            content_size: le_u64(raw.as_ref(), 0x208),
+            // ProgramID is stored in little-endian but always displayed in big-endian
+            program_id: le_u64(raw.as_ref(), 0x210).to_be_bytes(),
+            content_index: le_u32(raw.as_ref(), 0x218),
+            sdk_addon_version: le_u32(raw.as_ref(), 0x21C),
+            rights_id: raw[0x230..0x240].try_into().expect("Slice is of len 0x10"),
+            sections,
+            encrypted_key_area,
+            raw,
+        })
+    }
+    /// Titlekey crypto is used instead of the key area if the RightsID is set.
+    pub fn has_rights_id(&self) -> bool {
+        self.rights_id != RightsID::default()
+    }
+    /// Index of the master key needed for this NCA.
+    pub fn master_key_revision(&self) -> u8 {
+        self.key_generation.saturating_sub(1)
+    }
+    /// See [`Nca::id_offset`].
+    pub fn id_offset(&self) -> u8 {
+        (u64::from_be_bytes(self.program_id) & ID_OFFSET_MASK) as u8
+    }
+    /// Highest master key revision needed to decrypt the sections.
+    pub fn required_key_revision(&self) -> u8 {
+        if self.has_rights_id() {
+            self.master_key_revision()
+                .max(rights_id_key_revision(&self.rights_id))
+        } else {
+            self.master_key_revision()
+        }
+    }
+}
 
 /// https://switchbrew.org/wiki/NCA\
 /// Provides some methods relating to Nca, an encrypted content archive.
@@ -45,19 +343,96 @@ pub struct Nca {
     #[derivative(Debug(format_with = "program_id_fmt"))]
     pub program_id: ProgramID,
     pub content_type: ContentType,
+    pub header: NcaHeader,
 }
 
 fn program_id_fmt(program_id: &ProgramID, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
     fmt.write_fmt(format_args!("{:?}", hex::encode(program_id)))
 }
 
+fn rights_id_fmt(rights_id: &RightsID, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+    fmt.write_fmt(format_args!("{:?}", hex::encode(rights_id)))
+}
+
+/// A problem with a NCA, found by [`Nca::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NcaIssue {
+    /// The keyfile lacks `nca_hdr_fixed_key_modulus_{key_generation:02x}`.
+    SignatureUnverified {
+        key_generation: u8,
+    },
+    /// The header was modified after signing, which is expected of repacked NCAs.
+    BadSignature,
+    /// The FsHeader doesn't match its hash in the header.
+    BadFsHeaderHash {
+        section: usize,
+    },
+    /// The first hash level doesn't match the master hash, the data is mostly
+    /// decrypted with the wrong key.
+    BadMasterHash {
+        section: usize,
+    },
+    /// A block of a hash level doesn't match its hash, the data itself is damaged.
+    BadBlock {
+        section: usize,
+        level: usize,
+        offset: u64,
+    },
+    SectionUnverified {
+        section: usize,
+        reason: String,
+    },
+}
+
+impl NcaIssue {
+    /// Whether the issue can only be explained by a bad dump, rather than by the keys.
+    pub fn is_corrupt(&self) -> bool {
+        matches!(
+            self,
+            NcaIssue::BadFsHeaderHash { .. } | NcaIssue::BadBlock { .. }
+        )
+    }
+}
+
+impl fmt::Display for NcaIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NcaIssue::SignatureUnverified { key_generation } => write!(
+                f,
+                "Header signature couldn't be verified, 'nca_hdr_fixed_key_modulus_{:02x}' is missing",
+                key_generation
+            ),
+            NcaIssue::BadSignature => write!(f, "Header signature is invalid, it was modified"),
+            NcaIssue::BadFsHeaderHash { section } => {
+                write!(f, "FsHeader of section {} is corrupt", section)
+            }
+            NcaIssue::BadMasterHash { section } => write!(
+                f,
+                "Section {} doesn't match its master hash, the keys are likely wrong",
+                section
+            ),
+            NcaIssue::BadBlock {
+                section,
+                level,
+                offset,
+            } => write!(
+                f,
+                "Section {} is corrupt at level {} offset {:#x}",
+                section, level, offset
+            ),
+            NcaIssue::SectionUnverified { section, reason } => {
+                write!(f, "Section {} couldn't be verified, {}", section, reason)
+            }
+        }
+    }
+}
+
 // TODO?: Add the stdout to the logs in case an error is catched in main
 
 impl Nca {
-    pub fn try_new<P: AsRef<Path>>(reader: &Backend, file_path: P) -> Result<Self> {
-        // Can't rely on Backend tools to check for NCA file because they're
-        // pretty bad cli tools (don't even have non zero exit status on failure)
-        // excluding Hactoolnet.
+    const KEY_AREA_CTR_INDEX: usize = 2;
+
+    pub fn try_new<P: AsRef<Path>>(keyset: &Keyset, file_path: P) -> Result<Self> {
         if !file_path.as_ref().is_file() || !ext_matches(file_path.as_ref(), "nca") {
             bail!("'{}' is not a NCA file", file_path.as_ref().display())
         }
@@ -65,104 +440,175 @@ impl Nca {
         info!(
             nca = %file_path.as_ref().display(),
             size = %get_fmt_size(file_path.as_ref()).unwrap_or_default(),
-            "Identifying TitleID and ContentType",
+            "Reading NCA header",
         );
 
-        let output = Command::new(reader.path())
-            .args([file_path.as_ref()])
-            .output()?; // All streams are piped
-        let stderr = filter_out_key_mismatches(&output.stderr);
-        if !output.status.success() {
-            warn!(
-                nca = %file_path.as_ref().display(),
-                backend = ?reader.kind(),
-                %stderr,
-                "Encountered an error while viewing info",
-            );
-        }
-        let stdout = String::from_utf8_lossy(&output.stdout);
-
-        let program_id_pat = match reader.kind() {
-            #[cfg(all(
-                target_arch = "x86_64",
-                any(target_os = "windows", target_os = "linux")
-            ))]
-            BackendKind::Hactoolnet => "TitleID:",
-            // On all supported platforms
-            BackendKind::Hactool => "Title ID:",
-            BackendKind::Hac2l => "Program Id:",
-            _ => unimplemented!(),
-        };
-        let mut program_id = [0u8; 8];
-        stdout
-            .lines()
-            .find(|line| line.contains(program_id_pat))
-            .and_then(|line| line.trim().split(' ').last())
-            .map(|id_str| hex::decode_to_slice(id_str, program_id.as_mut()))
-            .ok_or_else(|| {
-                eyre!(
-                    "Failed to process ProgramID of '{}'",
-                    file_path.as_ref().display()
-                )
-            })??;
-        debug!(program_id = ?hex::encode(program_id));
-
-        let content_type = match stdout
-            .lines()
-            .find(|line| line.contains("Content Type:"))
-            .and_then(|line| line.trim().split(' ').last().map(ContentType::from_str))
-            .transpose()
-        {
-            Ok(content_type) => content_type.ok_or_else(|| {
-                eyre!(
-                    "Failed to process ContentType of '{}'",
-                    file_path.as_ref().display()
-                )
-            })?,
-            Err(err) => {
-                // Unknown ContentType
-                warn!(
-                    nca = %file_path.as_ref().display(),
-                    backend = ?reader.kind(),
-                    stdout = %stdout,
-                    "Dumping stdout"
-                );
-                bail!(err);
-            }
-        };
-        debug!(?content_type);
+        let header = NcaHeader::from_reader(
+            keyset,
+            &mut BufReader::new(fs::File::open(file_path.as_ref())?),
+        )
+        .map_err(|err| {
+            eyre!(
+                "Failed to read header of '{}': {}",
+                file_path.as_ref().display(),
+                err
+            )
+        })?;
+        debug!(?header);
 
         Ok(Self {
             path: file_path.as_ref().to_owned(),
-            program_id,
-            content_type,
+            program_id: header.program_id,
+            content_type: header.content_type,
+            header,
         })
     }
     pub fn get_program_id(&self) -> String {
         hex::encode(self.program_id)
     }
-    pub fn unpack_romfs<P: AsRef<Path>>(&self, extractor: &Backend, romfs_dir: P) -> Result<()> {
-        info!(nca = %self.path.display(), "Unpacking RomFS from NCA");
-        let output = Command::new(extractor.path())
-            .args([
-                self.path.as_path(),
-                "--romfsdir".as_ref(),
-                romfs_dir.as_ref(),
-            ])
-            .stderr(Stdio::piped())
-            .spawn()?
-            .wait_with_output()?;
-        let stderr = filter_out_key_mismatches(&output.stderr);
-        eprint!("{}", stderr);
-        if !output.status.success() {
-            warn!(
-                nca = %self.path.display(),
-                backend = ?extractor.kind(),
-                %stderr,
-                "Encountered an error while unpacking RomFS from NCA",
+    /// Id offset of the program within its application, non zero only for the
+    /// extra programs of a multi-program application.
+    pub fn id_offset(&self) -> u8 {
+        self.header.id_offset()
+    }
+    /// Key used for the AES-CTR encrypted sections.
+    pub fn section_key(&self, keyset: &Keyset) -> Result<AesKey> {
+        if self.header.has_rights_id() {
+            keyset.title_key(&self.header.rights_id)
+        } else {
+            let mut key = self.header.encrypted_key_area[Nca::KEY_AREA_CTR_INDEX];
+            aes_ecb_decrypt(
+                &keyset.key_area_key(
+                    self.header.key_area_key_index,
+                    self.header.master_key_revision(),
+                )?,
+                &mut key,
             );
-            bail!("Encountered an error while unpacking RomFS from NCA");
+            Ok(key)
         }
+    }
+    /// Provides a decrypted reader over the section at `index`.
+    pub fn open_section(&self, keyset: &Keyset, index: usize) -> Result<Box<dyn ReadSeek>> {
+        let section = self
+            .header
+            .sections
+            .iter()
+            .find(|section| section.index == index)
+            .ok_or_else(|| eyre!("'{}' has no section {}", self.path.display(), index))?;
+        let storage = SubStorage::new(
+            BufReader::new(fs::File::open(&self.path)?),
+            section.offset,
+            section.size,
+        );
+
+        Ok(match section.fs_header.encryption_type {
+            EncryptionType::None => Box::new(storage),
+            EncryptionType::AesCtr | EncryptionType::AesCtrSkipLayerHash => {
+                Box::new(AesCtrStorage::new(
+                    storage,
+                    &self.section_key(keyset)?,
+                    section.fs_header.upper_iv,
+                    section.offset,
+                ))
+            }
+            EncryptionType::AesCtrEx | EncryptionType::AesCtrExSkipLayerHash => {
+                let patch_info = PatchInfo::from_fs_header(&section.fs_header.raw[..])?;
+                let storage = AesCtrStorage::new(
+                    storage,
+                    &self.section_key(keyset)?,
+                    section.fs_header.upper_iv,
+                    section.offset,
+                );
+                Box::new(AesCtrExStorage::new(
+                    &patch_info.aes_ctr_ex,
+                    storage,
+                    section.fs_header.upper_iv,
+                )?)
+            }
+            kind => bail!(
+                "Section {} of '{}' uses unsupported {:?} encryption",
+                index,
+                self.path.display(),
+                kind
+            ),
+        })
+    }
+    /// Provides a decrypted reader over the FS data of the first section of `fs_type`.
+    pub fn open_fs(
+        &self,
+        keyset: &Keyset,
+        fs_type: FsType,
+    ) -> Result<SubStorage<Box<dyn ReadSeek>>> {
+        let section = self.find_section(fs_type)?;
+        let (offset, size) = section.fs_header.data_region(section.size)?;
+        Ok(SubStorage::new(
+            self.open_section(keyset, section.index)?,
+            offset,
+            size,
+        ))
+    }
+    /// First section of `fs_type`.
+    pub fn find_section(&self, fs_type: FsType) -> Result<&NcaSection> {
+        self.header
+            .sections
+            .iter()
+            .find(|section| section.fs_header.fs_type == fs_type)
+            .ok_or_else(|| eyre!("'{}' has no {:?} section", self.path.display(), fs_type))
+    }
+    /// Provides the RomFS of this patch NCA applied over the RomFS of `base`.
+    ///
+    /// Falls back to the RomFS of this NCA if it isn't a patch, i.e. has no BKTR tables.
+    pub fn open_patched_romfs(
+        &self,
+        keyset: &Keyset,
+        base: &Nca,
+    ) -> Result<(RomFs, SubStorage<Box<dyn ReadSeek>>)> {
+        let section = self.find_section(FsType::RomFs)?;
+        let patch_info = PatchInfo::from_fs_header(&section.fs_header.raw[..])?;
+        if patch_info.indirect.size == 0 {
+            debug!(nca = %self.path.display(), "Not a patch NCA, reading RomFS as is");
+            return self.open_romfs(keyset);
+        }
+
+        let base_section = base.find_section(FsType::RomFs)?;
+        let indirect = IndirectStorage::new(
+            &patch_info.indirect,
+            &mut self.open_section(keyset, section.index)?,
+            base.open_section(keyset, base_section.index)?,
+            self.open_section(keyset, section.index)?,
+        )
+        .map_err(|err| eyre!("Failed to apply '{}': {}", self.path.display(), err))?;
+        let (offset, size) = section.fs_header.data_region(indirect.len())?;
+        let mut reader = SubStorage::new(Box::new(indirect) as Box<dyn ReadSeek>, offset, size);
+        let romfs = RomFs::from_reader(&mut reader).map_err(|err| {
+            eyre!(
+                "Failed to read patched RomFS of '{}': {}",
+                self.path.display(),
+                err
+            )
+        })?;
+        Ok((romfs, reader))
+    }
+    pub fn open_romfs(&self, keyset: &Keyset) -> Result<(RomFs, SubStorage<Box<dyn ReadSeek>>)> {
+        let mut reader = self.open_fs(keyset, FsType::RomFs)?;
+        let romfs = RomFs::from_reader(&mut reader)
+            .map_err(|err| eyre!("Failed to read RomFS of '{}': {}", self.path.display(), err))?;
+        Ok((romfs, reader))
+    }
+    /// Reads a single file from the RomFS.
+    pub fn read_romfs_file(&self, keyset: &Keyset, path: &str) -> Result<Vec<u8>> {
+        let (romfs, mut reader) = self.open_romfs(keyset)?;
+        let entry = romfs
+            .find(path)
+            .ok_or_else(|| eyre!("Couldn't find '{}' in '{}'", path, self.path.display()))?;
+        let mut buf = Vec::with_capacity(entry.size as _);
+        RomFs::copy_entry(&mut reader, entry, &mut buf)?;
+        Ok(buf)
+    }
+    pub fn unpack_romfs<P: AsRef<Path>>(&self, keyset: &Keyset, romfs_dir: P) -> Result<()> {
+        info!(nca = %self.path.display(), "Unpacking RomFS from NCA");
+        let (romfs, mut reader) = self.open_romfs(keyset)?;
+        romfs.extract(&mut reader, "", romfs_dir.as_ref())?;
 
         info!(
             nca = %self.path.display(),
@@ -172,37 +618,51 @@ impl Nca {
 
         Ok(())
     }
+    pub fn unpack_exefs<P: AsRef<Path>>(&self, keyset: &Keyset, exefs_dir: P) -> Result<()> {
+        info!(nca = %self.path.display(), "Unpacking ExeFS from NCA");
+        let mut reader = self.open_fs(keyset, FsType::PartitionFs)?;
+        let exefs = Pfs0::from_reader(&mut reader)
+            .map_err(|err| eyre!("Failed to read ExeFS of '{}': {}", self.path.display(), err))?;
+        fs::create_dir_all(exefs_dir.as_ref())?;
+        for entry in &exefs.entries {
+            let dest = exefs_dir.as_ref().join(&entry.name);
+            Pfs0::copy_entry(
+                &mut reader,
+                entry,
+                &mut BufWriter::new(fs::File::create(dest)?),
+            )?;
+        }
+
+        info!(
+            nca = %self.path.display(),
+            exefs = %exefs_dir.as_ref().display(),
+            "Unpacked ExeFS from NCA"
+        );
+
+        Ok(())
+    }
+    /// Unpacks the RomFS of `aux` applied over this NCA and the ExeFS of `aux`.
+    ///
+    /// `aux` can be this NCA itself to only unpack the base.
     pub fn unpack_all<P: AsRef<Path>, Q: AsRef<Path>>(
         &self,
-        extractor: &Backend,
+        keyset: &Keyset,
         aux: &Nca,
         romfs_dir: P,
         exefs_dir: Q,
     ) -> Result<()> {
         info!(basenca = %self.path.display(), nca = %aux.path.display(), "Unpacking RomFS/ExeFS from NCAs");
-        let mut cmd = Command::new(extractor.path());
-        cmd.args([
-            "--basenca".as_ref(),
-            self.path.as_path(),
-            aux.path.as_path(),
-            "--romfsdir".as_ref(),
-            romfs_dir.as_ref(),
-            "--exefsdir".as_ref(),
-            exefs_dir.as_ref(),
-        ])
-        .stderr(Stdio::piped());
-        let output = cmd.spawn()?.wait_with_output()?;
-        let stderr = filter_out_key_mismatches(&output.stderr);
-        eprint!("{}", stderr);
-        if !output.status.success() {
-            error!(
-                backend = ?extractor.kind(),
-                code = ?output.status.code(),
-                %stderr,
-                "Encountered an error while unpacking RomFS/ExeFS from NCAs"
-            );
-            bail!("Encountered an error while unpacking RomFS/ExeFS from NCAs");
+        if aux.find_section(FsType::RomFs).is_ok() {
+            let (romfs, mut reader) = if aux.path == self.path {
+                self.open_romfs(keyset)?
+            } else {
+                aux.open_patched_romfs(keyset, self)?
+            };
+            romfs.extract(&mut reader, "", romfs_dir.as_ref())?;
+        } else {
+            warn!(nca = %aux.path.display(), "NCA has no RomFS, skipping");
         }
+        aux.unpack_exefs(keyset, exefs_dir.as_ref())?;
 
         info!(
             basenca = %self.path.display(),
@@ -213,8 +673,8 @@ impl Nca {
         );
         Ok(())
     }
-    pub fn pack_program<'a, P, Q, R, K, I>(
-        readers: I,
+    pub fn pack_program<P, Q, R, K>(
+        keyset: &Keyset,
         packer: &Backend,
         program_id: &str,
         keyfile: K,
@@ -227,7 +687,6 @@ impl Nca {
         Q: AsRef<Path>,
         R: AsRef<Path>,
         K: AsRef<Path>,
-        I: IntoIterator<Item = &'a Backend>,
     {
         info!(
             romfs = ?romfs_dir.as_ref(),
@@ -266,12 +725,7 @@ impl Nca {
             );
         }
 
-        let patched_nca = readers
-            .into_iter()
-            .inspect(|reader| info!("Using {:?} as reader", reader.kind()))
-            .map(|reader| nca_with_kind(reader, outdir.as_ref(), ContentType::Program))
-            .find(|filtered| filtered.is_some())
-            .flatten()
+        let patched_nca = nca_with_kind(keyset, outdir.as_ref(), ContentType::Program)
             .ok_or_else(|| eyre!("Failed to pack FS files to NCA"))?
             .remove(0);
         info!(
@@ -281,95 +735,322 @@ impl Nca {
         );
         Ok(patched_nca)
     }
-    pub fn create_meta<K, O, T>(
-        packer: &Backend,
-        program_id: &str,
-        keyfile: K,
+    /// Builds a Meta NCA for `cnmt` in `outdir`, the key generation and SDK version are
+    /// taken from `program`.
+    ///
+    /// The CNMT is read back from the built NCA and checked against `cnmt`.
+    pub fn create_meta<O, T>(
+        keyset: &Keyset,
+        cnmt: &Cnmt,
         program: &Nca,
-        control: &Nca,
         outdir: O,
         tempdir_in: T,
-    ) -> Result<()>
+    ) -> Result<Nca>
     where
-        K: AsRef<Path>,
         O: AsRef<Path>,
         T: AsRef<Path>,
     {
-        // figure out a solution for this
-        info!(?program.path, ?control.path, "Generating Meta NCA");
+        info!(title_id = %cnmt.get_title_id(), version = cnmt.version, "Generating Meta NCA");
 
-        let temp_outdir = tempfile::tempdir_in(tempdir_in.as_ref())?;
-        let mut cmd = Command::new(packer.path());
-        cmd.args([
-            "--keyset".as_ref(),
-            keyfile.as_ref(),
-            "--type".as_ref(),
-            "nca".as_ref(),
-            "--ncatype".as_ref(),
-            "meta".as_ref(),
-            "--titletype".as_ref(),
-            "application".as_ref(),
-            "--programnca".as_ref(),
-            program.path.as_path(),
-            "--controlnca".as_ref(),
-            control.path.as_path(),
-            "--titleid".as_ref(),
-            program_id.as_ref(),
-            "--outdir".as_ref(),
-            temp_outdir.path(),
-        ])
-        .stderr(Stdio::piped());
-        let output = cmd.spawn()?.wait_with_output()?;
-        let stderr = filter_out_key_mismatches(&output.stderr);
-        eprint!("{}", stderr);
-        if !output.status.success() {
-            warn!(
-                backend = ?packer.kind(),
-                code = ?output.status.code(),
-                %stderr,
-                "Encountered an error while generating Meta NCA"
+        // PFS0 holding the CNMT
+        let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+        let cnmt_path = temp_dir.path().join(cnmt.filename());
+        fs::write(&cnmt_path, cnmt.to_bytes())?;
+        let mut pfs0 = vec![];
+        Pfs0Builder::new()
+            .add_file(cnmt.filename(), &cnmt_path)
+            .write(&mut pfs0)?;
+
+        // https://switchbrew.org/wiki/NCA#HierarchicalSha256Data
+        let hash_table = pfs0
+            .chunks(META_HASH_BLOCK_SIZE)
+            .flat_map(Sha256::digest)
+            .collect::<Vec<_>>();
+        let pfs0_offset = align(hash_table.len() as u64, MEDIA_UNIT_SIZE);
+        let mut section = hash_table.clone();
+        section.resize(pfs0_offset as usize, 0);
+        section.extend(&pfs0);
+        section.resize(align(section.len() as u64, MEDIA_UNIT_SIZE) as usize, 0);
+
+        let mut fs_header = [0u8; FS_HEADER_SIZE];
+        fs_header[0x0..0x2].copy_from_slice(&2u16.to_le_bytes());
+        fs_header[0x2] = FsType::PartitionFs as u8;
+        fs_header[0x3] = HashType::HierarchicalSha256 as u8;
+        fs_header[0x4] = EncryptionType::AesCtr as u8;
+        let hash_data = &mut fs_header[0x8..0x100];
+        hash_data[..0x20].copy_from_slice(&Sha256::digest(&hash_table));
+        hash_data[0x20..0x24].copy_from_slice(&(META_HASH_BLOCK_SIZE as u32).to_le_bytes());
+        hash_data[0x24..0x28].copy_from_slice(&2u32.to_le_bytes());
+        hash_data[0x30..0x38].copy_from_slice(&(hash_table.len() as u64).to_le_bytes());
+        hash_data[0x38..0x40].copy_from_slice(&pfs0_offset.to_le_bytes());
+        hash_data[0x40..0x48].copy_from_slice(&(pfs0.len() as u64).to_le_bytes());
+
+        let key_generation = program.header.key_generation;
+        let revision = program.header.master_key_revision();
+        // Deterministic, so that the same CNMT always results in the same NCA
+        let section_key: AesKey = Sha256::digest(&section)[..0x10]
+            .try_into()
+            .expect("Slice is of len 0x10");
+        let mut key_area = [[0u8; 0x10]; 4];
+        key_area[Nca::KEY_AREA_CTR_INDEX] = section_key;
+        let key_area_key = keyset.key_area_key(0, revision)?;
+
+        let content_size = NCA_HEADER_SIZE as u64 + section.len() as u64;
+        let mut header = [0u8; NCA_HEADER_SIZE];
+        header[0x200..0x204].copy_from_slice(b"NCA3");
+        header[0x204] = DistributionType::Download as u8;
+        header[0x205] = ContentType::Meta as u8;
+        header[0x206] = key_generation.min(2);
+        header[0x207] = 0;
+        header[0x208..0x210].copy_from_slice(&content_size.to_le_bytes());
+        header[0x210..0x218].copy_from_slice(&cnmt.title_id.to_le_bytes());
+        header[0x21C..0x220].copy_from_slice(&program.header.sdk_addon_version.to_le_bytes());
+        header[0x220] = if key_generation > 2 {
+            key_generation
+        } else {
+            0
+        };
+        header[0x240..0x244]
+            .copy_from_slice(&((NCA_HEADER_SIZE as u64 / MEDIA_UNIT_SIZE) as u32).to_le_bytes());
+        header[0x244..0x248]
+            .copy_from_slice(&((content_size / MEDIA_UNIT_SIZE) as u32).to_le_bytes());
+        header[0x248] = 0x1;
+        header[0x280..0x2A0].copy_from_slice(&Sha256::digest(fs_header));
+        for (i, key) in key_area.iter_mut().enumerate() {
+            aes_ecb_encrypt(&key_area_key, key);
+            header[0x300 + i * 0x10..0x310 + i * 0x10].copy_from_slice(key);
+        }
+        header[FS_HEADER_OFFSET..FS_HEADER_OFFSET + FS_HEADER_SIZE].copy_from_slice(&fs_header);
+
+        AesXts::new(&keyset.header_key).encrypt(&mut header, 0, SECTOR_SIZE);
+        AesCtr::new(&section_key).apply(
+            &mut section,
+            AesCtrStorage::<fs::File>::counter(0, NCA_HEADER_SIZE as u64),
+        );
+
+        let mut hasher = Sha256::new();
+        hasher.update(header);
+        hasher.update(&section);
+        let content_id = hex::encode(&hasher.finalize()[..0x10]);
+        let meta_path = outdir.as_ref().join(format!("{}.cnmt.nca", content_id));
+        let mut writer = BufWriter::new(fs::File::create(&meta_path)?);
+        writer.write_all(&header)?;
+        writer.write_all(&section)?;
+        writer.flush()?;
+        drop(writer);
+
+        // Verifying what was written
+        let meta = Nca::try_new(keyset, &meta_path)?;
+        let written = Cnmt::from_nca(keyset, &meta)?;
+        if written.title_id != cnmt.title_id
+            || written.version != cnmt.version
+            || written.content_records != cnmt.content_records
+        {
+            bail!(
+                "CNMT in '{}' doesn't match the generated content records",
+                meta_path.display()
             );
         }
 
-        for entry in WalkDir::new(temp_outdir.path())
-            .min_depth(1)
-            .into_iter()
-            .filter_map(|e| e.ok())
+        info!(nca = %meta_path.display(), "Generated Meta NCA");
+        Ok(meta)
+    }
+    /// SHA-256 of the whole NCA, the first half of which is its content id.
+    pub fn hash(&self) -> Result<[u8; 0x20]> {
+        let mut hasher = Sha256::new();
+        io::copy(
+            &mut BufReader::new(fs::File::open(&self.path)?),
+            &mut hasher,
+        )?;
+        Ok(hasher.finalize().into())
+    }
+    /// Checks the header signature, the FsHeader hashes and the hash levels of every section.
+    ///
+    /// Patch sections are left unverified since their data depends on the base NCA.
+    pub fn verify(&self, keyset: &Keyset) -> Result<Vec<NcaIssue>> {
+        info!(nca = %self.path.display(), "Verifying NCA");
+        let raw = &self.header.raw;
+        let mut issues = vec![];
+
+        // https://switchbrew.org/wiki/NCA#NCA_Header
+        let key_generation = raw[0x221];
+        match keyset
+            .nca_hdr_fixed_key_modulus
+            .get(key_generation as usize)
+            .and_then(Option::as_ref)
         {
-            if entry.path().is_file() && ext_matches(entry.path(), "nca") {
-                // Moving NCA file from temp outdir to outdir
-                move_file(entry.path(), outdir.as_ref().join(entry.file_name()))?;
-                info!(outdir = ?outdir.as_ref(), "Generated Meta NCA");
-                return Ok(());
+            Some(key) => {
+                if key
+                    .verify(
+                        Pss::new::<Sha256>(),
+                        &Sha256::digest(&raw[0x200..0x400]),
+                        &raw[..0x100],
+                    )
+                    .is_err()
+                {
+                    issues.push(NcaIssue::BadSignature);
+                }
+            }
+            None => issues.push(NcaIssue::SignatureUnverified { key_generation }),
+        }
+
+        for section in &self.header.sections {
+            let hash = 0x280 + section.index * 0x20;
+            if Sha256::digest(&section.fs_header.raw[..]).as_slice() != &raw[hash..hash + 0x20] {
+                issues.push(NcaIssue::BadFsHeaderHash {
+                    section: section.index,
+                });
+                continue;
+            }
+            match self.verify_section(keyset, section) {
+                Ok(Some(issue)) => issues.push(issue),
+                Ok(None) => {}
+                Err(err) => issues.push(NcaIssue::SectionUnverified {
+                    section: section.index,
+                    reason: err.to_string(),
+                }),
             }
         }
 
-        bail!("Failed to generate Meta NCA");
+        for issue in &issues {
+            warn!(nca = %self.path.display(), %issue);
+        }
+        Ok(issues)
+    }
+    /// Stops at the first bad block, as every level below it can't be trusted anyway.
+    fn verify_section(&self, keyset: &Keyset, section: &NcaSection) -> Result<Option<NcaIssue>> {
+        let fs_header = &section.fs_header;
+        let reason = match (fs_header.encryption_type, fs_header.hash_type) {
+            (EncryptionType::AesCtrEx | EncryptionType::AesCtrExSkipLayerHash, _) => {
+                "it's a patch of the base NCA"
+            }
+            (EncryptionType::AesCtrSkipLayerHash, _) => "its hash layers are skipped",
+            (_, HashType::None) => "it isn't hashed",
+            (
+                _,
+                HashType::AutoSha3
+                | HashType::HierarchicalSha3256
+                | HashType::HierarchicalIntegritySha3,
+            ) => "SHA3-256 hashes aren't supported",
+            _ => "",
+        };
+        if !reason.is_empty() {
+            return Ok(Some(NcaIssue::SectionUnverified {
+                section: section.index,
+                reason: reason.into(),
+            }));
+        }
+
+        let pad = fs_header.is_ivfc();
+        let mut reader = self.open_section(keyset, section.index)?;
+        let levels = fs_header.hash_levels()?;
+        let mut table = fs_header.master_hash().to_vec();
+        for (level, &(offset, size, block_size)) in levels.iter().enumerate() {
+            if offset
+                .checked_add(size)
+                .map_or(true, |end| end > section.size)
+            {
+                bail!("Hash level {} is out of the section bounds", level);
+            }
+            debug!(
+                section = section.index,
+                level, offset, size, "Verifying hash level"
+            );
+            if let Some(bad) = find_bad_block(&mut reader, &table, offset, size, block_size, pad)? {
+                return Ok(Some(if level == 0 {
+                    NcaIssue::BadMasterHash {
+                        section: section.index,
+                    }
+                } else {
+                    NcaIssue::BadBlock {
+                        section: section.index,
+                        level,
+                        offset: offset + bad,
+                    }
+                }));
+            }
+            if level + 1 == levels.len() {
+                break;
+            }
+            // This level is the hash table of the next one
+            table = vec![0u8; size as usize];
+            reader.seek(SeekFrom::Start(offset))?;
+            reader.read_exact(&mut table)?;
+        }
+        Ok(None)
+    }
+}
+
+fn align(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) / alignment * alignment
+}
+
+/// Offset within the level of the first block of `size` bytes at `offset` that doesn't match
+/// its SHA-256 in `table`.
+///
+/// With `pad` the last block is hashed zero padded to `block_size`, as IVFC does.
+fn find_bad_block<R: Read + Seek>(
+    reader: &mut R,
+    table: &[u8],
+    offset: u64,
+    size: u64,
+    block_size: u64,
+    pad: bool,
+) -> Result<Option<u64>> {
+    if block_size == 0 {
+        bail!("Invalid hash block size 0");
+    }
+    let block_count = (size + block_size - 1) / block_size;
+    if (table.len() as u64) < block_count * 0x20 {
+        bail!("Hash table is too small for {} blocks", block_count);
+    }
+
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut block = vec![0u8; block_size as usize];
+    for (i, hash) in table
+        .chunks_exact(0x20)
+        .take(block_count as usize)
+        .enumerate()
+    {
+        let start = i as u64 * block_size;
+        let len = block_size.min(size - start) as usize;
+        reader.read_exact(&mut block[..len])?;
+        let data = if pad {
+            block[len..].fill(0);
+            &block[..]
+        } else {
+            &block[..len]
+        };
+        if Sha256::digest(data).as_slice() != hash {
+            return Ok(Some(start));
+        }
     }
+    Ok(None)
 }
 
-/// Returns filtered NCA(s) in descending order of size.
+/// Returns filtered NCA(s) in the order of the content records of the CNMT(s) in `from`,
+/// i.e. by their id offset.
+///
+/// Falls back to the descending order of size if there's no readable Meta NCA.
 ///
 /// For eg-
-/// ```
-/// // This'll return the largest Control type NCA in "."
-/// nca_with_filters(
-///     Backend::new(BackendKind::Hactoolnet),
-///     ".",
-///     HashSet::from([NcaType::Control]),
-/// )
-/// .get(&NcaType::Control)
-/// .unwrap()[0];
+/// ```no_run
+/// # use std::collections::HashSet;
+/// # use hac::{keys::Keyset, vfs::nca::{nca_with_filters, ContentType}};
+/// # let keyset = Keyset::try_new("prod.keys").unwrap();
+/// // This'll return the main Control type NCA in "."
+/// let control = &nca_with_filters(&keyset, ".", &HashSet::from([ContentType::Control]))
+///     [&ContentType::Control][0];
 /// ```
 pub fn nca_with_filters<P>(
-    reader: &Backend,
+    keyset: &Keyset,
     from: P,
     filters: &HashSet<ContentType>,
 ) -> HashMap<ContentType, Vec<Nca>>
 where
     P: AsRef<Path>,
 {
-    let mut filtered_ncas = HashMap::new();
+    let mut ncas = vec![];
 
     for entry in WalkDir::new(from.as_ref())
         .min_depth(1)
@@ -392,28 +1073,100 @@ where
             }
         })
     {
-        match Nca::try_new(reader, entry.path()) {
-            Ok(nca) => {
-                if filters.contains(&nca.content_type) {
-                    filtered_ncas
-                        .entry(nca.content_type)
-                        .or_insert(vec![])
-                        .push(nca);
-                }
-            }
+        match Nca::try_new(keyset, entry.path()) {
+            Ok(nca) => ncas.push(nca),
             Err(err) => {
                 warn!(%err);
             }
         }
     }
 
+    let (metas, mut ncas): (Vec<_>, Vec<_>) = ncas
+        .into_iter()
+        .partition(|nca| nca.content_type == ContentType::Meta);
+    let mut cnmts = vec![];
+    let mut meta_ncas = vec![];
+    // Still selectable as Meta, just not usable for picking the other NCAs
+    let mut unparsed_metas = vec![];
+    for meta in metas {
+        match Cnmt::from_nca(keyset, &meta) {
+            Ok(cnmt) => {
+                cnmts.push(cnmt);
+                meta_ncas.push(meta);
+            }
+            Err(err) => {
+                warn!(meta = %meta.path.display(), %err, "Failed to read CNMT");
+                unparsed_metas.push(meta);
+            }
+        }
+    }
+
+    let mut filtered_ncas: HashMap<ContentType, Vec<Nca>> = HashMap::new();
+    if cnmts.is_empty() {
+        info!(
+            from = %from.as_ref().display(),
+            "No CNMT found, selecting NCAs by their size"
+        );
+        for nca in ncas.into_iter().chain(unparsed_metas) {
+            if filters.contains(&nca.content_type) {
+                filtered_ncas.entry(nca.content_type).or_default().push(nca);
+            }
+        }
+        return filtered_ncas;
+    }
+
+    for cnmt in &cnmts {
+        let mut records = cnmt.content_records.iter().collect::<Vec<_>>();
+        records.sort_by_key(|record| record.id_offset);
+        for record in records
+            .into_iter()
+            .filter(|record| filters.contains(&record.content_type.nca_content_type()))
+        {
+            let filename = record.nca_filename();
+            match ncas.iter().position(|nca| {
+                nca.path.file_name().map_or(false, |name| {
+                    name.to_string_lossy().to_lowercase() == filename
+                })
+            }) {
+                Some(index) => {
+                    let nca = ncas.remove(index);
+                    filtered_ncas.entry(nca.content_type).or_default().push(nca);
+                }
+                None => warn!(
+                    title_id = %cnmt.get_title_id(),
+                    content_id = %record.get_content_id(),
+                    content_type = ?record.content_type,
+                    "Couldn't find the NCA of a CNMT content record"
+                ),
+            }
+        }
+    }
+    if filters.contains(&ContentType::Meta) {
+        meta_ncas.extend(unparsed_metas);
+        filtered_ncas.insert(ContentType::Meta, meta_ncas);
+    }
+
     filtered_ncas
 }
 
 #[allow(unused)]
-pub fn nca_with_kind<P>(reader: &Backend, from: P, kind: ContentType) -> Option<Vec<Nca>>
+pub fn nca_with_kind<P>(keyset: &Keyset, from: P, kind: ContentType) -> Option<Vec<Nca>>
 where
     P: AsRef<Path>,
 {
-    nca_with_filters(reader, from, &HashSet::from([kind])).remove(&kind)
+    nca_with_filters(keyset, from, &HashSet::from([kind])).remove(&kind)
+}
+
+/// Keys the `ncas` by their id offset, only the first NCA of an offset is kept.
+pub fn ncas_by_id_offset(ncas: Vec<Nca>) -> BTreeMap<u8, Nca> {
+    let mut by_offset = BTreeMap::new();
+    for nca in ncas {
+        let id_offset = nca.id_offset();
+        if by_offset.contains_key(&id_offset) {
+            debug!(nca = %nca.path.display(), id_offset, "Skipping NCA of an already seen id offset");
+            continue;
+        }
+        by_offset.insert(id_offset, nca);
+    }
+    by_offset
 }
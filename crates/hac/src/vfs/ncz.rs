@@ -0,0 +1,570 @@
+//! https://github.com/nicoboss/nsz#ncz-format
+//!
+//! NCZ is a NCA with its sections decrypted and then zstd compressed, either as a single
+//! stream or in independently compressed blocks. NSZ is just a NSP with NCZs in place of NCAs.
+
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use std::{
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+use strum_macros::FromRepr;
+use tracing::{debug, info};
+
+use super::{
+    copy_exact, le_u32, le_u64,
+    nca::{EncryptionType, Nca},
+    storage::{AesCtrStorage, SubStorage},
+};
+use crate::{
+    crypto::{AesCtr, AesKey},
+    keys::Keyset,
+};
+
+const SECTION_MAGIC: &[u8; 0x8] = b"NCZSECTN";
+const BLOCK_MAGIC: &[u8; 0x8] = b"NCZBLOCK";
+const SECTION_SIZE: usize = 0x40;
+const BLOCK_HEADER_SIZE: usize = 0x18;
+/// The start of the NCA, which includes the header, is stored as is.
+pub const UNCOMPRESSED_HEADER_SIZE: u64 = 0x4000;
+const STREAM_CHUNK_SIZE: usize = 0x100000;
+
+#[derive(Debug, Clone, Copy, FromRepr, PartialEq, Eq)]
+#[repr(u64)]
+pub enum NczCryptoType {
+    None = 0x1,
+    Xts = 0x2,
+    Ctr = 0x3,
+    Bktr = 0x4,
+}
+
+/// Region of the NCA and how to encrypt it back.
+#[derive(Debug, Clone)]
+pub struct NczSection {
+    pub offset: u64,
+    pub size: u64,
+    pub crypto_type: NczCryptoType,
+    pub crypto_key: AesKey,
+    pub crypto_counter: [u8; 0x10],
+}
+
+impl NczSection {
+    /// Upper half of the AES-CTR counter, same as the IV in the FS header.
+    pub fn upper_iv(&self) -> u64 {
+        u64::from_be_bytes(
+            self.crypto_counter[..0x8]
+                .try_into()
+                .expect("Slice is of len 8"),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NczBlockHeader {
+    pub version: u8,
+    pub block_type: u8,
+    pub block_size_exponent: u8,
+    pub decompressed_size: u64,
+    pub compressed_block_sizes: Vec<u32>,
+}
+
+impl NczBlockHeader {
+    pub fn block_size(&self) -> u64 {
+        1 << self.block_size_exponent
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ncz {
+    pub sections: Vec<NczSection>,
+    pub block: Option<NczBlockHeader>,
+    /// Offset of the compressed data.
+    pub data_offset: u64,
+}
+
+impl Ncz {
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        reader.seek(SeekFrom::Start(UNCOMPRESSED_HEADER_SIZE))?;
+        let mut buf = [0u8; 0x10];
+        reader.read_exact(&mut buf)?;
+        if &buf[..0x8] != SECTION_MAGIC {
+            bail!("Invalid NCZ section magic");
+        }
+
+        let section_count = le_u64(&buf, 0x8);
+        let mut sections = vec![];
+        for _ in 0..section_count {
+            let mut section = [0u8; SECTION_SIZE];
+            reader.read_exact(&mut section)?;
+            let crypto_type = le_u64(&section, 0x10);
+            sections.push(NczSection {
+                offset: le_u64(&section, 0x0),
+                size: le_u64(&section, 0x8),
+                crypto_type: NczCryptoType::from_repr(crypto_type)
+                    .ok_or_else(|| eyre!("Unknown NCZ crypto type {:#x}", crypto_type))?,
+                crypto_key: section[0x20..0x30].try_into()?,
+                crypto_counter: section[0x30..0x40].try_into()?,
+            });
+        }
+
+        let mut data_offset = reader.stream_position()?;
+        let mut block_header = [0u8; BLOCK_HEADER_SIZE];
+        let block = match reader.read_exact(&mut block_header) {
+            Ok(_) if &block_header[..0x8] == BLOCK_MAGIC => {
+                let block_size_exponent = block_header[0xB];
+                if !(14..=32).contains(&block_size_exponent) {
+                    bail!("Invalid NCZ block size exponent {}", block_size_exponent);
+                }
+                let block_count = le_u32(&block_header, 0xC);
+                let mut sizes = vec![0u8; block_count as usize * 0x4];
+                reader.read_exact(&mut sizes)?;
+                data_offset = reader.stream_position()?;
+                Some(NczBlockHeader {
+                    version: block_header[0x8],
+                    block_type: block_header[0x9],
+                    block_size_exponent,
+                    decompressed_size: le_u64(&block_header, 0x10),
+                    compressed_block_sizes: sizes
+                        .chunks_exact(0x4)
+                        .map(|size| le_u32(size, 0x0))
+                        .collect(),
+                })
+            }
+            Ok(_) => None,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        let ncz = Self {
+            sections,
+            block,
+            data_offset,
+        };
+        debug!(?ncz);
+
+        Ok(ncz)
+    }
+    /// Writes the NCA back, re-encrypting the sections.
+    ///
+    /// Returns the size of the NCA.
+    pub fn decompress<R, W>(&self, reader: &mut R, writer: &mut W) -> Result<u64>
+    where
+        R: Read + Seek,
+        W: Write,
+    {
+        if let Some(section) = self
+            .sections
+            .iter()
+            .find(|section| section.crypto_type == NczCryptoType::Xts)
+        {
+            bail!(
+                "NCZ section at {:#x} is XTS encrypted, which isn't supported",
+                section.offset
+            );
+        }
+
+        let mut written = copy_exact(reader, 0, UNCOMPRESSED_HEADER_SIZE, writer)?;
+        reader.seek(SeekFrom::Start(self.data_offset))?;
+        match &self.block {
+            Some(block) => {
+                let mut compressed = vec![];
+                // Size of the data after the uncompressed header
+                let mut remaining = block.decompressed_size;
+                for &compressed_size in &block.compressed_block_sizes {
+                    let size = remaining.min(block.block_size());
+                    compressed.resize(compressed_size as _, 0);
+                    reader.read_exact(&mut compressed)?;
+                    // Blocks which didn't get any smaller are stored as is
+                    let mut chunk = if (compressed_size as u64) < size {
+                        zstd::bulk::decompress(&compressed, size as _)?
+                    } else {
+                        std::mem::take(&mut compressed)
+                    };
+                    if chunk.len() as u64 != size {
+                        bail!(
+                            "NCZ block at {:#x} decompressed to {:#x} bytes, expected {:#x}",
+                            written,
+                            chunk.len(),
+                            size
+                        );
+                    }
+                    self.encrypt(&mut chunk, written);
+                    writer.write_all(&chunk)?;
+                    written += size;
+                    remaining -= size;
+                }
+                if remaining != 0 {
+                    bail!("NCZ is missing {:#x} bytes of blocks", remaining);
+                }
+            }
+            None => {
+                let mut decoder = zstd::stream::read::Decoder::new(reader)?;
+                let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+                loop {
+                    // Filling up the chunk to keep the offsets block aligned
+                    let mut len = 0;
+                    while len < chunk.len() {
+                        match decoder.read(&mut chunk[len..])? {
+                            0 => break,
+                            n => len += n,
+                        }
+                    }
+                    if len == 0 {
+                        break;
+                    }
+                    self.encrypt(&mut chunk[..len], written);
+                    writer.write_all(&chunk[..len])?;
+                    written += len as u64;
+                }
+            }
+        }
+        writer.flush()?;
+
+        info!(size = written, "Decompressed NCZ");
+        Ok(written)
+    }
+    /// Compresses `nca` to a NCZ with a single zstd stream.
+    ///
+    /// Returns the size of the NCZ.
+    pub fn compress<W: Write>(
+        keyset: &Keyset,
+        nca: &Nca,
+        writer: &mut W,
+        options: &NczOptions,
+    ) -> Result<u64> {
+        let nca_size = fs::metadata(&nca.path)?.len();
+        if nca_size <= UNCOMPRESSED_HEADER_SIZE {
+            bail!("'{}' is too small to be compressed", nca.path.display());
+        }
+
+        let mut nca_sections = nca.header.sections.iter().collect::<Vec<_>>();
+        nca_sections.sort_by_key(|section| section.offset);
+        let mut sections = vec![];
+        for section in nca_sections {
+            let crypto_type = match section.fs_header.encryption_type {
+                EncryptionType::None => NczCryptoType::None,
+                EncryptionType::AesCtr | EncryptionType::AesCtrSkipLayerHash => NczCryptoType::Ctr,
+                // Sub-regions of other generations round-trip fine with the plain counter,
+                // just that they compress poorly
+                EncryptionType::AesCtrEx | EncryptionType::AesCtrExSkipLayerHash => {
+                    NczCryptoType::Bktr
+                }
+                kind => bail!(
+                    "Section {} of '{}' uses {:?} encryption, which isn't supported by NCZ",
+                    section.index,
+                    nca.path.display(),
+                    kind
+                ),
+            };
+            let mut crypto_counter = [0u8; 0x10];
+            crypto_counter[..0x8].copy_from_slice(&section.fs_header.upper_iv.to_be_bytes());
+            sections.push(NczSection {
+                offset: section.offset,
+                size: section.size,
+                crypto_type,
+                crypto_key: match crypto_type {
+                    NczCryptoType::None => AesKey::default(),
+                    _ => nca.section_key(keyset)?,
+                },
+                crypto_counter,
+            });
+        }
+        let ncz = Self {
+            sections,
+            block: None,
+            data_offset: 0,
+        };
+        debug!(?ncz);
+
+        info!(
+            nca = %nca.path.display(),
+            level = options.level,
+            threads = options.threads,
+            "Compressing NCA"
+        );
+        let written = ncz.write(
+            &mut BufReader::new(fs::File::open(&nca.path)?),
+            writer,
+            options,
+        )?;
+
+        info!(
+            nca = %nca.path.display(),
+            from = nca_size,
+            to = written,
+            "Compressed NCA"
+        );
+        Ok(written)
+    }
+    /// Writes the NCA in `reader` as a NCZ of these sections, returns the size of the NCZ.
+    fn write<R, W>(&self, reader: &mut R, writer: &mut W, options: &NczOptions) -> Result<u64>
+    where
+        R: Read + Seek,
+        W: Write,
+    {
+        let mut writer = CountingWriter::new(writer);
+        copy_exact(reader, 0, UNCOMPRESSED_HEADER_SIZE, &mut writer)?;
+        writer.write_all(SECTION_MAGIC)?;
+        writer.write_all(&(self.sections.len() as u64).to_le_bytes())?;
+        for section in &self.sections {
+            writer.write_all(&section.offset.to_le_bytes())?;
+            writer.write_all(&section.size.to_le_bytes())?;
+            writer.write_all(&(section.crypto_type as u64).to_le_bytes())?;
+            writer.write_all(&[0u8; 0x8])?;
+            writer.write_all(&section.crypto_key)?;
+            writer.write_all(&section.crypto_counter)?;
+        }
+
+        let mut encoder = zstd::stream::write::Encoder::new(&mut writer, options.level)?;
+        encoder.multithread(options.threads)?;
+        let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+        let mut offset = UNCOMPRESSED_HEADER_SIZE;
+        reader.seek(SeekFrom::Start(offset))?;
+        loop {
+            let mut len = 0;
+            while len < chunk.len() {
+                match reader.read(&mut chunk[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            if len == 0 {
+                break;
+            }
+            // Decrypting, same as encrypting with CTR
+            self.encrypt(&mut chunk[..len], offset);
+            encoder.write_all(&chunk[..len])?;
+            offset += len as u64;
+        }
+        encoder.finish()?;
+        writer.flush()?;
+
+        Ok(writer.count)
+    }
+    /// Encrypts `buf`, which is at `offset` of the NCA, as per the sections.
+    fn encrypt(&self, buf: &mut [u8], offset: u64) {
+        let end = offset + buf.len() as u64;
+        for section in self.sections.iter().filter(|section| {
+            matches!(
+                section.crypto_type,
+                NczCryptoType::Ctr | NczCryptoType::Bktr
+            )
+        }) {
+            let start = section.offset.max(offset);
+            let stop = (section.offset + section.size).min(end);
+            if start >= stop {
+                continue;
+            }
+            AesCtr::new(&section.crypto_key).apply(
+                &mut buf[(start - offset) as usize..(stop - offset) as usize],
+                AesCtrStorage::<io::Empty>::counter(section.upper_iv(), start),
+            );
+        }
+    }
+}
+
+/// Decompresses the NCZ at `offset` of `reader` to a NCA at `dest`.
+pub(crate) fn decompress_entry<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    size: u64,
+    dest: &Path,
+) -> Result<()> {
+    info!(nca = %dest.display(), "Decompressing NCZ");
+    let mut storage = SubStorage::new(reader, offset, size);
+    let ncz = Ncz::from_reader(&mut storage)?;
+    ncz.decompress(&mut storage, &mut BufWriter::new(fs::File::create(dest)?))?;
+    Ok(())
+}
+
+/// Compression settings for NCZs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NczOptions {
+    /// zstd compression level, from 1 to 22.
+    pub level: i32,
+    /// Worker threads used by zstd, 0 to compress on the calling thread.
+    pub threads: u32,
+}
+
+impl NczOptions {
+    pub const DEFAULT_LEVEL: i32 = 18;
+}
+
+impl Default for NczOptions {
+    fn default() -> Self {
+        Self {
+            level: Self::DEFAULT_LEVEL,
+            threads: std::thread::available_parallelism().map_or(0, |threads| threads.get() as _),
+        }
+    }
+}
+
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const CTR_OFFSET: u64 = 0x8000;
+    // Spans more than one stream chunk
+    const CTR_SIZE: u64 = 0x180000;
+
+    fn sample_ncz() -> Ncz {
+        let mut crypto_counter = [0u8; 0x10];
+        crypto_counter[..0x8].copy_from_slice(&0x0000_0003_0000_0000u64.to_be_bytes());
+        Ncz {
+            sections: vec![
+                NczSection {
+                    offset: UNCOMPRESSED_HEADER_SIZE,
+                    size: CTR_OFFSET - UNCOMPRESSED_HEADER_SIZE,
+                    crypto_type: NczCryptoType::None,
+                    crypto_key: AesKey::default(),
+                    crypto_counter: [0u8; 0x10],
+                },
+                NczSection {
+                    offset: CTR_OFFSET,
+                    size: CTR_SIZE,
+                    crypto_type: NczCryptoType::Ctr,
+                    crypto_key: [0x42; 0x10],
+                    crypto_counter,
+                },
+            ],
+            block: None,
+            data_offset: 0,
+        }
+    }
+
+    /// Plain NCA of `ncz` with its sections encrypted.
+    fn sample_nca(ncz: &Ncz) -> Vec<u8> {
+        let mut nca = (0..CTR_OFFSET + CTR_SIZE)
+            .map(|i| (i / 0x100 % 0x7) as u8)
+            .collect::<Vec<_>>();
+        ncz.encrypt(&mut nca[CTR_OFFSET as usize..], CTR_OFFSET);
+        nca
+    }
+
+    /// NCZ of `nca` in blocks of `1 << exponent` bytes, or as a single stream.
+    fn sample_image(ncz: &Ncz, nca: &[u8], exponent: Option<u8>) -> Vec<u8> {
+        let mut plain = nca.to_vec();
+        ncz.encrypt(&mut plain[CTR_OFFSET as usize..], CTR_OFFSET);
+
+        let mut image = nca[..UNCOMPRESSED_HEADER_SIZE as usize].to_vec();
+        image.extend(SECTION_MAGIC);
+        image.extend((ncz.sections.len() as u64).to_le_bytes());
+        for section in &ncz.sections {
+            image.extend(section.offset.to_le_bytes());
+            image.extend(section.size.to_le_bytes());
+            image.extend((section.crypto_type as u64).to_le_bytes());
+            image.extend([0u8; 0x8]);
+            image.extend(section.crypto_key);
+            image.extend(section.crypto_counter);
+        }
+        let body = &plain[UNCOMPRESSED_HEADER_SIZE as usize..];
+        let exponent = match exponent {
+            Some(exponent) => exponent,
+            None => {
+                image.extend(zstd::encode_all(body, 3).unwrap());
+                return image;
+            }
+        };
+        let blocks = body
+            .chunks(1 << exponent)
+            .enumerate()
+            // Blocks that don't get smaller are stored as is
+            .map(|(i, chunk)| match i {
+                1 => chunk.to_vec(),
+                _ => zstd::bulk::compress(chunk, 3).unwrap(),
+            })
+            .collect::<Vec<_>>();
+        image.extend(BLOCK_MAGIC);
+        image.extend([0x2, 0x1, 0x0, exponent]);
+        image.extend((blocks.len() as u32).to_le_bytes());
+        image.extend((body.len() as u64).to_le_bytes());
+        for block in &blocks {
+            image.extend((block.len() as u32).to_le_bytes());
+        }
+        for block in &blocks {
+            image.extend(block);
+        }
+        image
+    }
+
+    #[test]
+    fn decompress_stream() {
+        let ncz = sample_ncz();
+        let nca = sample_nca(&ncz);
+        let image = sample_image(&ncz, &nca, None);
+
+        let mut reader = Cursor::new(&image);
+        let parsed = Ncz::from_reader(&mut reader).unwrap();
+        assert!(parsed.block.is_none());
+        assert_eq!(parsed.sections.len(), 2);
+        assert_eq!(parsed.sections[1].upper_iv(), ncz.sections[1].upper_iv());
+        let mut out = vec![];
+        let size = parsed.decompress(&mut reader, &mut out).unwrap();
+        assert_eq!(size, nca.len() as u64);
+        assert!(out == nca);
+    }
+
+    #[test]
+    fn decompress_blocks() {
+        let ncz = sample_ncz();
+        let nca = sample_nca(&ncz);
+        let image = sample_image(&ncz, &nca, Some(16));
+
+        let mut reader = Cursor::new(&image);
+        let parsed = Ncz::from_reader(&mut reader).unwrap();
+        assert_eq!(parsed.block.as_ref().unwrap().block_size(), 1 << 16);
+        let mut out = vec![];
+        let size = parsed.decompress(&mut reader, &mut out).unwrap();
+        assert_eq!(size, nca.len() as u64);
+        assert!(out == nca);
+    }
+
+    #[test]
+    fn round_trip() {
+        let ncz = sample_ncz();
+        let nca = sample_nca(&ncz);
+        let options = NczOptions {
+            level: 3,
+            threads: 0,
+        };
+
+        let mut image = vec![];
+        let written = ncz
+            .write(&mut Cursor::new(&nca), &mut image, &options)
+            .unwrap();
+        assert_eq!(written, image.len() as u64);
+        // Sections are compressed decrypted
+        assert!(image.len() < nca.len() / 0x10);
+
+        let mut reader = Cursor::new(&image);
+        let parsed = Ncz::from_reader(&mut reader).unwrap();
+        let mut out = vec![];
+        let size = parsed.decompress(&mut reader, &mut out).unwrap();
+        assert_eq!(size, nca.len() as u64);
+        assert!(out == nca);
+    }
+}
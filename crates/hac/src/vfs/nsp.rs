@@ -1,29 +1,73 @@
 use crate::{
-    backend::Backend,
-    vfs::{filter_out_key_mismatches, ticket::TitleKey},
+    keys::Keyset,
+    vfs::{
+        cnmt::{Cnmt, CnmtContentType},
+        nacp::{Icon, NacpData},
+        nca::{ContentType, Nca, NcaHeader, NcaIssue},
+        ncz::{decompress_entry, Ncz, NczOptions},
+        pfs0::{Pfs0, Pfs0Builder, Pfs0Entry},
+        storage::SubStorage,
+        ticket::TitleKey,
+    },
 };
 use common::utils::{ext_matches, get_fmt_size};
-use eyre::{bail, Result};
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use sha2::{Digest, Sha256};
 use std::{
+    collections::HashMap,
+    fmt,
+    io::{BufReader, BufWriter, Read, Seek},
     path::{Path, PathBuf},
-    process::{Command, Stdio},
 };
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 use walkdir::WalkDir;
 
 /// https://switchbrew.org/wiki/NCA#PFS0
 ///
 /// Provides some methods relating to Pfs0, a file system.
+///
+/// NSZs are handled as well, their NCZs get decompressed to NCAs on extraction.
 #[derive(Debug, Default, Clone)]
 pub struct Nsp {
     pub path: PathBuf,
     pub title_key: Option<TitleKey>,
 }
 
+/// A problem with the contents of a NSP, found by [`Nsp::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentIssue {
+    /// There's no CNMT to verify the contents against.
+    NoMeta,
+    /// A content record has no NCA in the NSP.
+    Missing {
+        name: String,
+        content_type: CnmtContentType,
+    },
+    /// The NCA doesn't match its content record, or couldn't be read at all.
+    Corrupt { name: String, reason: String },
+}
+
+impl fmt::Display for ContentIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContentIssue::NoMeta => write!(f, "No Meta NCA, contents couldn't be verified"),
+            ContentIssue::Missing { name, content_type } => {
+                write!(f, "'{}' ({:?}) is missing", name, content_type)
+            }
+            ContentIssue::Corrupt { name, reason } => {
+                write!(f, "'{}' is corrupt, {}", name, reason)
+            }
+        }
+    }
+}
+
 impl Nsp {
     pub fn try_new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        if !path.as_ref().is_file() || !ext_matches(path.as_ref(), "nsp") {
-            bail!("'{}' is not a NSP file", path.as_ref().display());
+        if !path.as_ref().is_file()
+            || !(ext_matches(path.as_ref(), "nsp") || ext_matches(path.as_ref(), "nsz"))
+        {
+            bail!("'{}' is not a NSP/NSZ file", path.as_ref().display());
         }
 
         Ok(Self {
@@ -31,84 +75,351 @@ impl Nsp {
             ..Default::default()
         })
     }
-    pub fn unpack<P: AsRef<Path>>(&self, extractor: &Backend, to: P) -> Result<()> {
+    /// Whether the NSP is a NSZ, i.e. has NCZs in place of NCAs.
+    pub fn is_compressed(&self) -> bool {
+        ext_matches(&self.path, "nsz")
+    }
+    /// Lists the entries contained within the NSP.
+    pub fn entries(&self) -> Result<Vec<Pfs0Entry>> {
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        Ok(Pfs0::from_reader(&mut reader)?.entries)
+    }
+    pub fn unpack<P: AsRef<Path>>(&self, to: P) -> Result<()> {
         info!(nsp = %self.path.display(), "Unpacking NSP");
-        let mut cmd = Command::new(extractor.path());
-        cmd.args([
-            "-t".as_ref(),
-            "pfs0".as_ref(),
-            "--outdir".as_ref(),
-            to.as_ref(),
-            self.path.as_path(),
-        ])
-        .stderr(Stdio::piped());
-        let output = cmd.spawn()?.wait_with_output()?;
-        // Better to have it lossy since accuracy doesn't matter here,
-        // also it won't bail from the function anymore.
-        let stderr = filter_out_key_mismatches(&output.stderr);
-        eprint!("{}", stderr);
-        if !output.status.success() {
-            error!(
+        fs::create_dir_all(to.as_ref())?;
+
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        let pfs0 = Pfs0::from_reader(&mut reader)
+            .map_err(|err| eyre!("Failed to extract '{}': {}", self.path.display(), err))?;
+        for entry in &pfs0.entries {
+            extract_entry(&mut reader, entry, to.as_ref())?;
+        }
+
+        info!(nsp = %self.path.display(), to = %to.as_ref().display(), "Unpacked NSP");
+        Ok(())
+    }
+    /// Checks that `keyset` has the master keys needed for the NCAs in the NSP.
+    ///
+    /// Only the NCA headers are read, nothing is extracted.
+    pub fn check_keys(&self, keyset: &Keyset) -> Result<()> {
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        let pfs0 = Pfs0::from_reader(&mut reader)?;
+        let mut required = None;
+        // NCZs keep the NCA header as is
+        for entry in pfs0
+            .entries
+            .iter()
+            .filter(|entry| entry.name.ends_with(".nca") || entry.name.ends_with(".ncz"))
+        {
+            let header = NcaHeader::from_reader(
+                keyset,
+                &mut SubStorage::new(&mut reader, entry.offset, entry.size),
+            )
+            .map_err(|err| {
+                eyre!(
+                    "Failed to read header of '{}' in '{}': {}",
+                    entry.name,
+                    self.path.display(),
+                    err
+                )
+            })?;
+            required = required.max(Some(header.required_key_revision()));
+        }
+
+        if let Some(revision) = required {
+            info!(
                 nsp = %self.path.display(),
-                backend = ?extractor.kind(),
-                %stderr,
-                "Encountered an error while unpacking NSP"
+                revision = %format!("{:#04x}", revision),
+                "Required master key revision"
             );
-            bail!("Failed to extract '{}'", self.path.display());
+            keyset
+                .ensure_key_revision(revision)
+                .map_err(|err| eyre!("'{}': {}", self.path.display(), err))?;
         }
+        Ok(())
+    }
+    /// Reads the CNMTs of the Meta NCAs in the NSP, only those NCAs get extracted.
+    pub fn cnmts<P: AsRef<Path>>(&self, keyset: &Keyset, tempdir_in: P) -> Result<Vec<Cnmt>> {
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        let pfs0 = Pfs0::from_reader(&mut reader)?;
+        let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+        read_cnmts(&mut reader, &pfs0.entries, keyset, temp_dir.path())
+    }
+    /// Hashes every NCA and compares it with the content records of the CNMTs.
+    ///
+    /// NCZs are hashed as the NCA they decompress to.
+    pub fn verify<P: AsRef<Path>>(
+        &self,
+        keyset: &Keyset,
+        tempdir_in: P,
+    ) -> Result<Vec<ContentIssue>> {
+        info!(nsp = %self.path.display(), "Verifying NSP");
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        let pfs0 = Pfs0::from_reader(&mut reader)
+            .map_err(|err| eyre!("Failed to read '{}': {}", self.path.display(), err))?;
+        let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
 
-        info!(nsp = %self.path.display(), to = %to.as_ref().display(), "Unpacked NSP");
+        let mut hashes = HashMap::new();
+        for entry in pfs0
+            .entries
+            .iter()
+            .filter(|entry| entry.name.ends_with(".nca") || entry.name.ends_with(".ncz"))
+        {
+            debug!(name = %entry.name, "Hashing NCA");
+            hashes.insert(entry.name.as_str(), hash_entry(&mut reader, entry));
+        }
+
+        let mut issues = vec![];
+        let mut cnmts = vec![];
+        // Meta NCAs aren't in the content records, but they're named after their hash too
+        for entry in pfs0
+            .entries
+            .iter()
+            .filter(|entry| entry.name.ends_with(".cnmt.nca"))
+        {
+            let corrupt = |reason: String| ContentIssue::Corrupt {
+                name: entry.name.clone(),
+                reason,
+            };
+            match &hashes[entry.name.as_str()] {
+                Ok((hash, _)) if entry.name.starts_with(&hex::encode(&hash[..0x10])) => {}
+                Ok(_) => {
+                    issues.push(corrupt("its hash doesn't match its name".into()));
+                    continue;
+                }
+                Err(err) => {
+                    issues.push(corrupt(err.to_string()));
+                    continue;
+                }
+            }
+            match extract_entry(&mut reader, entry, temp_dir.path())
+                .and_then(|meta_path| Nca::try_new(keyset, meta_path))
+                .and_then(|meta| Cnmt::from_nca(keyset, &meta))
+            {
+                Ok(cnmt) => cnmts.push(cnmt),
+                Err(err) => issues.push(corrupt(err.to_string())),
+            }
+        }
+        if cnmts.is_empty() && issues.is_empty() {
+            warn!(nsp = %self.path.display(), "No Meta NCA to verify against");
+            issues.push(ContentIssue::NoMeta);
+        }
+
+        // Dumps don't include the DeltaFragments of patches, they're only used for
+        // installing an update over an older one
+        for record in cnmts
+            .iter()
+            .flat_map(|cnmt| &cnmt.content_records)
+            .filter(|record| record.content_type != CnmtContentType::DeltaFragment)
+        {
+            let name = record.nca_filename();
+            let ncz_name = format!("{}.ncz", record.get_content_id());
+            let (name, hashed) = match (hashes.get(name.as_str()), hashes.get(ncz_name.as_str())) {
+                (Some(hashed), _) => (name, hashed),
+                (None, Some(hashed)) => (ncz_name, hashed),
+                (None, None) => {
+                    issues.push(ContentIssue::Missing {
+                        name,
+                        content_type: record.content_type,
+                    });
+                    continue;
+                }
+            };
+            let reason = match hashed {
+                Ok((_, size)) if *size != record.size => format!(
+                    "its size is {:#x} bytes while {:#x} was expected",
+                    size, record.size
+                ),
+                Ok((hash, _)) if hash != &record.hash => "its hash doesn't match".into(),
+                Ok(_) => continue,
+                Err(err) => err.to_string(),
+            };
+            issues.push(ContentIssue::Corrupt { name, reason });
+        }
+
+        for issue in &issues {
+            warn!(nsp = %self.path.display(), %issue);
+        }
+        info!(nsp = %self.path.display(), issues = issues.len(), "Verified NSP");
+        Ok(issues)
+    }
+    /// Fails listing the corrupt contents found by [`Nsp::verify`], missing contents
+    /// and a missing CNMT are only warned about.
+    pub fn ensure_intact<P: AsRef<Path>>(&self, keyset: &Keyset, tempdir_in: P) -> Result<()> {
+        let issues = self
+            .verify(keyset, tempdir_in)?
+            .into_iter()
+            .filter(|issue| matches!(issue, ContentIssue::Corrupt { .. }))
+            .map(|issue| format!("- {}", issue))
+            .collect::<Vec<_>>();
+        if !issues.is_empty() {
+            bail!(
+                "'{}' is damaged, try getting it again:\n{}",
+                self.path.display(),
+                issues.join("\n")
+            );
+        }
         Ok(())
     }
-    pub fn pack<K, P, Q>(
-        packer: &Backend,
+    /// Runs [`Nca::verify`] on every NCA, extracting them one at a time.
+    ///
+    /// Returns the issues by the entry name, an NCA which couldn't be read at all has an error instead.
+    pub fn verify_ncas<P: AsRef<Path>>(
+        &self,
+        keyset: &Keyset,
+        tempdir_in: P,
+    ) -> Result<Vec<(String, Result<Vec<NcaIssue>>)>> {
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        let pfs0 = Pfs0::from_reader(&mut reader)?;
+        let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+
+        let mut keyset = keyset.clone();
+        for entry in pfs0
+            .entries
+            .iter()
+            .filter(|entry| entry.name.ends_with(".tik"))
+        {
+            let tik_path = extract_entry(&mut reader, entry, temp_dir.path())?;
+            keyset.add_title_key(&TitleKey::try_new(&keyset, tik_path)?);
+        }
+
+        let mut results = vec![];
+        for entry in pfs0
+            .entries
+            .iter()
+            .filter(|entry| entry.name.ends_with(".nca") || entry.name.ends_with(".ncz"))
+        {
+            let res = extract_entry(&mut reader, entry, temp_dir.path()).and_then(|nca_path| {
+                let issues = Nca::try_new(&keyset, &nca_path).and_then(|nca| nca.verify(&keyset));
+                fs::remove_file(nca_path)?;
+                issues
+            });
+            results.push((entry.name.clone(), res));
+        }
+        Ok(results)
+    }
+    /// Reads the icons from the main Control NCA, only it and the Tickets get extracted.
+    pub fn icons<P: AsRef<Path>>(&self, keyset: &Keyset, tempdir_in: P) -> Result<Vec<Icon>> {
+        let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+        let (keyset, control) = self.extract_main_control(keyset, temp_dir.path())?;
+        Icon::from_nca(&keyset, &control)
+    }
+    /// Reads the NACP from the main Control NCA, only it and the Tickets get extracted.
+    pub fn nacp<P: AsRef<Path>>(&self, keyset: &Keyset, tempdir_in: P) -> Result<NacpData> {
+        let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+        let (keyset, control) = self.extract_main_control(keyset, temp_dir.path())?;
+        NacpData::from_nca(&keyset, &control)
+    }
+    /// Returns the Control NCA of id offset 0 extracted to `to`, along with `keyset`
+    /// holding the TitleKeys of the Tickets.
+    fn extract_main_control(&self, keyset: &Keyset, to: &Path) -> Result<(Keyset, Nca)> {
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        let pfs0 = Pfs0::from_reader(&mut reader)?;
+        extract_main_control(&mut reader, &pfs0.entries, keyset, to)?
+            .ok_or_else(|| eyre!("Couldn't find a Control NCA in '{}'", self.path.display()))
+    }
+    /// Extracts a single file from the NSP to the `to` dir.
+    ///
+    /// NCZs are decompressed, so the returned path is of the NCA.
+    pub fn unpack_entry<P: AsRef<Path>>(&self, name: &str, to: P) -> Result<PathBuf> {
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        let pfs0 = Pfs0::from_reader(&mut reader)?;
+        let entry = pfs0
+            .find(name)
+            .ok_or_else(|| eyre!("Couldn't find '{}' in '{}'", name, self.path.display()))?;
+        fs::create_dir_all(to.as_ref())?;
+        extract_entry(&mut reader, entry, to.as_ref())
+    }
+    /// Packs all the files in `nca_dir` to a NSP.
+    pub fn pack<P, Q>(program_id: &str, nca_dir: P, outdir: Q) -> Result<Nsp>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        Nsp::pack_as(
+            nca_dir.as_ref(),
+            &outdir.as_ref().join(format!("{}.nsp", program_id)),
+        )
+    }
+    /// Packs all the files in `nca_dir` to a NSZ, the NCAs are compressed to NCZs in place.
+    ///
+    /// Meta NCAs, and the ones that can't be compressed, are kept as is.
+    pub fn pack_nsz<P, Q>(
+        keyset: &Keyset,
         program_id: &str,
-        keyfile: K,
         nca_dir: P,
         outdir: Q,
+        options: &NczOptions,
     ) -> Result<Nsp>
     where
-        K: AsRef<Path>,
         P: AsRef<Path>,
         Q: AsRef<Path>,
     {
-        info!(nca_dir = ?nca_dir.as_ref(), "Packing NCAs to NSP");
-        let mut cmd = Command::new(packer.path());
-        cmd.args([
-            "--keyset".as_ref(),
-            keyfile.as_ref(),
-            "--type".as_ref(),
-            "nsp".as_ref(),
-            "--ncadir".as_ref(),
-            nca_dir.as_ref(),
-            "--titleid".as_ref(),
-            program_id.as_ref(),
-            "--outdir".as_ref(),
-            outdir.as_ref(),
-        ])
-        .stderr(Stdio::piped());
-        let output = cmd.spawn()?.wait_with_output()?;
-        let stderr = filter_out_key_mismatches(&output.stderr);
-        eprint!("{}", stderr);
-        if !output.status.success() {
-            error!(
-                backend = ?packer.kind(),
-                code = ?output.status.code(),
-                %stderr,
-                "Encountered an error while packing NCAs to NSP"
+        for entry in WalkDir::new(nca_dir.as_ref())
+            .min_depth(1)
+            .max_depth(1)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|entry| entry.file_type().is_file() && ext_matches(entry.path(), "nca"))
+        {
+            let nca = Nca::try_new(keyset, entry.path())?;
+            if nca.content_type == ContentType::Meta {
+                continue;
+            }
+            let ncz_path = nca.path.with_extension("ncz");
+            let compressed = Ncz::compress(
+                keyset,
+                &nca,
+                &mut BufWriter::new(fs::File::create(&ncz_path)?),
+                options,
             );
+            match compressed {
+                Ok(_) => fs::remove_file(&nca.path)?,
+                Err(err) => {
+                    warn!(nca = %nca.path.display(), %err, "Keeping NCA uncompressed");
+                    fs::remove_file(&ncz_path)?;
+                }
+            }
+        }
+
+        Nsp::pack_as(
+            nca_dir.as_ref(),
+            &outdir.as_ref().join(format!("{}.nsz", program_id)),
+        )
+    }
+    fn pack_as(nca_dir: &Path, nsp_path: &Path) -> Result<Nsp> {
+        info!(?nca_dir, "Packing NCAs to NSP");
+        let mut builder = Pfs0Builder::new();
+        for entry in WalkDir::new(nca_dir)
+            .min_depth(1)
+            .max_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|entry| entry.file_type().is_file())
+        {
+            builder.add_file(entry.file_name().to_string_lossy(), entry.path());
+        }
+
+        let mut writer = BufWriter::new(fs::File::create(nsp_path)?);
+        if let Err(err) = builder.write(&mut writer) {
+            error!(%err, "Encountered an error while packing NCAs to NSP");
             bail!("Encountered an error while packing NCAs to NSP");
         }
 
-        let nsp_path = outdir.as_ref().join(format!("{}.nsp", program_id));
         info!(
-            outdir = %outdir.as_ref().display(),
-            size = %get_fmt_size(&nsp_path).unwrap_or_default(),
+            nsp = %nsp_path.display(),
+            size = %get_fmt_size(nsp_path).unwrap_or_default(),
             "Packed NCAs to NSP"
         );
         Nsp::try_new(nsp_path)
     }
-    pub fn derive_title_key<P: AsRef<Path>>(&mut self, data_path: P) -> Result<()> {
+    /// Reads the TitleKey from the Ticket in `data_path`, if there's one.
+    pub fn derive_title_key<P: AsRef<Path>>(
+        &mut self,
+        keyset: &Keyset,
+        data_path: P,
+    ) -> Result<()> {
         if self.title_key.is_none() {
             info!(nsp = %self.path.display(), "Deriving TitleKey");
             for entry in WalkDir::new(data_path.as_ref())
@@ -117,12 +428,13 @@ impl Nsp {
                 .filter_map(|e| e.ok())
             {
                 if ext_matches(entry.path(), "tik") {
-                    self.title_key = Some(TitleKey::try_new(entry.path())?);
+                    self.title_key = Some(TitleKey::try_new(keyset, entry.path())?);
                     break;
                 }
             }
             if self.title_key.is_none() {
-                bail!("Couldn't find a Ticket file in '{}'", self.path.display());
+                warn!(nsp = %self.path.display(), "Couldn't find a Ticket file");
+                return Ok(());
             }
             info!("Derived TitleKey");
         } else {
@@ -135,3 +447,161 @@ impl Nsp {
         Ok(())
     }
 }
+
+/// Reads the CNMTs of the Meta NCAs in `entries`, only those NCAs get extracted to `to`.
+pub(crate) fn read_cnmts<R: Read + Seek>(
+    reader: &mut R,
+    entries: &[Pfs0Entry],
+    keyset: &Keyset,
+    to: &Path,
+) -> Result<Vec<Cnmt>> {
+    let mut cnmts = vec![];
+    for entry in entries.iter().filter(|entry| entry.name.ends_with(".nca")) {
+        let header = NcaHeader::from_reader(
+            keyset,
+            &mut SubStorage::new(&mut *reader, entry.offset, entry.size),
+        )?;
+        if header.content_type != ContentType::Meta {
+            continue;
+        }
+        let meta_path = extract_entry(reader, entry, to)?;
+        cnmts.push(Cnmt::from_nca(keyset, &Nca::try_new(keyset, &meta_path)?)?);
+    }
+    Ok(cnmts)
+}
+
+/// Extracts the Control NCA of id offset 0 in `entries` to `to`, along with `keyset`
+/// holding the TitleKeys of the Tickets.
+pub(crate) fn extract_main_control<R: Read + Seek>(
+    reader: &mut R,
+    entries: &[Pfs0Entry],
+    keyset: &Keyset,
+    to: &Path,
+) -> Result<Option<(Keyset, Nca)>> {
+    // Control NCAs can have a RightsID too
+    let mut keyset = keyset.clone();
+    for entry in entries.iter().filter(|entry| entry.name.ends_with(".tik")) {
+        let tik_path = extract_entry(reader, entry, to)?;
+        keyset.add_title_key(&TitleKey::try_new(&keyset, tik_path)?);
+    }
+
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.name.ends_with(".nca") || entry.name.ends_with(".ncz"))
+    {
+        let header = NcaHeader::from_reader(
+            &keyset,
+            &mut SubStorage::new(&mut *reader, entry.offset, entry.size),
+        )?;
+        if header.content_type != ContentType::Control || header.id_offset() != 0 {
+            continue;
+        }
+        let control = Nca::try_new(&keyset, extract_entry(reader, entry, to)?)?;
+        return Ok(Some((keyset, control)));
+    }
+    Ok(None)
+}
+
+/// Extracts `entry` to the `to` dir, NCZs are decompressed to NCAs.
+pub(crate) fn extract_entry<R: Read + Seek>(
+    reader: &mut R,
+    entry: &Pfs0Entry,
+    to: &Path,
+) -> Result<PathBuf> {
+    // Entries are expected to be plain filenames
+    if entry.name.is_empty() || entry.name == ".." || entry.name.contains(['/', '\\']) {
+        bail!("Invalid entry name '{}'", entry.name);
+    }
+
+    if let Some(stem) = entry.name.strip_suffix(".ncz") {
+        let dest = to.join(format!("{}.nca", stem));
+        decompress_entry(reader, entry.offset, entry.size, &dest)
+            .map_err(|err| eyre!("Failed to decompress '{}': {}", entry.name, err))?;
+        return Ok(dest);
+    }
+
+    let dest = to.join(&entry.name);
+    let mut writer = BufWriter::new(fs::File::create(&dest)?);
+    Pfs0::copy_entry(reader, entry, &mut writer)?;
+    Ok(dest)
+}
+
+/// SHA-256 and size of the NCA in `entry`, NCZs are decompressed for it.
+fn hash_entry<R: Read + Seek>(reader: &mut R, entry: &Pfs0Entry) -> Result<([u8; 0x20], u64)> {
+    let mut hasher = Sha256::new();
+    let size = if entry.name.ends_with(".ncz") {
+        let mut storage = SubStorage::new(reader, entry.offset, entry.size);
+        Ncz::from_reader(&mut storage)?.decompress(&mut storage, &mut hasher)?
+    } else {
+        Pfs0::copy_entry(reader, entry, &mut hasher)?
+    };
+    Ok((hasher.finalize().into(), size))
+}
+
+/// Converts a NSZ to a NSP, decompressing all of its NCZs.
+pub fn nsz_to_nsp<P, Q, R>(nsz: P, outdir: Q, tempdir_in: R) -> Result<Nsp>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    info!(
+        nsz = %nsz.as_ref().display(),
+        size = %get_fmt_size(nsz.as_ref()).unwrap_or_default(),
+        "Converting to NSP"
+    );
+
+    let nsz = Nsp::try_new(nsz.as_ref())?;
+    if !nsz.is_compressed() {
+        bail!("'{}' is not a NSZ file", nsz.path.display());
+    }
+    let stem = nsz
+        .path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .ok_or_else(|| eyre!("Failed to get filename of '{}'", nsz.path.display()))?;
+
+    let data_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+    nsz.unpack(data_dir.path())?;
+    let nsp = Nsp::pack(&stem, data_dir.path(), outdir.as_ref())?;
+
+    info!(nsp = %nsp.path.display(), "Converted to NSP");
+
+    Ok(nsp)
+}
+
+/// Converts a NSP to a NSZ, compressing its NCAs with `options`.
+pub fn nsp_to_nsz<P, Q, R>(nsp: P, outdir: Q, tempdir_in: R, options: &NczOptions) -> Result<Nsp>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    info!(
+        nsp = %nsp.as_ref().display(),
+        size = %get_fmt_size(nsp.as_ref()).unwrap_or_default(),
+        "Converting to NSZ"
+    );
+
+    let mut keyset = Keyset::from_default_path()?;
+    let nsp = Nsp::try_new(nsp.as_ref())?;
+    if nsp.is_compressed() {
+        bail!("'{}' is already a NSZ file", nsp.path.display());
+    }
+    nsp.check_keys(&keyset)?;
+    let stem = nsp
+        .path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .ok_or_else(|| eyre!("Failed to get filename of '{}'", nsp.path.display()))?;
+
+    let data_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+    nsp.unpack(data_dir.path())?;
+    // DLC bundles have a Ticket per AddOnContent
+    keyset.add_title_keys_from_dir(data_dir.path())?;
+    let nsz = Nsp::pack_nsz(&keyset, &stem, data_dir.path(), outdir.as_ref(), options)?;
+
+    info!(nsz = %nsz.path.display(), "Converted to NSZ");
+
+    Ok(nsz)
+}
@@ -0,0 +1,206 @@
+//! https://switchbrew.org/wiki/NCA#PFS0
+//!
+//! Reader and builder for PFS0, the partition file system used by NSPs and NCA sections.
+
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use tracing::debug;
+
+use super::{copy_exact, le_u32, le_u64};
+
+const PFS0_MAGIC: &[u8; 4] = b"PFS0";
+const HEADER_SIZE: usize = 0x10;
+const ENTRY_SIZE: usize = 0x18;
+/// Total header size (including the string table) is padded to this.
+const HEADER_ALIGNMENT: usize = 0x20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pfs0Entry {
+    pub name: String,
+    /// Offset of the file data relative to the start of the PFS0.
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Pfs0 {
+    pub entries: Vec<Pfs0Entry>,
+}
+
+impl Pfs0 {
+    /// Parses the PFS0 header, the `reader` should start at the beginning of the PFS0.
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if &header[..0x4] != PFS0_MAGIC {
+            bail!("Invalid PFS0 magic");
+        }
+        let entry_count = le_u32(&header, 0x4) as usize;
+        let string_table_size = le_u32(&header, 0x8) as usize;
+        // Don't trust the counts of a corrupt header for allocating the tables
+        let header_size =
+            (HEADER_SIZE + entry_count * ENTRY_SIZE) as u64 + string_table_size as u64;
+        let len = reader.seek(SeekFrom::End(0))?;
+        if header_size > len {
+            bail!(
+                "PFS0 header size {:#x} exceeds the PFS0 size {:#x}",
+                header_size,
+                len
+            );
+        }
+        reader.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+
+        let mut entry_table = vec![0u8; entry_count * ENTRY_SIZE];
+        reader.read_exact(&mut entry_table)?;
+        let mut string_table = vec![0u8; string_table_size];
+        reader.read_exact(&mut string_table)?;
+
+        let data_offset = (HEADER_SIZE + entry_table.len() + string_table.len()) as u64;
+        let entries = entry_table
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| {
+                let name_offset = le_u32(entry, 0x10) as usize;
+                let name = string_table
+                    .get(name_offset..)
+                    .and_then(|s| s.split(|ch| *ch == 0).next())
+                    .ok_or_else(|| eyre!("PFS0 entry name is out of bounds"))?;
+                Ok(Pfs0Entry {
+                    name: String::from_utf8_lossy(name).into(),
+                    offset: data_offset + le_u64(entry, 0x0),
+                    size: le_u64(entry, 0x8),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        debug!(?entries, "Parsed PFS0");
+
+        Ok(Self { entries })
+    }
+    pub fn find(&self, name: &str) -> Option<&Pfs0Entry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+    /// Copies the data of `entry` from `reader` to `writer`.
+    pub fn copy_entry<R, W>(reader: &mut R, entry: &Pfs0Entry, writer: &mut W) -> Result<u64>
+    where
+        R: Read + Seek,
+        W: Write,
+    {
+        copy_exact(reader, entry.offset, entry.size, writer)
+            .map_err(|err| eyre!("Failed to read '{}' from PFS0: {}", entry.name, err))
+    }
+}
+
+/// Builds a PFS0 out of files on the disk.
+#[derive(Debug, Default, Clone)]
+pub struct Pfs0Builder {
+    files: Vec<(String, PathBuf)>,
+}
+
+impl Pfs0Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Entries are written in the order they were added.
+    pub fn add_file<S: Into<String>, P: AsRef<Path>>(&mut self, name: S, path: P) -> &mut Self {
+        self.files.push((name.into(), path.as_ref().to_owned()));
+        self
+    }
+    /// Writes the PFS0 to `writer`, returns the total no. of bytes written.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<u64> {
+        let sizes = self
+            .files
+            .iter()
+            .map(|(_, path)| Ok(fs::metadata(path)?.len()))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut string_table = vec![];
+        let mut entry_table = Vec::with_capacity(self.files.len() * ENTRY_SIZE);
+        let mut data_offset = 0u64;
+        for ((name, _), size) in self.files.iter().zip(&sizes) {
+            entry_table.extend(data_offset.to_le_bytes());
+            entry_table.extend(size.to_le_bytes());
+            entry_table.extend((string_table.len() as u32).to_le_bytes());
+            entry_table.extend(0u32.to_le_bytes());
+            string_table.extend(name.as_bytes());
+            string_table.push(0);
+            data_offset += size;
+        }
+        let header_size = HEADER_SIZE + entry_table.len() + string_table.len();
+        string_table.resize(
+            string_table.len()
+                + (HEADER_ALIGNMENT - header_size % HEADER_ALIGNMENT) % HEADER_ALIGNMENT,
+            0,
+        );
+
+        writer.write_all(PFS0_MAGIC)?;
+        writer.write_all(&(self.files.len() as u32).to_le_bytes())?;
+        writer.write_all(&(string_table.len() as u32).to_le_bytes())?;
+        writer.write_all(&[0u8; 0x4])?;
+        writer.write_all(&entry_table)?;
+        writer.write_all(&string_table)?;
+        let mut written = (HEADER_SIZE + entry_table.len() + string_table.len()) as u64;
+
+        for ((name, path), size) in self.files.iter().zip(&sizes) {
+            let copied = io::copy(&mut fs::File::open(path)?, writer)?;
+            if copied != *size {
+                bail!("'{}' changed in size while being packed", name);
+            }
+            written += copied;
+        }
+        writer.flush()?;
+
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: [(&str, &[u8]); 3] = [
+            ("a.nca", &[0xAB; 0x1234]),
+            ("empty.tik", b""),
+            ("b.cnmt.nca", b"hello"),
+        ];
+        let mut builder = Pfs0Builder::new();
+        for (name, data) in files {
+            let path = dir.path().join(name);
+            std::fs::write(&path, data).unwrap();
+            builder.add_file(name, path);
+        }
+        let mut image = vec![];
+        let written = builder.write(&mut image).unwrap();
+        assert_eq!(written, image.len() as u64);
+
+        let mut reader = Cursor::new(&image);
+        let pfs0 = Pfs0::from_reader(&mut reader).unwrap();
+        assert_eq!(pfs0.entries.len(), files.len());
+        for ((name, data), entry) in files.iter().zip(&pfs0.entries) {
+            assert_eq!(entry.name, *name);
+            assert_eq!(entry.size, data.len() as u64);
+            let mut out = vec![];
+            Pfs0::copy_entry(&mut reader, entry, &mut out).unwrap();
+            assert_eq!(out, *data);
+        }
+        // Data starts right after the padded header
+        assert_eq!(pfs0.entries[0].offset % HEADER_ALIGNMENT as u64, 0);
+    }
+
+    #[test]
+    fn oversized_tables() {
+        let mut image = PFS0_MAGIC.to_vec();
+        image.extend(u32::MAX.to_le_bytes());
+        image.extend(u32::MAX.to_le_bytes());
+        image.extend([0u8; 0x4]);
+        assert!(Pfs0::from_reader(&mut Cursor::new(&image)).is_err());
+    }
+}
@@ -0,0 +1,687 @@
+//! https://switchbrew.org/wiki/RomFS
+//!
+//! Reader and builder for RomFS, the read-only file system used by NCA sections.
+
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info};
+
+use super::{copy_exact, le_u32, le_u64, nca::MEDIA_UNIT_SIZE, storage::SubStorage};
+
+const HEADER_SIZE: usize = 0x50;
+const DIR_ENTRY_SIZE: usize = 0x18;
+const FILE_ENTRY_SIZE: usize = 0x20;
+const EMPTY_ENTRY: u32 = 0xFFFFFFFF;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomFsEntry {
+    /// Path relative to the root, separated by '/'.
+    pub path: String,
+    /// Offset of the file data relative to the start of the RomFS.
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RomFs {
+    /// Paths of all the directories excluding the root.
+    pub dirs: Vec<String>,
+    pub files: Vec<RomFsEntry>,
+}
+
+impl RomFs {
+    /// Parses the RomFS metadata, the `reader` should start at the beginning of the RomFS
+    /// i.e. the data level of the IVFC.
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if le_u64(&header, 0x0) != HEADER_SIZE as u64 {
+            bail!("Invalid RomFS header size");
+        }
+        let dir_table = read_table(reader, le_u64(&header, 0x18), le_u64(&header, 0x20))?;
+        let file_table = read_table(reader, le_u64(&header, 0x38), le_u64(&header, 0x40))?;
+        let data_offset = le_u64(&header, 0x48);
+
+        let mut romfs = Self::default();
+        // (dir entry offset, dir path)
+        let mut pending = vec![(0u32, String::new())];
+        // Each dir can only be visited once, guards against malformed tables looping forever
+        let mut visited = 0;
+        while let Some((dir_offset, dir_path)) = pending.pop() {
+            visited += 1;
+            if visited > dir_table.len() / DIR_ENTRY_SIZE + 1 {
+                bail!("RomFS directory table is malformed");
+            }
+            let dir = entry_at(&dir_table, dir_offset, DIR_ENTRY_SIZE)?;
+
+            let mut child = le_u32(dir, 0x8);
+            while child != EMPTY_ENTRY {
+                let entry = entry_at(&dir_table, child, DIR_ENTRY_SIZE)?;
+                let path = join(&dir_path, entry_name(entry, DIR_ENTRY_SIZE)?)?;
+                romfs.dirs.push(path.clone());
+                pending.push((child, path));
+                child = le_u32(entry, 0x4);
+            }
+
+            let mut file = le_u32(dir, 0xC);
+            while file != EMPTY_ENTRY {
+                let entry = entry_at(&file_table, file, FILE_ENTRY_SIZE)?;
+                romfs.files.push(RomFsEntry {
+                    path: join(&dir_path, entry_name(entry, FILE_ENTRY_SIZE)?)?,
+                    offset: data_offset + le_u64(entry, 0x8),
+                    size: le_u64(entry, 0x10),
+                });
+                if romfs.files.len() > file_table.len() / FILE_ENTRY_SIZE {
+                    bail!("RomFS file table is malformed");
+                }
+                file = le_u32(entry, 0x4);
+            }
+        }
+        debug!(
+            dirs = romfs.dirs.len(),
+            files = romfs.files.len(),
+            "Parsed RomFS"
+        );
+
+        Ok(romfs)
+    }
+    pub fn find(&self, path: &str) -> Option<&RomFsEntry> {
+        let path = path.trim_start_matches('/');
+        self.files.iter().find(|entry| entry.path == path)
+    }
+    /// Provides a reader over the data of `entry`.
+    pub fn open_entry<R: Read + Seek>(reader: R, entry: &RomFsEntry) -> SubStorage<R> {
+        SubStorage::new(reader, entry.offset, entry.size)
+    }
+    /// Copies the data of `entry` from `reader` to `writer`.
+    pub fn copy_entry<R, W>(reader: &mut R, entry: &RomFsEntry, writer: &mut W) -> Result<u64>
+    where
+        R: Read + Seek,
+        W: Write,
+    {
+        copy_exact(reader, entry.offset, entry.size, writer)
+            .map_err(|err| eyre!("Failed to read '{}' from RomFS: {}", entry.path, err))
+    }
+    /// Extracts the files/dirs under `prefix` to the `to` dir, an empty `prefix` extracts everything.
+    pub fn extract<R, P>(&self, reader: &mut R, prefix: &str, to: P) -> Result<Vec<PathBuf>>
+    where
+        R: Read + Seek,
+        P: AsRef<Path>,
+    {
+        let prefix = prefix.trim_matches('/');
+        let selected = |path: &str| {
+            prefix.is_empty()
+                || path == prefix
+                || path
+                    .strip_prefix(prefix)
+                    .map_or(false, |rest| rest.starts_with('/'))
+        };
+
+        fs::create_dir_all(to.as_ref())?;
+        for dir in self.dirs.iter().filter(|dir| selected(dir)) {
+            fs::create_dir_all(to.as_ref().join(dir))?;
+        }
+        let mut extracted = vec![];
+        for entry in self.files.iter().filter(|entry| selected(&entry.path)) {
+            let dest = to.as_ref().join(&entry.path);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            RomFs::copy_entry(reader, entry, &mut BufWriter::new(fs::File::create(&dest)?))?;
+            extracted.push(dest);
+        }
+        if extracted.is_empty() && !prefix.is_empty() && !self.dirs.iter().any(|d| d == prefix) {
+            bail!("Couldn't find '{}' in RomFS", prefix);
+        }
+        info!(files = extracted.len(), to = %to.as_ref().display(), "Extracted RomFS");
+
+        Ok(extracted)
+    }
+}
+
+fn read_table<R: Read + Seek>(reader: &mut R, offset: u64, size: u64) -> Result<Vec<u8>> {
+    let mut table = vec![];
+    copy_exact(reader, offset, size, &mut table)?;
+    Ok(table)
+}
+
+fn entry_at(table: &[u8], offset: u32, header_size: usize) -> Result<&[u8]> {
+    table
+        .get(offset as usize..)
+        .filter(|entry| entry.len() >= header_size)
+        .ok_or_else(|| eyre!("RomFS entry at {:#x} is out of bounds", offset))
+}
+
+fn entry_name(entry: &[u8], header_size: usize) -> Result<&str> {
+    let name_size = le_u32(entry, header_size - 0x4) as usize;
+    let name = entry
+        .get(header_size..header_size + name_size)
+        .ok_or_else(|| eyre!("RomFS entry name is out of bounds"))?;
+    std::str::from_utf8(name).map_err(|err| eyre!("RomFS entry name is not UTF-8: {}", err))
+}
+
+fn join(dir: &str, name: &str) -> Result<String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        bail!("Invalid RomFS entry name '{}'", name);
+    }
+    Ok(if dir.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", dir, name)
+    })
+}
+
+/// RomFS data is placed after the header, at this offset.
+const DATA_OFFSET: u64 = 0x200;
+const DATA_ALIGNMENT: u64 = 0x10;
+/// Block size of all the IVFC levels.
+const IVFC_BLOCK_SIZE: u64 = 0x4000;
+const IVFC_LEVEL_COUNT: usize = 6;
+const IVFC_VERSION: u32 = 0x20000;
+const SHA256_SIZE: u64 = 0x20;
+
+#[derive(Debug, Clone)]
+struct DirNode {
+    name: String,
+    parent: usize,
+    dirs: Vec<usize>,
+    files: Vec<usize>,
+    offset: u32,
+}
+
+#[derive(Debug, Clone)]
+struct FileNode {
+    name: String,
+    path: PathBuf,
+    parent: usize,
+    size: u64,
+    offset: u32,
+    data_offset: u64,
+}
+
+/// Builds a RomFS image out of a dir on the disk.
+///
+/// Entries are sorted by their name so the same input always results in the same image.
+#[derive(Debug, Clone)]
+pub struct RomFsBuilder {
+    dirs: Vec<DirNode>,
+    files: Vec<FileNode>,
+}
+
+impl RomFsBuilder {
+    pub fn from_dir<P: AsRef<Path>>(root: P) -> Result<Self> {
+        info!(dir = %root.as_ref().display(), "Building RomFS");
+
+        let mut builder = Self {
+            dirs: vec![DirNode {
+                name: String::new(),
+                parent: 0,
+                dirs: vec![],
+                files: vec![],
+                offset: 0,
+            }],
+            files: vec![],
+        };
+        // Dirs are ordered depth-first, files are grouped by their parent in the same order
+        let mut pending = vec![(0, root.as_ref().to_owned())];
+        while let Some((index, path)) = pending.pop() {
+            let mut entries = fs::read_dir(&path)?
+                .map(|entry| {
+                    let entry = entry?;
+                    let name = entry.file_name().into_string().map_err(|name| {
+                        eyre!("'{}' is not a valid UTF-8 name", name.to_string_lossy())
+                    })?;
+                    Ok((name, entry.path()))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            entries.sort();
+
+            let mut child_dirs = vec![];
+            for (name, path) in entries {
+                let meta = fs::metadata(&path)?;
+                if meta.is_dir() {
+                    let child = builder.dirs.len();
+                    builder.dirs[index].dirs.push(child);
+                    child_dirs.push((child, path));
+                    builder.dirs.push(DirNode {
+                        name,
+                        parent: index,
+                        dirs: vec![],
+                        files: vec![],
+                        offset: 0,
+                    });
+                } else {
+                    let child = builder.files.len();
+                    builder.dirs[index].files.push(child);
+                    builder.files.push(FileNode {
+                        name,
+                        path,
+                        parent: index,
+                        size: meta.len(),
+                        offset: 0,
+                        data_offset: 0,
+                    });
+                }
+            }
+            pending.extend(child_dirs.into_iter().rev());
+        }
+
+        // Files are reordered so their data follows the order of the file table
+        let mut order = vec![];
+        for dir in &builder.dirs {
+            order.extend(dir.files.iter().copied());
+        }
+        let mut remap = vec![0; order.len()];
+        for (new, old) in order.iter().enumerate() {
+            remap[*old] = new;
+        }
+        let mut files = order
+            .into_iter()
+            .map(|old| builder.files[old].clone())
+            .collect::<Vec<_>>();
+        for dir in builder.dirs.iter_mut() {
+            dir.files.iter_mut().for_each(|file| *file = remap[*file]);
+        }
+
+        let mut dir_offset = 0;
+        for dir in builder.dirs.iter_mut() {
+            dir.offset = dir_offset;
+            dir_offset += (DIR_ENTRY_SIZE + align4(dir.name.len())) as u32;
+        }
+        let (mut file_offset, mut data_offset) = (0, 0);
+        for file in files.iter_mut() {
+            file.offset = file_offset;
+            file.data_offset = data_offset;
+            file_offset += (FILE_ENTRY_SIZE + align4(file.name.len())) as u32;
+            data_offset = align(data_offset + file.size, DATA_ALIGNMENT);
+        }
+        builder.files = files;
+        debug!(
+            dirs = builder.dirs.len(),
+            files = builder.files.len(),
+            "Collected RomFS entries"
+        );
+
+        Ok(builder)
+    }
+    /// Size of the RomFS image.
+    pub fn romfs_size(&self) -> u64 {
+        let (offset, _) = self.layout();
+        offset
+            + (self
+                .metadata()
+                .iter()
+                .map(|table| table.len())
+                .sum::<usize>() as u64)
+    }
+    /// Writes the RomFS image to `writer`, returns the total no. of bytes written.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<u64> {
+        let (tables_offset, data_size) = self.layout();
+        let [dir_hash, dir_meta, file_hash, file_meta] = self.metadata();
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        let mut offset = tables_offset;
+        header.extend((HEADER_SIZE as u64).to_le_bytes());
+        for table in [&dir_hash, &dir_meta, &file_hash, &file_meta] {
+            header.extend(offset.to_le_bytes());
+            header.extend((table.len() as u64).to_le_bytes());
+            offset += table.len() as u64;
+        }
+        header.extend(DATA_OFFSET.to_le_bytes());
+        writer.write_all(&header)?;
+        write_zeroes(writer, DATA_OFFSET - HEADER_SIZE as u64)?;
+
+        let mut written = DATA_OFFSET;
+        for file in &self.files {
+            write_zeroes(writer, DATA_OFFSET + file.data_offset - written)?;
+            let copied = io::copy(&mut fs::File::open(&file.path)?, writer)?;
+            if copied != file.size {
+                bail!(
+                    "'{}' changed in size while being packed",
+                    file.path.display()
+                );
+            }
+            written = DATA_OFFSET + file.data_offset + copied;
+        }
+        write_zeroes(writer, DATA_OFFSET + data_size - written)?;
+        write_zeroes(writer, tables_offset - DATA_OFFSET - data_size)?;
+        for table in [dir_hash, dir_meta, file_hash, file_meta] {
+            writer.write_all(&table)?;
+        }
+        writer.flush()?;
+
+        Ok(offset)
+    }
+    /// Writes the RomFS image along with its IVFC hash levels, as laid out in a NCA section.
+    ///
+    /// Returns the hash data for the section's FsHeader.
+    pub fn write_section<W: Write + Seek>(&self, writer: &mut W) -> Result<IvfcHeader> {
+        let start = writer.stream_position()?;
+
+        let mut sizes = [0u64; IVFC_LEVEL_COUNT];
+        sizes[IVFC_LEVEL_COUNT - 1] = self.romfs_size();
+        for i in (0..IVFC_LEVEL_COUNT - 1).rev() {
+            sizes[i] = align(sizes[i + 1], IVFC_BLOCK_SIZE) / IVFC_BLOCK_SIZE * SHA256_SIZE;
+        }
+        let mut offsets = [0u64; IVFC_LEVEL_COUNT];
+        for i in 1..IVFC_LEVEL_COUNT {
+            offsets[i] = align(offsets[i - 1] + sizes[i - 1], IVFC_BLOCK_SIZE);
+        }
+        if sizes[0] > IVFC_BLOCK_SIZE {
+            bail!("RomFS is too large for the IVFC levels");
+        }
+
+        // Data level is hashed while being written, hash levels are written in front of it
+        writer.seek(SeekFrom::Start(start + offsets[IVFC_LEVEL_COUNT - 1]))?;
+        let mut hasher = BlockHasher::new(writer);
+        self.write(&mut hasher)?;
+        let (writer, mut hashes) = hasher.finish();
+        for i in (0..IVFC_LEVEL_COUNT - 1).rev() {
+            writer.seek(SeekFrom::Start(start + offsets[i]))?;
+            writer.write_all(&hashes)?;
+            hashes = hash_blocks(&hashes);
+        }
+        let master_hash = hashes;
+
+        let end = start + offsets[IVFC_LEVEL_COUNT - 1] + sizes[IVFC_LEVEL_COUNT - 1];
+        writer.seek(SeekFrom::Start(end))?;
+        write_zeroes(writer, align(end - start, MEDIA_UNIT_SIZE) - (end - start))?;
+        writer.flush()?;
+
+        let mut header = IvfcHeader {
+            raw: [0u8; IvfcHeader::SIZE],
+            size: align(end - start, MEDIA_UNIT_SIZE),
+        };
+        header.raw[..0x4].copy_from_slice(b"IVFC");
+        header.raw[0x4..0x8].copy_from_slice(&IVFC_VERSION.to_le_bytes());
+        header.raw[0x8..0xC].copy_from_slice(&(master_hash.len() as u32).to_le_bytes());
+        header.raw[0xC..0x10].copy_from_slice(&(IVFC_LEVEL_COUNT as u32 + 1).to_le_bytes());
+        for i in 0..IVFC_LEVEL_COUNT {
+            let level = 0x10 + i * 0x18;
+            header.raw[level..level + 0x8].copy_from_slice(&offsets[i].to_le_bytes());
+            header.raw[level + 0x8..level + 0x10].copy_from_slice(&sizes[i].to_le_bytes());
+            header.raw[level + 0x10..level + 0x14]
+                .copy_from_slice(&IVFC_BLOCK_SIZE.trailing_zeros().to_le_bytes());
+        }
+        header.raw[IvfcHeader::MASTER_HASH_OFFSET..IvfcHeader::MASTER_HASH_OFFSET + 0x20]
+            .copy_from_slice(&master_hash);
+        debug!(?offsets, ?sizes, "Built IVFC levels");
+
+        Ok(header)
+    }
+    /// Returns the offset of the metadata tables and the size of the file data.
+    fn layout(&self) -> (u64, u64) {
+        let data_size = self
+            .files
+            .last()
+            .map_or(0, |file| file.data_offset + file.size);
+        (align(DATA_OFFSET + data_size, 0x4), data_size)
+    }
+    /// Returns the dir hash, dir meta, file hash and file meta tables.
+    fn metadata(&self) -> [Vec<u8>; 4] {
+        let mut dir_hash = vec![EMPTY_ENTRY; hash_table_count(self.dirs.len())];
+        let mut dir_meta = vec![];
+        for (index, dir) in self.dirs.iter().enumerate() {
+            let parent = self.dirs[dir.parent].offset;
+            let bucket = path_hash(parent, &dir.name) as usize % dir_hash.len();
+            let sibling = self.dirs[dir.parent]
+                .dirs
+                .iter()
+                .skip_while(|&&sibling| sibling != index)
+                .nth(1)
+                .map_or(EMPTY_ENTRY, |&sibling| self.dirs[sibling].offset);
+            for value in [
+                parent,
+                sibling,
+                dir.dirs
+                    .first()
+                    .map_or(EMPTY_ENTRY, |&i| self.dirs[i].offset),
+                dir.files
+                    .first()
+                    .map_or(EMPTY_ENTRY, |&i| self.files[i].offset),
+                dir_hash[bucket],
+                dir.name.len() as u32,
+            ] {
+                dir_meta.extend(value.to_le_bytes());
+            }
+            dir_meta.extend(dir.name.as_bytes());
+            dir_meta.resize(align4(dir_meta.len()), 0);
+            dir_hash[bucket] = dir.offset;
+        }
+
+        let mut file_hash = vec![EMPTY_ENTRY; hash_table_count(self.files.len())];
+        let mut file_meta = vec![];
+        for (index, file) in self.files.iter().enumerate() {
+            let parent = self.dirs[file.parent].offset;
+            let bucket = path_hash(parent, &file.name) as usize % file_hash.len();
+            let sibling = self.dirs[file.parent]
+                .files
+                .iter()
+                .skip_while(|&&sibling| sibling != index)
+                .nth(1)
+                .map_or(EMPTY_ENTRY, |&sibling| self.files[sibling].offset);
+            file_meta.extend(parent.to_le_bytes());
+            file_meta.extend(sibling.to_le_bytes());
+            file_meta.extend(file.data_offset.to_le_bytes());
+            file_meta.extend(file.size.to_le_bytes());
+            file_meta.extend(file_hash[bucket].to_le_bytes());
+            file_meta.extend((file.name.len() as u32).to_le_bytes());
+            file_meta.extend(file.name.as_bytes());
+            file_meta.resize(align4(file_meta.len()), 0);
+            file_hash[bucket] = file.offset;
+        }
+
+        let to_bytes = |table: Vec<u32>| table.into_iter().flat_map(u32::to_le_bytes).collect();
+        [to_bytes(dir_hash), dir_meta, to_bytes(file_hash), file_meta]
+    }
+}
+
+/// https://switchbrew.org/wiki/NCA#HierarchicalIntegrity
+#[derive(Debug, Clone)]
+pub struct IvfcHeader {
+    /// Goes into the hash data of the FsHeader.
+    pub raw: [u8; IvfcHeader::SIZE],
+    /// Size of the whole section, including the hash levels.
+    pub size: u64,
+}
+
+impl IvfcHeader {
+    pub const SIZE: usize = 0xF8;
+    const MASTER_HASH_OFFSET: usize = 0xC0;
+}
+
+/// Hashes the data written through it in blocks of `IVFC_BLOCK_SIZE`.
+struct BlockHasher<W> {
+    inner: W,
+    hasher: Sha256,
+    filled: u64,
+    hashes: Vec<u8>,
+}
+
+impl<W: Write> BlockHasher<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            filled: 0,
+            hashes: vec![],
+        }
+    }
+    /// The last block is zero padded.
+    fn finish(mut self) -> (W, Vec<u8>) {
+        if self.filled != 0 {
+            self.hasher
+                .update(vec![0u8; (IVFC_BLOCK_SIZE - self.filled) as usize]);
+            self.hashes.extend(self.hasher.finalize_reset());
+        }
+        (self.inner, self.hashes)
+    }
+}
+
+impl<W: Write> Write for BlockHasher<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min((IVFC_BLOCK_SIZE - self.filled) as usize);
+        let written = self.inner.write(&buf[..len])?;
+        self.hasher.update(&buf[..written]);
+        self.filled += written as u64;
+        if self.filled == IVFC_BLOCK_SIZE {
+            self.hashes.extend(self.hasher.finalize_reset());
+            self.filled = 0;
+        }
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn hash_blocks(data: &[u8]) -> Vec<u8> {
+    data.chunks(IVFC_BLOCK_SIZE as usize)
+        .flat_map(|block| {
+            let mut hasher = Sha256::new();
+            hasher.update(block);
+            hasher.update(vec![0u8; IVFC_BLOCK_SIZE as usize - block.len()]);
+            hasher.finalize()
+        })
+        .collect()
+}
+
+fn path_hash(parent: u32, name: &str) -> u32 {
+    name.bytes().fold(parent ^ 123456789, |hash, ch| {
+        hash.rotate_right(5) ^ ch as u32
+    })
+}
+
+fn hash_table_count(entries: usize) -> usize {
+    if entries < 3 {
+        3
+    } else if entries < 19 {
+        entries | 1
+    } else {
+        (entries..)
+            .find(|count| {
+                [2, 3, 5, 7, 11, 13, 17]
+                    .iter()
+                    .all(|prime| count % prime != 0)
+            })
+            .expect("Should find a count")
+    }
+}
+
+fn write_zeroes<W: Write>(writer: &mut W, len: u64) -> io::Result<u64> {
+    io::copy(&mut io::repeat(0).take(len), writer)
+}
+
+fn align(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) / alignment * alignment
+}
+
+fn align4(value: usize) -> usize {
+    (value + 0x3) & !0x3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn sample_tree() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        let files: [(&str, &[u8]); 5] = [
+            ("a.txt", b"hello"),
+            ("empty", b""),
+            ("dir/nested/deep.bin", &[0xAB; 0x4321]),
+            ("dir/ゲーム.dat", "データ".as_bytes()),
+            ("dir/empty_file", b""),
+        ];
+        for (path, data) in files {
+            let path = root.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        }
+        std::fs::create_dir_all(root.path().join("empty_dir")).unwrap();
+        root
+    }
+
+    fn build(root: &Path) -> Vec<u8> {
+        let mut image = vec![];
+        RomFsBuilder::from_dir(root)
+            .unwrap()
+            .write(&mut image)
+            .unwrap();
+        image
+    }
+
+    #[test]
+    fn round_trip() {
+        let root = sample_tree();
+        let image = build(root.path());
+        let mut reader = Cursor::new(&image);
+        let romfs = RomFs::from_reader(&mut reader).unwrap();
+
+        let mut dirs = romfs.dirs.clone();
+        dirs.sort();
+        assert_eq!(dirs, ["dir", "dir/nested", "empty_dir"]);
+        let mut paths = romfs
+            .files
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "a.txt",
+                "dir/empty_file",
+                "dir/nested/deep.bin",
+                "dir/ゲーム.dat",
+                "empty"
+            ]
+        );
+        for entry in &romfs.files {
+            let mut data = vec![];
+            RomFs::copy_entry(&mut reader, entry, &mut data).unwrap();
+            assert_eq!(data, std::fs::read(root.path().join(&entry.path)).unwrap());
+        }
+    }
+
+    #[test]
+    fn deterministic_output() {
+        let root = sample_tree();
+        assert_eq!(build(root.path()), build(root.path()));
+    }
+
+    #[test]
+    fn ivfc_master_hash() {
+        let root = sample_tree();
+        let builder = RomFsBuilder::from_dir(root.path()).unwrap();
+        let mut section = Cursor::new(vec![]);
+        let header = builder.write_section(&mut section).unwrap();
+        let section = section.into_inner();
+        assert_eq!(section.len() as u64, header.size);
+
+        // Every level hashes the one after it, the master hash covers the first level
+        let level = |i: usize| {
+            let raw = &header.raw[0x10 + i * 0x18..];
+            let (offset, size) = (le_u64(raw, 0x0) as usize, le_u64(raw, 0x8) as usize);
+            &section[offset..offset + size]
+        };
+        assert_eq!(level(IVFC_LEVEL_COUNT - 1), build(root.path()));
+        let mut hashes = hash_blocks(level(IVFC_LEVEL_COUNT - 1));
+        for i in (0..IVFC_LEVEL_COUNT - 1).rev() {
+            assert_eq!(level(i), hashes);
+            hashes = hash_blocks(&hashes);
+        }
+        assert_eq!(
+            &header.raw[IvfcHeader::MASTER_HASH_OFFSET..IvfcHeader::MASTER_HASH_OFFSET + 0x20],
+            hashes
+        );
+    }
+}
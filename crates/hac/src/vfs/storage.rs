@@ -0,0 +1,154 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::crypto::{AesCtr, AesKey, AES_BLOCK_SIZE};
+
+/// Restricts a `Read + Seek` source to a window of `len` bytes starting at `offset`.
+///
+/// Used for reading containers nested within other containers, for eg- a PFS0 within a
+/// NCA section, without having to extract them first.
+#[derive(Debug)]
+pub struct SubStorage<R> {
+    inner: R,
+    offset: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> SubStorage<R> {
+    pub fn new(inner: R, offset: u64, len: u64) -> Self {
+        Self {
+            inner,
+            offset,
+            len,
+            pos: 0,
+        }
+    }
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for SubStorage<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        let max = (self.len - self.pos).min(buf.len() as u64) as usize;
+        self.inner.seek(SeekFrom::Start(self.offset + self.pos))?;
+        let read = self.inner.read(&mut buf[..max])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for SubStorage<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::End(_) => seek_offset(self.len, pos)?,
+            _ => seek_offset(self.pos, pos)?,
+        };
+        Ok(self.pos)
+    }
+}
+
+/// Object-safe `Read + Seek`, for storages whose type depends on the content, for eg- whether
+/// a NCA section is encrypted or not.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Decrypts an AES-CTR encrypted storage on the fly.
+pub struct AesCtrStorage<R> {
+    inner: R,
+    ctr: AesCtr,
+    upper_iv: u64,
+    /// Offset of `inner` within the NCA, the counter is derived from it.
+    base_offset: u64,
+    pos: u64,
+    buf: Vec<u8>,
+}
+
+impl<R: Read + Seek> AesCtrStorage<R> {
+    const MAX_READ_SIZE: usize = 0x40000;
+
+    pub fn new(inner: R, key: &AesKey, upper_iv: u64, base_offset: u64) -> Self {
+        Self {
+            inner,
+            ctr: AesCtr::new(key),
+            upper_iv,
+            base_offset,
+            pos: 0,
+            buf: vec![],
+        }
+    }
+    /// Counter of the block at `offset` (relative to the start of the NCA).
+    pub fn counter(upper_iv: u64, offset: u64) -> u128 {
+        ((upper_iv as u128) << 64) | (offset >> 4) as u128
+    }
+    pub(crate) fn set_upper_iv(&mut self, upper_iv: u64) {
+        self.upper_iv = upper_iv;
+    }
+    /// The underlying encrypted storage.
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for AesCtrStorage<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let aligned_pos = self.pos & !(AES_BLOCK_SIZE as u64 - 1);
+        let skip = (self.pos - aligned_pos) as usize;
+        let len = (skip + buf.len()).min(Self::MAX_READ_SIZE);
+
+        self.buf.resize(len, 0);
+        self.inner.seek(SeekFrom::Start(aligned_pos))?;
+        let mut read = 0;
+        while read < len {
+            match self.inner.read(&mut self.buf[read..len])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        if read <= skip {
+            return Ok(0);
+        }
+
+        self.ctr.apply(
+            &mut self.buf[..read],
+            Self::counter(self.upper_iv, self.base_offset + aligned_pos),
+        );
+        let n = (read - skip).min(buf.len());
+        buf[..n].copy_from_slice(&self.buf[skip..skip + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for AesCtrStorage<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::End(_) => seek_offset(self.inner.seek(SeekFrom::End(0))?, pos)?,
+            _ => seek_offset(self.pos, pos)?,
+        };
+        Ok(self.pos)
+    }
+}
+
+pub(crate) fn seek_offset(base: u64, pos: SeekFrom) -> io::Result<u64> {
+    let offset = match pos {
+        SeekFrom::Start(offset) => return Ok(offset),
+        SeekFrom::End(offset) | SeekFrom::Current(offset) => offset,
+    };
+    base.checked_add_signed(offset).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
@@ -1,21 +1,31 @@
 //! https://switchbrew.org/wiki/Ticket
 //!
-//! Contains method for extracting `TitleKey` from Tickets, a format used to store an encrypted title key.\
-//! Cheap implementation only supporting 'Common' Title key type.
+//! Tickets store the encrypted title key of a RightsID, either in plain ('Common') or
+//! wrapped with the console-unique eticket RSA key ('Personalized').
 
-use eyre::Result;
+use eyre::{bail, eyre, Result};
 use fs_err as fs;
+use rsa::Oaep;
+use serde::Serialize;
+use sha2::Sha256;
 use std::{
     fmt,
     io::{self, Read, Seek},
     path::Path,
 };
+use strum_macros::FromRepr;
 use tracing::{debug, info};
 
+use super::{le_u32, le_u64};
+use crate::keys::Keyset;
+
+const TICKET_DATA_SIZE: usize = 0x180;
+
 #[derive(Debug, Default, Clone)]
 pub struct TitleKey {
-    rights_id: [u8; 0x10],
-    title_key: [u8; 0x10], // for Common TitleKey type
+    pub rights_id: [u8; 0x10],
+    /// Still encrypted with the titlekek.
+    pub title_key: [u8; 0x10],
 }
 
 impl fmt::Display for TitleKey {
@@ -30,28 +40,159 @@ impl fmt::Display for TitleKey {
 }
 
 impl TitleKey {
-    const RIGHTS_ID_OFFSET: usize = 0x2a0;
-    const TITLE_KEY_OFFSET: usize = 0x180;
+    pub fn try_new<P: AsRef<Path>>(keyset: &Keyset, tik_file: P) -> Result<TitleKey> {
+        let title_key = Ticket::try_new(tik_file.as_ref())?
+            .title_key(keyset)
+            .map_err(|err| eyre!("'{}': {}", tik_file.as_ref().display(), err))?;
+        debug!(title_key = %title_key);
+
+        Ok(title_key)
+    }
+}
+
+/// https://switchbrew.org/wiki/Ticket#Signature_type
+#[derive(Debug, Clone, Copy, FromRepr, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SignatureType {
+    Rsa4096Sha1 = 0x10000,
+    Rsa2048Sha1 = 0x10001,
+    EcdsaSha1 = 0x10002,
+    Rsa4096Sha256 = 0x10003,
+    Rsa2048Sha256 = 0x10004,
+    EcdsaSha256 = 0x10005,
+    HmacSha1 = 0x10006,
+}
 
-    pub fn try_new<P: AsRef<Path>>(tik_file: P) -> Result<TitleKey> {
-        let mut title_key = TitleKey::default();
-        let mut ticket = fs::File::open(tik_file.as_ref())?;
+impl SignatureType {
+    /// Sizes of the signature and the padding after it.
+    fn sizes(&self) -> (usize, usize) {
+        match self {
+            SignatureType::Rsa4096Sha1 | SignatureType::Rsa4096Sha256 => (0x200, 0x3C),
+            SignatureType::Rsa2048Sha1 | SignatureType::Rsa2048Sha256 => (0x100, 0x3C),
+            SignatureType::EcdsaSha1 | SignatureType::EcdsaSha256 => (0x3C, 0x40),
+            SignatureType::HmacSha1 => (0x14, 0x28),
+        }
+    }
+}
 
+#[derive(Debug, Clone, Copy, FromRepr, PartialEq, Eq, Serialize)]
+#[repr(u8)]
+pub enum TitleKeyType {
+    Common = 0x0,
+    Personalized = 0x1,
+}
+
+/// https://switchbrew.org/wiki/Ticket
+#[derive(Debug, Clone)]
+pub struct Ticket {
+    pub signature_type: SignatureType,
+    pub signature: Vec<u8>,
+    pub issuer: String,
+    /// Title key in the first 0x10 bytes for 'Common', RSA-OAEP encrypted for 'Personalized'.
+    pub title_key_block: Vec<u8>,
+    pub format_version: u8,
+    pub title_key_type: TitleKeyType,
+    pub ticket_version: u16,
+    pub license_type: u8,
+    /// Master key revision of the titlekek.
+    pub key_generation: u8,
+    pub property_mask: u16,
+    pub ticket_id: u64,
+    pub device_id: u64,
+    pub rights_id: [u8; 0x10],
+    pub account_id: u32,
+}
+
+impl Ticket {
+    pub fn try_new<P: AsRef<Path>>(tik_file: P) -> Result<Self> {
         info!(tik = %tik_file.as_ref().display(), "Reading ticket");
+        Ticket::from_reader(&mut fs::File::open(tik_file.as_ref())?)
+            .map_err(|err| eyre!("Failed to read '{}': {}", tik_file.as_ref().display(), err))
+    }
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        reader.seek(io::SeekFrom::Start(0))?;
+        let mut signature_type = [0u8; 0x4];
+        reader.read_exact(&mut signature_type)?;
+        let signature_type = SignatureType::from_repr(u32::from_le_bytes(signature_type))
+            .ok_or_else(|| {
+                eyre!(
+                    "Unknown signature type {:#x}",
+                    u32::from_le_bytes(signature_type)
+                )
+            })?;
+        let (signature_size, padding_size) = signature_type.sizes();
+        let mut signature = vec![0u8; signature_size + padding_size];
+        reader.read_exact(&mut signature)?;
+        signature.truncate(signature_size);
 
-        ticket.seek(io::SeekFrom::Start(TitleKey::RIGHTS_ID_OFFSET as _))?;
-        ticket.read_exact(&mut title_key.rights_id)?;
+        let mut data = [0u8; TICKET_DATA_SIZE];
+        reader.read_exact(&mut data)?;
+        let title_key_type = TitleKeyType::from_repr(data[0x141])
+            .ok_or_else(|| eyre!("Unknown title key type {:#x}", data[0x141]))?;
 
-        ticket.seek(io::SeekFrom::Start(TitleKey::TITLE_KEY_OFFSET as _))?;
-        ticket.read_exact(&mut title_key.title_key)?;
+        let ticket = Self {
+            signature_type,
+            signature,
+            issuer: String::from_utf8_lossy(&data[..0x40])
+                .trim_end_matches('\0')
+                .to_owned(),
+            title_key_block: data[0x40..0x140].to_vec(),
+            format_version: data[0x140],
+            title_key_type,
+            ticket_version: u16::from_le_bytes([data[0x142], data[0x143]]),
+            license_type: data[0x144],
+            key_generation: data[0x145],
+            property_mask: u16::from_le_bytes([data[0x146], data[0x147]]),
+            ticket_id: le_u64(&data, 0x150),
+            device_id: le_u64(&data, 0x158),
+            rights_id: data[0x160..0x170].try_into().expect("Slice is of len 0x10"),
+            account_id: le_u32(&data, 0x170),
+        };
         debug!(
-            title_key = ?format!(
-                "{}={}",
-                hex::encode(title_key.rights_id),
-                hex::encode(title_key.title_key)
-            )
+            rights_id = %hex::encode(ticket.rights_id),
+            title_key_type = ?ticket.title_key_type,
+            key_generation = ticket.key_generation,
+            "Parsed ticket"
         );
 
-        Ok(title_key)
+        Ok(ticket)
+    }
+    pub fn is_personalized(&self) -> bool {
+        self.title_key_type == TitleKeyType::Personalized
+    }
+    /// Title key, still encrypted with the titlekek.
+    ///
+    /// Personalized tickets are unwrapped with the 'eticket_rsa_keypair' of the keyset.
+    pub fn title_key(&self, keyset: &Keyset) -> Result<TitleKey> {
+        let title_key = match self.title_key_type {
+            TitleKeyType::Common => self.title_key_block[..0x10]
+                .try_into()
+                .expect("Slice is of len 0x10"),
+            TitleKeyType::Personalized => {
+                let rsa_key = keyset.eticket_rsa_keypair.as_ref().ok_or_else(|| {
+                    eyre!(
+                        "Ticket is personalized, 'eticket_rsa_keypair' of the console it was \
+                        dumped from is needed in the keyfile"
+                    )
+                })?;
+                let title_key = rsa_key
+                    .decrypt(Oaep::new::<Sha256>(), &self.title_key_block)
+                    .map_err(|_| {
+                        eyre!(
+                            "Failed to decrypt personalized ticket, the 'eticket_rsa_keypair' \
+                            is not of the console it was dumped from"
+                        )
+                    })?;
+                if title_key.len() != 0x10 {
+                    bail!("Personalized ticket has a malformed title key");
+                }
+                title_key.try_into().expect("Len is checked to be 0x10")
+            }
+        };
+
+        Ok(TitleKey {
+            rights_id: self.rights_id,
+            title_key,
+        })
     }
 }
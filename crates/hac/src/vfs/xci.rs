@@ -1,68 +1,341 @@
-use super::nsp::Nsp;
-use crate::backend::{Backend, BackendKind};
-use common::{
-    defines::DEFAULT_PRODKEYS_PATH,
-    utils::{ext_matches, get_fmt_size, move_file},
+use super::{
+    cnmt::{Cnmt, CnmtContentType},
+    copy_exact,
+    hfs0::{Hfs0, Hfs0Entry},
+    le_u64,
+    nacp::NacpData,
+    nca::{ContentType, Nca},
+    ncz::decompress_entry,
+    nsp::{extract_main_control, read_cnmts, Nsp},
+    pfs0::Pfs0Entry,
+    storage::SubStorage,
+    ticket::Ticket,
 };
-use eyre::{bail, Result};
+use crate::keys::Keyset;
+use common::utils::{ext_matches, get_fmt_size, move_file};
+use eyre::{bail, eyre, Result};
 use fs_err as fs;
-use std::{path::Path, process::Command};
-use tracing::{info, warn};
-use walkdir::WalkDir;
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info, warn};
 
-pub fn xci_to_nsps<P, Q, R>(xci: P, outdir: Q, tempdir_in: R) -> Result<Vec<Nsp>>
-where
-    P: AsRef<Path>,
-    Q: AsRef<Path>,
-    R: AsRef<Path>,
-{
-    is_xci(xci.as_ref())?;
+/// https://switchbrew.org/wiki/XCI
+///
+/// Provides access to the HFS0 partitions of a gamecard image, XCZs (with NCZs in the
+/// secure partition) included.
+#[derive(Debug, Clone)]
+pub struct Xci {
+    pub path: PathBuf,
+    pub partitions: Vec<XciPartition>,
+}
 
-    info!(
-        xci = %xci.as_ref().display(),
-        size = %get_fmt_size(xci.as_ref()).unwrap_or_default(),
-        "Converting to NSP"
-    );
+#[derive(Debug, Clone)]
+pub struct XciPartition {
+    pub name: String,
+    /// Offset of the partition relative to the start of the XCI.
+    pub offset: u64,
+    pub size: u64,
+    /// Offsets of the entries are relative to the start of the XCI.
+    pub entries: Vec<Hfs0Entry>,
+}
+
+impl Xci {
+    const HEADER_MAGIC: &[u8; 4] = b"HEAD";
+    const HEADER_OFFSET: u64 = 0x100;
+    /// Some dumps are prefixed with the gamecard's key area.
+    const KEY_AREA_SIZE: u64 = 0x1000;
+    const ROOT_PARTITION_OFFSET: usize = 0x30;
 
-    let backend = Backend::try_new(BackendKind::FourNXCI)?;
-    let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
-    let temp_outdir = tempfile::tempdir_in(tempdir_in.as_ref())?;
-    fs::create_dir_all(&temp_outdir)?;
-    if !Command::new(backend.path())
-        .args([
-            "--keyset".as_ref(),
-            DEFAULT_PRODKEYS_PATH.as_path(),
-            "--tempdir".as_ref(),
+    pub fn try_new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        is_xci(path.as_ref())?;
+
+        info!(xci = %path.as_ref().display(), "Reading XCI partitions");
+
+        let mut reader = BufReader::new(fs::File::open(path.as_ref())?);
+        let xci_size = reader.seek(SeekFrom::End(0))?;
+
+        let mut header = [0u8; 0x100];
+        let mut start = None;
+        for candidate in [0, Xci::KEY_AREA_SIZE] {
+            reader.seek(SeekFrom::Start(candidate + Xci::HEADER_OFFSET))?;
+            if reader.read_exact(&mut header).is_ok() && &header[..0x4] == Xci::HEADER_MAGIC {
+                start = Some(candidate);
+                break;
+            }
+        }
+        let start = start.ok_or_else(|| {
+            eyre!(
+                "'{}' doesn't have a valid gamecard header",
+                path.as_ref().display()
+            )
+        })?;
+
+        let root_offset = start + le_u64(&header, Xci::ROOT_PARTITION_OFFSET);
+        let root = Hfs0::from_reader(&mut SubStorage::new(
+            &mut reader,
+            root_offset,
+            xci_size.saturating_sub(root_offset),
+        ))
+        .map_err(|err| eyre!("Failed to read the root partition: {}", err))?;
+
+        let mut partitions = vec![];
+        for entry in &root.entries {
+            let offset = root_offset + entry.offset;
+            let entries = Hfs0::from_reader(&mut SubStorage::new(&mut reader, offset, entry.size))
+                .map_err(|err| eyre!("Failed to read '{}' partition: {}", entry.name, err))?
+                .entries
+                .into_iter()
+                .map(|mut inner| {
+                    inner.offset += offset;
+                    inner
+                })
+                .collect();
+            partitions.push(XciPartition {
+                name: entry.name.clone(),
+                offset,
+                size: entry.size,
+                entries,
+            });
+        }
+        debug!(?partitions);
+
+        Ok(Self {
+            path: path.as_ref().to_owned(),
+            partitions,
+        })
+    }
+    pub fn partition(&self, name: &str) -> Option<&XciPartition> {
+        self.partitions
+            .iter()
+            .find(|partition| partition.name == name)
+    }
+    /// Reads the CNMTs of the Meta NCAs in the secure partition, only those NCAs get extracted.
+    pub fn cnmts<P: AsRef<Path>>(&self, keyset: &Keyset, tempdir_in: P) -> Result<Vec<Cnmt>> {
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+        read_cnmts(
+            &mut reader,
+            &self.secure_entries()?,
+            keyset,
             temp_dir.path(),
-            "--outdir".as_ref(),
-            temp_outdir.path(),
-            "--rename".as_ref(),
-            xci.as_ref(),
-        ])
-        .status()?
-        .success()
-    {
-        warn!("Encountered an error while trying to convert XCI to NSP");
+        )
     }
+    /// Reads the NACP from the main Control NCA of the secure partition, only it and the
+    /// Tickets get extracted.
+    pub fn nacp<P: AsRef<Path>>(&self, keyset: &Keyset, tempdir_in: P) -> Result<NacpData> {
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+        let (keyset, control) = extract_main_control(
+            &mut reader,
+            &self.secure_entries()?,
+            keyset,
+            temp_dir.path(),
+        )?
+        .ok_or_else(|| eyre!("Couldn't find a Control NCA in '{}'", self.path.display()))?;
+        NacpData::from_nca(&keyset, &control)
+    }
+    /// Entries of the secure partition, their offsets being absolute they're read the
+    /// same way as those of a NSP.
+    pub(crate) fn secure_entries(&self) -> Result<Vec<Pfs0Entry>> {
+        let partition = self.partition("secure").ok_or_else(|| {
+            eyre!(
+                "Couldn't find 'secure' partition in '{}'",
+                self.path.display()
+            )
+        })?;
+        Ok(partition
+            .entries
+            .iter()
+            .map(|entry| Pfs0Entry {
+                name: entry.name.clone(),
+                offset: entry.offset,
+                size: entry.size,
+            })
+            .collect())
+    }
+    /// Extracts all the files in a partition to the `to` dir.
+    pub fn unpack_partition<P: AsRef<Path>>(&self, name: &str, to: P) -> Result<Vec<PathBuf>> {
+        let partition = self.partition(name).ok_or_else(|| {
+            eyre!(
+                "Couldn't find '{}' partition in '{}'",
+                name,
+                self.path.display()
+            )
+        })?;
+
+        info!(xci = %self.path.display(), partition = %name, "Unpacking partition");
+        fs::create_dir_all(to.as_ref())?;
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        let mut unpacked = vec![];
+        for entry in &partition.entries {
+            if entry.name.is_empty() || entry.name.contains(['/', '\\']) || entry.name == ".." {
+                bail!("Invalid entry name '{}'", entry.name);
+            }
+            // NCZs of a XCZ are stored decompressed
+            if let Some(stem) = entry.name.strip_suffix(".ncz") {
+                let dest = to.as_ref().join(format!("{}.nca", stem));
+                decompress_entry(&mut reader, entry.offset, entry.size, &dest)
+                    .map_err(|err| eyre!("Failed to decompress '{}': {}", entry.name, err))?;
+                unpacked.push(dest);
+                continue;
+            }
+            let dest = to.as_ref().join(&entry.name);
+            copy_exact(
+                &mut reader,
+                entry.offset,
+                entry.size,
+                &mut BufWriter::new(fs::File::create(&dest)?),
+            )?;
+            unpacked.push(dest);
+        }
 
-    let mut nsps = vec![];
-    for entry in WalkDir::new(temp_outdir.path())
-        .min_depth(1)
-        .into_iter()
-        .filter_map(|e| e.ok())
+        Ok(unpacked)
+    }
+    /// Repacks the contents of the secure partition as one NSP per title.
+    pub fn to_nsps<P, Q>(&self, outdir: P, tempdir_in: Q) -> Result<Vec<Nsp>>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
     {
-        if let Ok(mut nsp) = Nsp::try_new(entry.path()) {
-            // Moving NSP file from temp outdir to outdir
-            let dest = outdir.as_ref().join(entry.file_name());
+        let keyset = Keyset::from_default_path()?;
+
+        let secure_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+        let files = self.unpack_partition("secure", secure_dir.path())?;
+
+        let mut ncas = BTreeMap::new();
+        for nca_path in files.iter().filter(|path| ext_matches(path, "nca")) {
+            match Nca::try_new(&keyset, nca_path) {
+                Ok(nca) => {
+                    ncas.insert(nca_path.to_owned(), nca);
+                }
+                Err(err) => {
+                    warn!(nca = %nca_path.display(), %err, "Failed to identify NCA, skipping")
+                }
+            }
+        }
+
+        // Grouping NCAs by the CNMT listing them, so that the programs of a multi-program
+        // application stay along with their Meta NCA
+        let mut titles = BTreeMap::<String, Vec<PathBuf>>::new();
+        let mut grouped = HashSet::new();
+        for meta in ncas
+            .values()
+            .filter(|nca| nca.content_type == ContentType::Meta)
+        {
+            let cnmt = match Cnmt::from_nca(&keyset, meta) {
+                Ok(cnmt) => cnmt,
+                Err(err) => {
+                    warn!(nca = %meta.path.display(), %err, "Failed to read CNMT, skipping");
+                    continue;
+                }
+            };
+            let group = titles.entry(cnmt.get_title_id()).or_default();
+            group.push(meta.path.clone());
+            grouped.insert(meta.path.clone());
+            for record in &cnmt.content_records {
+                let nca_path = secure_dir.path().join(record.nca_filename());
+                if ncas.contains_key(&nca_path) {
+                    // Each NCA is moved into a single NSP
+                    if grouped.insert(nca_path.clone()) {
+                        group.push(nca_path);
+                    }
+                } else if record.content_type != CnmtContentType::DeltaFragment {
+                    warn!(
+                        title_id = %cnmt.get_title_id(),
+                        nca = %record.nca_filename(),
+                        "NCA listed in the CNMT is missing"
+                    );
+                }
+            }
+        }
+        for nca_path in ncas.keys().filter(|path| !grouped.contains(*path)) {
+            warn!(nca = %nca_path.display(), "NCA isn't listed in any CNMT, skipping");
+        }
+        debug!(?titles);
+
+        let mut tickets = vec![];
+        for tik_path in files.iter().filter(|path| ext_matches(path, "tik")) {
+            match Ticket::try_new(tik_path) {
+                Ok(ticket) => tickets.push((tik_path, ticket.rights_id)),
+                Err(err) => warn!(%err, "Skipping ticket"),
+            }
+        }
+
+        let stem = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut nsps = vec![];
+        for (program_id, nca_paths) in titles {
+            let nca_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+            for nca in &nca_paths {
+                move_file(
+                    nca,
+                    nca_dir
+                        .path()
+                        .join(nca.file_name().expect("File should've a filename")),
+                )?;
+            }
+            // Tickets are matched by the RightsID of the NCAs, certs are named after them
+            let rights_ids = ncas
+                .iter()
+                .filter(|(nca_path, nca)| {
+                    nca_paths.contains(nca_path) && nca.header.has_rights_id()
+                })
+                .map(|(_, nca)| nca.header.rights_id)
+                .collect::<HashSet<_>>();
+            for (tik_path, _) in tickets
+                .iter()
+                .filter(|(_, rights_id)| rights_ids.contains(rights_id))
+            {
+                let cert_path = tik_path.with_extension("cert");
+                for file in [tik_path.as_path(), cert_path.as_path()] {
+                    if file.is_file() {
+                        // Copied as a ticket might be shared by several titles
+                        fs::copy(
+                            file,
+                            nca_dir
+                                .path()
+                                .join(file.file_name().expect("File should've a filename")),
+                        )?;
+                    }
+                }
+            }
+
+            let mut nsp = Nsp::pack(&program_id, nca_dir.path(), outdir.as_ref())?;
+            let dest = outdir
+                .as_ref()
+                .join(format!("{} [{}].nsp", stem, program_id));
             move_file(&nsp.path, &dest)?;
             nsp.path = dest;
             nsps.push(nsp);
         }
-    }
 
-    if nsps.is_empty() {
-        bail!("Failed to convert XCI to NSP");
+        if nsps.is_empty() {
+            bail!("Failed to convert XCI to NSP");
+        }
+
+        Ok(nsps)
     }
+}
+
+pub fn xci_to_nsps<P, Q, R>(xci: P, outdir: Q, tempdir_in: R) -> Result<Vec<Nsp>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    info!(
+        xci = %xci.as_ref().display(),
+        size = %get_fmt_size(xci.as_ref()).unwrap_or_default(),
+        "Converting to NSP"
+    );
+
+    let nsps = Xci::try_new(xci.as_ref())?.to_nsps(outdir, tempdir_in)?;
 
     info!(?nsps, "Converted to NSPs");
 
@@ -70,8 +343,10 @@ where
 }
 
 fn is_xci<P: AsRef<Path>>(path: P) -> Result<()> {
-    if !path.as_ref().is_file() || !ext_matches(path.as_ref(), "xci") {
-        bail!("'{}' is not a XCI file", path.as_ref().display());
+    if !path.as_ref().is_file()
+        || !(ext_matches(path.as_ref(), "xci") || ext_matches(path.as_ref(), "xcz"))
+    {
+        bail!("'{}' is not a XCI/XCZ file", path.as_ref().display());
     }
     Ok(())
 }
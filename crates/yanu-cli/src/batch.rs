@@ -0,0 +1,116 @@
+//! Job files of `update --batch`.
+
+use common::utils::ext_matches;
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// A single update, relative paths are relative to the job file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchJob {
+    pub base: PathBuf,
+    pub update: PathBuf,
+    #[serde(default)]
+    pub dlc: Vec<PathBuf>,
+    pub outdir: Option<PathBuf>,
+    pub titleid: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobFile {
+    jobs: Vec<BatchJob>,
+}
+
+/// Reads the jobs of a TOML or JSON job file, going by its extension.
+pub fn read_jobs<P: AsRef<Path>>(path: P) -> Result<Vec<BatchJob>> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)?;
+    let file: JobFile = if ext_matches(path, "toml") {
+        toml::from_str(&content)
+            .map_err(|err| eyre!("Invalid job file '{}': {}", path.display(), err))?
+    } else if ext_matches(path, "json") {
+        serde_json::from_str(&content)
+            .map_err(|err| eyre!("Invalid job file '{}': {}", path.display(), err))?
+    } else {
+        bail!("'{}' is not a TOML or JSON job file", path.display());
+    };
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    Ok(file
+        .jobs
+        .into_iter()
+        .map(|job| BatchJob {
+            base: dir.join(job.base),
+            update: dir.join(job.update),
+            dlc: job.dlc.into_iter().map(|dlc| dir.join(dlc)).collect(),
+            outdir: job.outdir.map(|outdir| dir.join(outdir)),
+            titleid: job.titleid,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths_resolve_against_the_job_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let absolute = dir.path().join("elsewhere/base.nsp");
+        let toml = format!(
+            r#"
+            [[jobs]]
+            base = "games/base.nsp"
+            update = "games/update.nsp"
+            dlc = ["dlc/a.nsp", {:?}]
+            outdir = "out"
+            titleid = "0100000000010000"
+
+            [[jobs]]
+            base = {:?}
+            update = "update.nsp"
+            "#,
+            absolute, absolute
+        );
+        fs::write(dir.path().join("jobs.toml"), toml).unwrap();
+
+        let jobs = read_jobs(dir.path().join("jobs.toml")).unwrap();
+        assert_eq!(
+            jobs,
+            [
+                BatchJob {
+                    base: dir.path().join("games/base.nsp"),
+                    update: dir.path().join("games/update.nsp"),
+                    dlc: vec![dir.path().join("dlc/a.nsp"), absolute.clone()],
+                    outdir: Some(dir.path().join("out")),
+                    titleid: Some("0100000000010000".into()),
+                },
+                BatchJob {
+                    base: absolute,
+                    update: dir.path().join("update.nsp"),
+                    dlc: vec![],
+                    outdir: None,
+                    titleid: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn json_and_unknown_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.json");
+        fs::write(&path, r#"{"jobs": [{"base": "b.nsp", "update": "u.nsp"}]}"#).unwrap();
+        assert_eq!(read_jobs(&path).unwrap()[0].base, dir.path().join("b.nsp"));
+
+        fs::write(
+            &path,
+            r#"{"jobs": [{"base": "b.nsp", "update": "u.nsp", "dlcs": []}]}"#,
+        )
+        .unwrap();
+        assert!(read_jobs(&path).is_err());
+    }
+}
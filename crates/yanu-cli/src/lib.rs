@@ -1,2 +1,3 @@
+pub mod batch;
 pub mod opts;
 pub mod utils;
@@ -1,26 +1,43 @@
-use std::{path::PathBuf, time::Instant};
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
 use clap::Parser;
 use common::{
-    defines::{APP_CONFIG_PATH, DEFAULT_PRODKEYS_PATH},
+    defines::{APP_CONFIG_PATH, DEFAULT_PRODKEYS_PATH, LIBRARY_INDEX_PATH},
     format::HumanDuration,
     log,
     utils::{ext_matches, get_disk_free, get_fmt_size, get_paths_size},
 };
 use config::Config;
-#[cfg(not(feature = "android-proot"))]
-use config::{NcaExtractor, NspExtractor};
 use console::style;
 use eyre::{bail, eyre, Result};
 use fs_err as fs;
 #[cfg(unix)]
 use hac::backend::{Backend, BackendKind};
 use hac::{
-    utils::{formatted_nsp_rename, pack::pack_fs_data, unpack::unpack_nsp, update::update_nsp},
-    vfs::{nsp::Nsp, validate_program_id, xci::xci_to_nsps},
+    keys::Keyset,
+    utils::{
+        formatted_nsp_rename,
+        info::PackageInfo,
+        library::{group_by_application, ApplicationGroup, Library, LibraryEntry},
+        pack::pack_fs_data,
+        unpack::unpack_nsp,
+        update::{newest_update, update_nsp},
+    },
+    vfs::{
+        ncz::NczOptions,
+        nsp::{nsp_to_nsz, nsz_to_nsp, ContentIssue, Nsp},
+        validate_program_id,
+        xci::xci_to_nsps,
+    },
 };
 use tracing::{debug, error, info, warn};
-use yanu_cli::opts::{self, YanuCli};
+use yanu_cli::{
+    batch::{read_jobs, BatchJob},
+    opts::{self, YanuCli},
+};
 
 // TODO: This but for specifics like file, and dir
 macro_rules! path_exists {
@@ -105,6 +122,7 @@ fn run() -> Result<()> {
             bail!("Invalid keyfile");
         }
         info!(?keyfile, "Selected keyfile");
+        Keyset::try_new(&keyfile)?;
 
         let default_path = DEFAULT_PRODKEYS_PATH.as_path();
         fs::create_dir_all(
@@ -121,27 +139,119 @@ fn run() -> Result<()> {
         )
     }
 
+    if matches!(
+        &opts.command,
+        Some(opts::Commands::Update(opts::Update {
+            no_verify: true,
+            ..
+        })) | Some(opts::Commands::Unpack(opts::Unpack {
+            no_verify: true,
+            ..
+        }))
+    ) {
+        // Only for this run, the config isn't stored by these
+        config.verify_packages = false;
+    }
+
     let mut timer: Option<Instant> = None;
     match opts.command {
+        Some(opts::Commands::Update(opts)) if opts.batch.is_some() => {
+            let keyset = Keyset::from_default_path()?;
+            let batch = opts.batch.as_ref().expect("Checked by the match guard");
+            fs::metadata(batch)?;
+
+            timer = Some(Instant::now());
+            run_batch(batch, &opts, &keyset, &config)?;
+        }
         Some(opts::Commands::Update(opts)) => {
-            if !DEFAULT_PRODKEYS_PATH.is_file() {
-                bail!("Failed to find keyfile");
-            }
+            let keyset = Keyset::from_default_path()?;
 
             // Path validation
-            path_exists!(Some(&opts.base), Some(&opts.update))?;
+            path_exists!(opts.base.as_ref(), opts.auto.as_ref())?;
+            for path in opts.update.iter().chain(&opts.dlc) {
+                fs::metadata(path)?;
+            }
 
             if let Some(program_id) = &opts.titleid {
                 validate_program_id(program_id)?;
             }
 
+            let (mut base, mut update, mut dlcs) = if let Some(dir) = &opts.auto {
+                let groups = updatable_applications(dir, &keyset, &config)?;
+                if groups.is_empty() {
+                    bail!(
+                        "Couldn't pair any base with an update in '{}'",
+                        dir.display()
+                    );
+                }
+                let group = inquire::Select::new("Select game to update:", groups).prompt()?;
+                let update = group
+                    .newest_update()
+                    .expect("Only groups with an update are listed");
+                eprintln!(
+                    "{} '{}'",
+                    style("Applying update").green().bold(),
+                    update.path.display()
+                );
+                (
+                    Nsp::try_new(&group.base.path)?,
+                    Nsp::try_new(&update.path)?,
+                    select_dlcs(&group)?,
+                )
+            } else {
+                let base_path = opts
+                    .base
+                    .as_ref()
+                    .expect("Clap requires it without '--auto'");
+                let base = Nsp::try_new(base_path)?;
+                let mut updates = collect_packages(&opts.update, base_path)?;
+                let update = match updates.len() {
+                    0 => bail!("No update packages found"),
+                    1 if opts.force => {
+                        warn!("Not checking if the update is of the base");
+                        updates.remove(0)
+                    }
+                    count => {
+                        let (newest, skipped) =
+                            newest_update(&base, updates, &config).map_err(|err| match count {
+                                1 => eyre!("{}\nUse '--force' to apply it anyway", err),
+                                _ => err,
+                            })?;
+                        for skipped in skipped {
+                            eprintln!(
+                                "{} '{}' {}",
+                                style("Skipped").yellow().bold(),
+                                skipped.nsp.path.display(),
+                                style(format!("({})", skipped.reason)).bold().dim()
+                            );
+                        }
+                        if count > 1 {
+                            eprintln!(
+                                "{} '{}'",
+                                style("Applying update").green().bold(),
+                                newest.path.display()
+                            );
+                        }
+                        newest
+                    }
+                };
+                (base, update, vec![])
+            };
+            for dlc in &opts.dlc {
+                dlcs.push(Nsp::try_new(dlc)?);
+            }
+
             info!("Started patching!");
             timer = Some(Instant::now());
             let (mut patched, nacp_data, program_id) = update_nsp(
-                &mut Nsp::try_new(opts.base)?,
-                &mut Nsp::try_new(opts.update)?,
+                &mut base,
+                &mut update,
+                &mut dlcs,
                 opts.titleid.as_deref(),
                 opts.outdir.unwrap_or(default_outdir()?),
+                opts.nsz
+                    .then(|| NczOptions::from(&opts.compression))
+                    .as_ref(),
                 &config,
             )?;
             formatted_nsp_rename(
@@ -152,14 +262,12 @@ fn run() -> Result<()> {
             )?;
             eprintln!(
                 "{} '{}'",
-                style("Patched NSP created at").green().bold(),
+                style("Patched package created at").green().bold(),
                 patched.path.display()
             );
         }
         Some(opts::Commands::Pack(opts)) => {
-            if !DEFAULT_PRODKEYS_PATH.is_file() {
-                bail!("Failed to find keyfile");
-            }
+            Keyset::from_default_path()?;
 
             // Path validation
             // ?let clap do this instead
@@ -178,6 +286,9 @@ fn run() -> Result<()> {
                 opts.romfsdir,
                 opts.exefsdir,
                 opts.outdir.unwrap_or(default_outdir()?),
+                opts.nsz
+                    .then(|| NczOptions::from(&opts.compression))
+                    .as_ref(),
                 &config,
             )?;
             formatted_nsp_rename(
@@ -188,14 +299,12 @@ fn run() -> Result<()> {
             )?;
             eprintln!(
                 "{} '{}'",
-                style("Packed NSP created at").green().bold(),
+                style("Packed package created at").green().bold(),
                 patched.path.display()
             );
         }
         Some(opts::Commands::Unpack(opts)) => {
-            if !DEFAULT_PRODKEYS_PATH.is_file() {
-                bail!("Failed to find keyfile");
-            }
+            Keyset::from_default_path()?;
 
             // Path validation
             path_exists!(Some(&opts.base), opts.update.as_ref())?;
@@ -216,6 +325,7 @@ fn run() -> Result<()> {
             unpack_nsp(
                 &mut Nsp::try_new(opts.base)?,
                 opts.update.and_then(|f| Nsp::try_new(f).ok()).as_mut(),
+                opts.id_offset,
                 &outdir,
                 &config,
             )?;
@@ -226,6 +336,12 @@ fn run() -> Result<()> {
             );
         }
         Some(opts::Commands::Convert(opts)) => {
+            if opts.kind == opts::ConvertKind::Nsp && opts.has_compression() {
+                bail!("'--level' and '--threads' only apply to '--kind nsz'");
+            }
+            let options = NczOptions::from(&opts.compression());
+            Keyset::from_default_path()?;
+
             path_exists!(Some(&opts.file), opts.outdir.as_ref())?;
 
             let outdir = opts.outdir.unwrap_or(default_outdir()?);
@@ -233,7 +349,7 @@ fn run() -> Result<()> {
             match opts.kind {
                 opts::ConvertKind::Nsp => {
                     match opts.file.extension().map(|ext| ext.to_ascii_lowercase()) {
-                        Some(ext) if ext == "xci" => {
+                        Some(ext) if ext == "xci" || ext == "xcz" => {
                             timer = Some(Instant::now());
                             let nsps = xci_to_nsps(opts.file, outdir, &config.temp_dir)?;
                             println!("{}", style("\nPath to converted NSPs:").bold().underlined());
@@ -250,6 +366,40 @@ fn run() -> Result<()> {
                                 );
                             }
                         }
+                        Some(ext) if ext == "nsz" => {
+                            timer = Some(Instant::now());
+                            let nsp = nsz_to_nsp(opts.file, outdir, &config.temp_dir)?;
+                            eprintln!(
+                                "{} '{}' {}",
+                                style("Converted NSP created at").green().bold(),
+                                nsp.path.display(),
+                                style(format!("({})", get_fmt_size(&nsp.path).unwrap_or_default()))
+                                    .bold()
+                                    .dim()
+                            );
+                        }
+                        Some(ext) => bail!(
+                            "Not supported conversion '{} -> {:?}'",
+                            ext.to_string_lossy(),
+                            opts.kind
+                        ),
+                        None => bail!("Non Unicode chars"),
+                    }
+                }
+                opts::ConvertKind::Nsz => {
+                    match opts.file.extension().map(|ext| ext.to_ascii_lowercase()) {
+                        Some(ext) if ext == "nsp" => {
+                            timer = Some(Instant::now());
+                            let nsz = nsp_to_nsz(opts.file, outdir, &config.temp_dir, &options)?;
+                            eprintln!(
+                                "{} '{}' {}",
+                                style("Converted NSZ created at").green().bold(),
+                                nsz.path.display(),
+                                style(format!("({})", get_fmt_size(&nsz.path).unwrap_or_default()))
+                                    .bold()
+                                    .dim()
+                            );
+                        }
                         Some(ext) => bail!(
                             "Not supported conversion '{} -> {:?}'",
                             ext.to_string_lossy(),
@@ -260,6 +410,117 @@ fn run() -> Result<()> {
                 }
             }
         }
+        Some(opts::Commands::Verify(opts)) => {
+            let keyset = Keyset::from_default_path()?;
+
+            for file in &opts.files {
+                fs::metadata(file)?;
+            }
+
+            timer = Some(Instant::now());
+            let mut damaged = 0;
+            for file in &opts.files {
+                let nsp = Nsp::try_new(file)?;
+                let issues = nsp.verify(&keyset, &config.temp_dir)?;
+                let mut is_damaged = false;
+                match issues.as_slice() {
+                    [] => eprintln!("{} '{}'", style("Intact").green().bold(), file.display()),
+                    [ContentIssue::NoMeta] => eprintln!(
+                        "{} '{}' {}",
+                        style("Unverified").yellow().bold(),
+                        file.display(),
+                        style(format!("({})", ContentIssue::NoMeta)).bold().dim()
+                    ),
+                    issues => {
+                        is_damaged = true;
+                        eprintln!("{} '{}'", style("Damaged").red().bold(), file.display());
+                        for issue in issues {
+                            eprintln!("  - {}", issue);
+                        }
+                    }
+                }
+                if opts.deep {
+                    for (name, res) in nsp.verify_ncas(&keyset, &config.temp_dir)? {
+                        match res {
+                            Ok(issues) => {
+                                for issue in issues {
+                                    let label = if issue.is_corrupt() {
+                                        is_damaged = true;
+                                        style("Corrupt").red()
+                                    } else {
+                                        style("Note").yellow()
+                                    };
+                                    eprintln!("  - {} '{}': {}", label, name, issue);
+                                }
+                            }
+                            Err(err) => {
+                                is_damaged = true;
+                                eprintln!("  - {} '{}': {}", style("Corrupt").red(), name, err);
+                            }
+                        }
+                    }
+                }
+                if is_damaged {
+                    damaged += 1;
+                }
+            }
+            if damaged != 0 {
+                bail!("{} of {} packages are damaged", damaged, opts.files.len());
+            }
+        }
+        Some(opts::Commands::Info(opts)) => {
+            let keyset = Keyset::from_default_path()?;
+            let info = PackageInfo::read(&opts.file, &keyset, &config.temp_dir)?;
+            if opts.json {
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                print_package_info(&info);
+            }
+        }
+        Some(opts::Commands::Library(opts)) => match opts.command {
+            opts::LibraryCommand::Scan { dir } => {
+                let dir = dir.or_else(|| config.yanu_dir.clone()).ok_or_else(|| {
+                    eyre!("No directory given and the Yanu Directory isn't set, set it with 'yanu-cli config --yanu-dir'")
+                })?;
+                if !dir.is_dir() {
+                    bail!("'{}' is not a valid directory", dir.display());
+                }
+                let keyset = Keyset::from_default_path()?;
+
+                timer = Some(Instant::now());
+                let mut library = Library::load(LIBRARY_INDEX_PATH.as_path())?;
+                let summary = library.scan(&dir, &keyset, &config.temp_dir)?;
+                library.store(LIBRARY_INDEX_PATH.as_path())?;
+
+                for (path, err) in &summary.failed {
+                    eprintln!(
+                        "{} '{}': {}",
+                        style("Failed").red().bold(),
+                        path.display(),
+                        err
+                    );
+                }
+                eprintln!(
+                    "{} {} packages ({} added, {} updated, {} unchanged, {} removed)",
+                    style("Indexed").green().bold(),
+                    library.entries.len(),
+                    summary.added,
+                    summary.updated,
+                    summary.unchanged,
+                    summary.removed
+                );
+            }
+            opts::LibraryCommand::List { json } => {
+                let library = Library::load(LIBRARY_INDEX_PATH.as_path())?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&library.entries)?);
+                } else if library.entries.is_empty() {
+                    eprintln!("Library is empty, run 'yanu-cli library scan' first");
+                } else {
+                    print_library_table(&library.entries);
+                }
+            }
+        },
         Some(opts::Commands::Config(opts)) => {
             if let Some(yanu_dir) = opts.yanu_dir {
                 if yanu_dir.is_dir() {
@@ -282,23 +543,6 @@ fn run() -> Result<()> {
                 }
             }
 
-            #[cfg(not(feature = "android-proot"))]
-            if let Some(nsp_extractor) = opts.nsp_extractor {
-                // ? How to do this better? and also not have dup enums
-                config.nsp_extractor = match dbg!(nsp_extractor) {
-                    opts::NspExtractor::Hactoolnet => NspExtractor::Hactoolnet,
-                    opts::NspExtractor::Hactool => NspExtractor::Hactool,
-                };
-            }
-
-            #[cfg(not(feature = "android-proot"))]
-            if let Some(nsp_extractor) = opts.nca_extractor {
-                config.nca_extractor = match dbg!(nsp_extractor) {
-                    opts::NcaExtractor::Hactoolnet => NcaExtractor::Hactoolnet,
-                    opts::NcaExtractor::Hac2l => NcaExtractor::Hac2l,
-                };
-            }
-
             info!("Updating config at '{}'", APP_CONFIG_PATH.display());
             Config::store(config)?;
             eprintln!("{}", style("Successfully modified config").green().bold());
@@ -363,6 +607,7 @@ fn run() -> Result<()> {
                 if !ext_matches(&keyfile_path, "keys") {
                     bail!("Invalid keyfile");
                 }
+                Keyset::try_new(&keyfile_path)?;
 
                 let default_path = DEFAULT_PRODKEYS_PATH.as_path();
                 fs::create_dir_all(
@@ -373,59 +618,60 @@ fn run() -> Result<()> {
                 fs::copy(keyfile_path, default_path)?;
                 info!("Copied keys successfully to the C2 ^-^");
             }
+            let keyset = Keyset::from_default_path()?;
 
-            let roms_path = WalkDir::new(yanu_dir)
-                .min_depth(1)
-                .into_iter()
-                .filter_map(|e| e.ok())
-                .filter(|entry| entry.file_type().is_file() && ext_matches(entry.path(), "nsp"))
-                .collect::<Vec<_>>();
-
-            let options = roms_path
-                .iter()
-                .map(|entry| {
-                    entry.file_name().to_str().unwrap_or_else(|| {
-                        panic!("'{}' should've valid Unicode", entry.path().display())
-                    })
-                })
-                .collect::<Vec<_>>();
-            if options.is_empty() {
-                bail!("No NSPs found in '{}'", yanu_dir.display());
-            }
-            let choice = inquire::Select::new("Select BASE package:", options.clone()).prompt()?;
-            let mut base = roms_path
-                .iter()
-                .find(|entry| entry.file_name() == choice)
-                .map(|entry| Nsp::try_new(entry.path()))
-                .transpose()?
-                .unwrap_or_else(|| {
-                    panic!(
-                        "Selected package '{}' should be in {:#?}",
-                        choice, roms_path
-                    )
-                });
-
-            let options = options
-                .into_iter()
-                .filter(|filename| filename != &choice)
-                .collect::<Vec<_>>();
-            if options.is_empty() {
-                bail!("No other NSPs found in '{}'", yanu_dir.display());
-            }
-            let choice = inquire::Select::new("Select UPDATE package:", options).prompt()?;
-            let mut update = roms_path
-                .iter()
-                .find(|entry| entry.file_name() == choice)
-                .map(|entry| Nsp::try_new(entry.path()))
-                .transpose()?
-                .unwrap_or_else(|| {
-                    panic!(
-                        "Selected package '{}' should be in {:#?}",
-                        choice, roms_path
-                    )
-                });
+            let groups = updatable_applications(yanu_dir, &keyset, &config)?;
+            let group = if groups.is_empty() {
+                eprintln!(
+                    "{} '{}'",
+                    style("Couldn't pair any base with an update in")
+                        .yellow()
+                        .bold(),
+                    yanu_dir.display()
+                );
+                None
+            } else {
+                let mut options = groups.iter().map(ToString::to_string).collect::<Vec<_>>();
+                options.push("Pick packages manually".into());
+                let choice =
+                    inquire::Select::new("Select game to update:", options).raw_prompt()?;
+                groups.get(choice.index)
+            };
+
+            let (mut base, mut update, mut dlcs) = match group {
+                Some(group) => (
+                    Nsp::try_new(&group.base.path)?,
+                    Nsp::try_new(
+                        &group
+                            .newest_update()
+                            .expect("Only groups with an update are listed")
+                            .path,
+                    )?,
+                    select_dlcs(group)?,
+                ),
+                None => {
+                    let (base, update, dlcs) = select_packages(yanu_dir)?;
+                    if let Err(err) = newest_update(&base, vec![update.clone()], &config) {
+                        eprintln!("{} {}", style("Mismatched packages:").red().bold(), err);
+                        if !inquire::Confirm::new("Apply the update anyway?")
+                            .with_default(false)
+                            .prompt()?
+                        {
+                            return Ok(());
+                        }
+                    }
+                    (base, update, dlcs)
+                }
+            };
 
-            if !check_space_with_prompt!(2, &[&base.path, &update.path], &config.temp_dir) {
+            if !check_space_with_prompt!(
+                2,
+                &[&base.path, &update.path]
+                    .into_iter()
+                    .chain(dlcs.iter().map(|dlc| &dlc.path))
+                    .collect::<Vec<_>>(),
+                &config.temp_dir
+            ) {
                 return Ok(());
             }
 
@@ -436,8 +682,15 @@ fn run() -> Result<()> {
                 info!("Started patching!");
                 timer = Some(Instant::now());
                 // TODO?: Maybe ask for ProgramID here?
-                let (mut patched, nacp_data, program_id) =
-                    update_nsp(&mut base, &mut update, None, default_outdir()?, &config)?;
+                let (mut patched, nacp_data, program_id) = update_nsp(
+                    &mut base,
+                    &mut update,
+                    &mut dlcs,
+                    None,
+                    default_outdir()?,
+                    None,
+                    &config,
+                )?;
                 formatted_nsp_rename(
                     &mut patched.path,
                     &nacp_data,
@@ -446,7 +699,7 @@ fn run() -> Result<()> {
                 )?;
                 eprintln!(
                     "{} '{}'",
-                    style("Patched NSP created at").green().bold(),
+                    style("Patched package created at").green().bold(),
                     patched.path.display()
                 );
             }
@@ -459,17 +712,9 @@ fn run() -> Result<()> {
             let mut res_pool = vec![];
             if build {
                 res_pool.push(Backend::build(BackendKind::Hacpack));
-                res_pool.push(Backend::build(BackendKind::Hactool));
-                res_pool.push(Backend::build(BackendKind::Hac2l));
-                res_pool.push(Backend::build(BackendKind::FourNXCI));
             } else {
                 res_pool.push(Backend::try_new(BackendKind::Hacpack));
-                res_pool.push(Backend::try_new(BackendKind::Hactool));
-                res_pool.push(Backend::try_new(BackendKind::Hac2l));
-                res_pool.push(Backend::try_new(BackendKind::FourNXCI));
             }
-            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
-            res_pool.push(Backend::try_new(BackendKind::Hactoolnet));
             let res_pool: Vec<_> = res_pool.into_iter().filter_map(|res| res.err()).collect();
             if res_pool.is_empty() {
                 eprintln!(
@@ -498,6 +743,360 @@ fn run() -> Result<()> {
     Ok(())
 }
 
+/// Packages at `paths`, the NSPs and NSZs directly within a directory are included.
+///
+/// `exclude` is left out when found in a directory.
+fn collect_packages(paths: &[PathBuf], exclude: &Path) -> Result<Vec<Nsp>> {
+    use walkdir::WalkDir;
+
+    let exclude = exclude.canonicalize()?;
+    let mut packages = vec![];
+    for path in paths {
+        if !path.is_dir() {
+            packages.push(Nsp::try_new(path)?);
+            continue;
+        }
+        for entry in WalkDir::new(path)
+            .min_depth(1)
+            .max_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|entry| {
+                entry.file_type().is_file()
+                    && (ext_matches(entry.path(), "nsp") || ext_matches(entry.path(), "nsz"))
+            })
+        {
+            if entry.path().canonicalize()? == exclude {
+                continue;
+            }
+            packages.push(Nsp::try_new(entry.path())?);
+        }
+    }
+    debug!(?packages);
+    Ok(packages)
+}
+
+/// Lets the user pick the BASE, UPDATE and DLC packages out of the ones in `yanu_dir`.
+fn select_packages(yanu_dir: &Path) -> Result<(Nsp, Nsp, Vec<Nsp>)> {
+    use walkdir::WalkDir;
+
+    let roms_path = WalkDir::new(yanu_dir)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|entry| {
+            entry.file_type().is_file()
+                && (ext_matches(entry.path(), "nsp") || ext_matches(entry.path(), "nsz"))
+        })
+        .collect::<Vec<_>>();
+
+    let options = roms_path
+        .iter()
+        .map(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .unwrap_or_else(|| panic!("'{}' should've valid Unicode", entry.path().display()))
+        })
+        .collect::<Vec<_>>();
+    if options.is_empty() {
+        bail!("No NSPs found in '{}'", yanu_dir.display());
+    }
+    let choice = inquire::Select::new("Select BASE package:", options.clone()).prompt()?;
+    let base = roms_path
+        .iter()
+        .find(|entry| entry.file_name() == choice)
+        .map(|entry| Nsp::try_new(entry.path()))
+        .transpose()?
+        .unwrap_or_else(|| {
+            panic!(
+                "Selected package '{}' should be in {:#?}",
+                choice, roms_path
+            )
+        });
+
+    let options = options
+        .into_iter()
+        .filter(|filename| filename != &choice)
+        .collect::<Vec<_>>();
+    if options.is_empty() {
+        bail!("No other NSPs found in '{}'", yanu_dir.display());
+    }
+    let choice = inquire::Select::new("Select UPDATE package:", options.clone()).prompt()?;
+    let update = roms_path
+        .iter()
+        .find(|entry| entry.file_name() == choice)
+        .map(|entry| Nsp::try_new(entry.path()))
+        .transpose()?
+        .unwrap_or_else(|| {
+            panic!(
+                "Selected package '{}' should be in {:#?}",
+                choice, roms_path
+            )
+        });
+
+    let options = options
+        .into_iter()
+        .filter(|filename| filename != &choice)
+        .collect::<Vec<_>>();
+    let dlcs = if options.is_empty() {
+        vec![]
+    } else {
+        inquire::MultiSelect::new("Select DLC packages (optional):", options)
+            .prompt()?
+            .into_iter()
+            .map(|choice| {
+                roms_path
+                    .iter()
+                    .find(|entry| entry.file_name() == choice)
+                    .map(|entry| Nsp::try_new(entry.path()))
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "Selected package '{}' should be in {:#?}",
+                            choice, roms_path
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    Ok((base, update, dlcs))
+}
+
+/// Packages in `dir` grouped by application, only the ones with an update.
+fn updatable_applications(
+    dir: &Path,
+    keyset: &Keyset,
+    config: &Config,
+) -> Result<Vec<ApplicationGroup>> {
+    Ok(scan_applications(dir, keyset, config)?
+        .into_iter()
+        .filter(|group| group.newest_update().is_some())
+        .collect())
+}
+
+/// Packages in `dir` grouped by application.
+///
+/// The library index is rescanned and stored if it's of `dir`, otherwise `dir` is scanned
+/// without touching it.
+fn scan_applications(
+    dir: &Path,
+    keyset: &Keyset,
+    config: &Config,
+) -> Result<Vec<ApplicationGroup>> {
+    let mut library = Library::load(LIBRARY_INDEX_PATH.as_path())?;
+    let is_indexed = library.dir.as_deref() == Some(dir.canonicalize()?.as_path());
+    if !is_indexed {
+        library = Library::default();
+    }
+    let summary = library.scan(dir, keyset, &config.temp_dir)?;
+    if is_indexed {
+        library.store(LIBRARY_INDEX_PATH.as_path())?;
+    }
+    for (path, err) in &summary.failed {
+        eprintln!(
+            "{} '{}' {}",
+            style("Skipped").yellow().bold(),
+            path.display(),
+            style(format!("({})", err)).bold().dim()
+        );
+    }
+
+    Ok(group_by_application(&library.entries))
+}
+
+/// Lets the user pick which DLCs of `group` to merge, if it has any.
+fn select_dlcs(group: &ApplicationGroup) -> Result<Vec<Nsp>> {
+    if group.dlcs.is_empty() {
+        return Ok(vec![]);
+    }
+    let options = group
+        .dlcs
+        .iter()
+        .map(|dlc| {
+            dlc.path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| dlc.title_id.clone())
+        })
+        .collect::<Vec<_>>();
+    inquire::MultiSelect::new("Select DLC packages (optional):", options)
+        .raw_prompt()?
+        .into_iter()
+        .map(|choice| Nsp::try_new(&group.dlcs[choice.index].path))
+        .collect()
+}
+
+/// Result of a single update of `update --batch`.
+enum JobOutcome {
+    Succeeded(PathBuf),
+    /// The update wasn't attempted.
+    Skipped(String),
+    Failed(String),
+}
+
+/// Runs the updates of a directory or job file one by one, a failed one doesn't stop the rest.
+fn run_batch(path: &Path, opts: &opts::Update, keyset: &Keyset, config: &Config) -> Result<()> {
+    let mut outcomes = vec![];
+    let mut jobs = vec![];
+    if path.is_dir() {
+        for group in scan_applications(path, keyset, config)? {
+            match group.newest_update() {
+                Some(update) => jobs.push((
+                    group.to_string(),
+                    BatchJob {
+                        base: group.base.path.clone(),
+                        update: update.path.clone(),
+                        dlc: vec![],
+                        outdir: None,
+                        titleid: None,
+                    },
+                )),
+                None => outcomes.push((
+                    group.to_string(),
+                    JobOutcome::Skipped("No update found for it".into()),
+                )),
+            }
+        }
+    } else {
+        for job in read_jobs(path)? {
+            let label = job
+                .base
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| job.base.display().to_string());
+            jobs.push((label, job));
+        }
+    }
+    if jobs.is_empty() {
+        bail!("No updates to apply in '{}'", path.display());
+    }
+
+    let count = jobs.len();
+    for (i, (label, job)) in jobs.into_iter().enumerate() {
+        eprintln!(
+            "{} {}",
+            style(format!("[{}/{}] Updating", i + 1, count))
+                .cyan()
+                .bold(),
+            label
+        );
+        let outcome = run_job(&job, opts, config);
+        match &outcome {
+            JobOutcome::Succeeded(patched) => {
+                info!(%label, patched = %patched.display(), "Job succeeded")
+            }
+            JobOutcome::Skipped(reason) => warn!(%label, %reason, "Job skipped"),
+            JobOutcome::Failed(reason) => error!(%label, %reason, "Job failed"),
+        }
+        outcomes.push((label, outcome));
+    }
+
+    let (mut succeeded, mut skipped, mut failed) = (0, 0, 0);
+    eprintln!("\n{}", style("Summary:").bold().underlined());
+    for (label, outcome) in &outcomes {
+        match outcome {
+            JobOutcome::Succeeded(patched) => {
+                succeeded += 1;
+                eprintln!(
+                    "{} {} -> '{}'",
+                    style("Succeeded").green().bold(),
+                    label,
+                    patched.display()
+                );
+            }
+            JobOutcome::Skipped(reason) => {
+                skipped += 1;
+                eprintln!(
+                    "{} {} {}",
+                    style("Skipped").yellow().bold(),
+                    label,
+                    style(format!("({})", reason)).bold().dim()
+                );
+            }
+            JobOutcome::Failed(reason) => {
+                failed += 1;
+                eprintln!("{} {}: {}", style("Failed").red().bold(), label, reason);
+            }
+        }
+    }
+    eprintln!(
+        "{} succeeded, {} skipped, {} failed",
+        succeeded, skipped, failed
+    );
+
+    if failed != 0 {
+        bail!("{} of {} updates failed", failed, outcomes.len());
+    }
+    Ok(())
+}
+
+fn run_job(job: &BatchJob, opts: &opts::Update, config: &Config) -> JobOutcome {
+    for path in [&job.base, &job.update].into_iter().chain(&job.dlc) {
+        if !path.is_file() {
+            return JobOutcome::Skipped(format!("'{}' doesn't exist", path.display()));
+        }
+    }
+    if let Some(Err(err)) = job.titleid.as_deref().map(validate_program_id) {
+        return JobOutcome::Skipped(err.to_string());
+    }
+    let (base, update) = match (Nsp::try_new(&job.base), Nsp::try_new(&job.update)) {
+        (Ok(base), Ok(update)) => (base, update),
+        (Err(err), _) | (_, Err(err)) => return JobOutcome::Skipped(err.to_string()),
+    };
+    if !opts.force {
+        if let Err(err) = newest_update(&base, vec![update.clone()], config) {
+            return JobOutcome::Skipped(err.to_string());
+        }
+    }
+
+    match patch_job(job, base, update, opts, config) {
+        Ok(patched) => JobOutcome::Succeeded(patched),
+        Err(err) => JobOutcome::Failed(err.to_string()),
+    }
+}
+
+fn patch_job(
+    job: &BatchJob,
+    mut base: Nsp,
+    mut update: Nsp,
+    opts: &opts::Update,
+    config: &Config,
+) -> Result<PathBuf> {
+    let outdir = match job.outdir.as_ref().or(opts.outdir.as_ref()) {
+        Some(outdir) => {
+            fs::create_dir_all(outdir)?;
+            outdir.to_owned()
+        }
+        None => default_outdir()?,
+    };
+    let mut dlcs = job
+        .dlc
+        .iter()
+        .map(Nsp::try_new)
+        .collect::<Result<Vec<_>>>()?;
+
+    let (mut patched, nacp_data, program_id) = update_nsp(
+        &mut base,
+        &mut update,
+        &mut dlcs,
+        job.titleid.as_deref(),
+        outdir,
+        opts.nsz
+            .then(|| NczOptions::from(&opts.compression))
+            .as_ref(),
+        config,
+    )?;
+    formatted_nsp_rename(
+        &mut patched.path,
+        &nacp_data,
+        &program_id,
+        concat!("[yanu-", env!("CARGO_PKG_VERSION"), "-patched]"),
+    )?;
+    Ok(patched.path)
+}
+
 fn default_outdir() -> Result<PathBuf> {
     let outdir: PathBuf = {
         if cfg!(feature = "android-proot") {
@@ -514,3 +1113,91 @@ fn default_outdir() -> Result<PathBuf> {
 
     Ok(outdir)
 }
+
+fn print_library_table(entries: &[LibraryEntry]) {
+    let rows = entries
+        .iter()
+        .map(|entry| {
+            [
+                format!("{:?}", entry.meta_type),
+                entry.title_id.clone(),
+                format!("v{}", entry.version),
+                bytesize::ByteSize(entry.size).to_string(),
+                entry.name.clone().unwrap_or_default(),
+                entry.path.display().to_string(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print_table(
+        ["Type", "TitleID", "Version", "Size", "Name", "Path"],
+        &rows,
+    );
+}
+
+fn print_package_info(info: &PackageInfo) {
+    let field = |name: &str, value: &dyn std::fmt::Display| {
+        println!("{} {}", style(format!("{:10}", name)).bold(), value)
+    };
+    field("Package", &info.path.display());
+    field("Type", &info.kind);
+    field("Size", &bytesize::ByteSize(info.size));
+    if let Some(nacp) = &info.nacp {
+        field("Name", &nacp.name);
+        field("Publisher", &nacp.publisher);
+        field("Version", &nacp.display_version);
+    }
+    for meta in &info.metas {
+        field(
+            "Meta",
+            &format!("{:?} {} v{}", meta.meta_type, meta.title_id, meta.version),
+        );
+    }
+    for ticket in &info.tickets {
+        field(
+            "Ticket",
+            &format!(
+                "{} ({:?}, key generation {})",
+                ticket.rights_id, ticket.title_key_type, ticket.key_generation
+            ),
+        );
+    }
+
+    println!();
+    let rows = info
+        .contents
+        .iter()
+        .map(|content| {
+            [
+                content.content_type.to_string(),
+                content.title_id.clone(),
+                content.key_generation.to_string(),
+                bytesize::ByteSize(content.size).to_string(),
+                content.name.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print_table(["Content", "TitleID", "KeyGen", "Size", "Name"], &rows);
+}
+
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(|title| title.chars().count());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let format_row = |row: &[String]| {
+        row.iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_owned()
+    };
+    println!("{}", style(format_row(&header.map(String::from))).bold());
+    for row in rows {
+        println!("{}", format_row(row));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::utils::get_section;
 use clap::{Args, Parser, Subcommand};
+use hac::vfs::ncz::NczOptions;
 use std::path::PathBuf;
 
 const SECTION_PADDING: &str = "  ";
@@ -32,6 +33,15 @@ pub enum Commands {
     /// Convert Switch file formats
     #[command()]
     Convert(Convert),
+    /// Verify the contents of packages against their CNMTs
+    #[command()]
+    Verify(Verify),
+    /// Describe the contents of a package
+    #[command()]
+    Info(Info),
+    /// Index the packages in the Yanu Directory
+    #[command()]
+    Library(Library),
     /// Manage yanu's config
     #[command(visible_alias = "cfg")]
     Config(Config),
@@ -52,13 +62,66 @@ pub enum Commands {
 // value_parser=clap::value_parser!(PathBuf)
 
 #[derive(Debug, Args, Default, PartialEq, Eq)]
+#[command(after_help = get_section("Examples", r#"For picking a game out of a directory, its packages get paired by TitleID:
+$ yanu-cli update --auto /path/to/dir
+For updating every game in a directory:
+$ yanu-cli update --batch /path/to/dir
+For updating the games listed in a TOML (or JSON) job file:
+$ yanu-cli update --batch jobs.toml
+With 'jobs.toml' being like:
+    [[jobs]]
+    base = "base.nsp"
+    update = "update.nsp"
+    # Optional
+    dlc = ["dlc.nsp"]
+    outdir = "patched"
+    titleid = "xxxxxxxxxxxxxxxx"
+"#, SECTION_PADDING))]
 pub struct Update {
     /// Select base package
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        required_unless_present_any = ["auto", "batch"]
+    )]
+    pub base: Option<PathBuf>,
+    /// Select update packages, or directories of them
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        required_unless_present_any = ["auto", "batch"],
+        long_help = "Select update packages, or directories of them\n\
+        Can be used multiple times, only the newest update of the base gets applied."
+    )]
+    pub update: Vec<PathBuf>,
+    /// Pick a game out of the packages in a directory instead
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["base", "update"],
+        long_help = "Pick a game out of the packages in a directory instead\n\
+        Bases are paired with their newest update and DLCs by TitleID."
+    )]
+    pub auto: Option<PathBuf>,
+    /// Update every game in a directory, or the ones listed in a job file
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["base", "update", "auto", "dlc", "titleid"],
+        long_help = "Update every game in a directory, or the ones listed in a job file\n\
+        Games in a directory get their bases paired with the newest update by TitleID.\n\
+        Job files are TOML or JSON, see the examples. A failed update doesn't stop the others.\n\
+        DLCs and TitleIDs can only be set per job in a job file."
+    )]
+    pub batch: Option<PathBuf>,
+    /// Apply the update even if it isn't one of the base
+    #[arg(long, action)]
+    pub force: bool,
+    /// Select DLC packages to merge, can be used multiple times
     #[arg(short, long, value_name = "FILE")]
-    pub base: PathBuf,
-    /// Select update package
-    #[arg(short, long, value_name = "FILE")]
-    pub update: PathBuf,
+    pub dlc: Vec<PathBuf>,
     /// Overwrite TitleID
     #[arg(
         short,
@@ -69,6 +132,14 @@ pub struct Update {
     pub titleid: Option<String>,
     #[arg(short, long, value_name = "DIR")]
     pub outdir: Option<PathBuf>,
+    /// Skip checking the packages against their CNMTs beforehand
+    #[arg(long, action)]
+    pub no_verify: bool,
+    /// Output a NSZ instead of a NSP
+    #[arg(long, action)]
+    pub nsz: bool,
+    #[command(flatten)]
+    pub compression: Compression,
 }
 
 #[derive(Debug, Args, Default, PartialEq, Eq)]
@@ -100,6 +171,11 @@ pub struct Pack {
     pub exefsdir: PathBuf,
     #[arg(short, long, value_name = "DIR")]
     pub outdir: Option<PathBuf>,
+    /// Output a NSZ instead of a NSP
+    #[arg(long, action)]
+    pub nsz: bool,
+    #[command(flatten)]
+    pub compression: Compression,
 }
 
 #[derive(Debug, Args, Default, PartialEq, Eq)]
@@ -107,6 +183,8 @@ pub struct Pack {
 $ yanu-cli unpack --base './path/to/base
 For unpacking both base and update NSPs together (i.e. updating):
 $ yanu-cli unpack --base '/path/to/base' --update '/path/to/update'
+For unpacking another program of a multi-program application:
+$ yanu-cli unpack --base '/path/to/base' --update '/path/to/update' --id-offset 1
 "#, SECTION_PADDING))]
 pub struct Unpack {
     /// Select base package
@@ -115,18 +193,35 @@ pub struct Unpack {
     /// Select update package
     #[arg(short, long, value_name = "FILE")]
     pub update: Option<PathBuf>,
+    /// Select the program of a multi-program application to unpack
+    #[arg(
+        long,
+        value_name = "OFFSET",
+        default_value = "0",
+        long_help = "Select the program of a multi-program application to unpack\n\
+        0 is the main program, the available ones are listed in the logs."
+    )]
+    pub id_offset: u8,
     #[arg(short, long, value_name = "DIR")]
     pub outdir: Option<PathBuf>,
+    /// Skip checking the packages against their CNMTs beforehand
+    #[arg(long, action)]
+    pub no_verify: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
 pub enum ConvertKind {
     Nsp,
+    Nsz,
 }
 
 #[derive(Debug, Args, PartialEq, Eq)]
 #[command(after_help = get_section("Examples", r#"For converting XCI to NSP:
 $ yanu-cli convert --kind nsp gta6.xci
+For decompressing NSZ to NSP:
+$ yanu-cli convert --kind nsp gta6.nsz
+For compressing NSP to NSZ:
+$ yanu-cli convert --kind nsz --level 22 gta6.nsp
 "#, SECTION_PADDING))]
 pub struct Convert {
     /// File format to convert to
@@ -136,7 +231,8 @@ pub struct Convert {
         value_enum,
         long_help = r#"File format to convert to
 Possible coversions:
-    To nsp: xci"#
+    To nsp: xci, xcz, nsz
+    To nsz: nsp"#
     )]
     pub kind: ConvertKind,
     /// Input file
@@ -145,22 +241,138 @@ Possible coversions:
     /// By default it'll be 'pwd'
     #[arg(short, long)]
     pub outdir: Option<PathBuf>,
+    /// zstd compression level used for NSZ, 18 by default
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        value_parser = clap::value_parser!(i32).range(1..=22)
+    )]
+    pub level: Option<i32>,
+    /// Threads used for compressing NSZ, all of them by default
+    #[arg(long, value_name = "COUNT")]
+    pub threads: Option<u32>,
+}
+
+impl Convert {
+    /// Set if '--level' or '--threads' were given, these only apply to '--kind nsz'.
+    pub fn has_compression(&self) -> bool {
+        self.level.is_some() || self.threads.is_some()
+    }
+
+    pub fn compression(&self) -> Compression {
+        Compression {
+            level: self.level.unwrap_or(NczOptions::DEFAULT_LEVEL),
+            threads: self.threads,
+        }
+    }
+}
+
+/// NSZ compression settings, only used along with '--nsz'
+#[derive(Debug, Args, PartialEq, Eq)]
+pub struct Compression {
+    /// zstd compression level used for NSZ
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        default_value = "18",
+        requires = "nsz",
+        value_parser = clap::value_parser!(i32).range(1..=22)
+    )]
+    pub level: i32,
+    /// Threads used for compressing NSZ, all of them by default
+    #[arg(long, value_name = "COUNT", requires = "nsz")]
+    pub threads: Option<u32>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            level: NczOptions::DEFAULT_LEVEL,
+            threads: None,
+        }
+    }
+}
+
+impl From<&Compression> for NczOptions {
+    fn from(value: &Compression) -> Self {
+        let mut options = NczOptions {
+            level: value.level,
+            ..Default::default()
+        };
+        if let Some(threads) = value.threads {
+            options.threads = threads;
+        }
+        options
+    }
+}
+
+#[derive(Debug, Args, Default, PartialEq, Eq)]
+#[command(after_help = get_section("Examples", r#"For verifying all the packages in a directory:
+$ yanu-cli verify /path/to/dir/*.nsp
+
+For also checking the NCA signatures and section hashes:
+$ yanu-cli verify --deep /path/to/base.nsp
+"#, SECTION_PADDING))]
+pub struct Verify {
+    /// Packages to verify
+    #[arg(required = true, value_name = "FILE")]
+    pub files: Vec<PathBuf>,
+    /// Also verify the header signature and section hashes of every NCA
+    #[arg(
+        long,
+        action,
+        long_help = "Also verify the header signature and section hashes of every NCA\n\
+        Tells a bad dump apart from wrong keys, the signature needs \
+        'nca_hdr_fixed_key_modulus_XX' keys in the keyfile"
+    )]
+    pub deep: bool,
 }
 
-#[cfg(not(feature = "android-proot"))]
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
-pub enum NspExtractor {
-    #[default]
-    Hactoolnet,
-    Hactool,
+#[derive(Debug, Args, Default, PartialEq, Eq)]
+#[command(after_help = get_section("Examples", r#"For describing a package:
+$ yanu-cli info /path/to/base.nsp
+For printing it as JSON:
+$ yanu-cli info --json /path/to/game.xci
+"#, SECTION_PADDING))]
+pub struct Info {
+    /// Package to describe, can be a NSP/NSZ/XCI/XCZ
+    #[arg(value_name = "FILE")]
+    pub file: PathBuf,
+    /// Print as JSON instead of text
+    #[arg(long, action)]
+    pub json: bool,
 }
 
-#[cfg(not(feature = "android-proot"))]
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
-pub enum NcaExtractor {
-    #[default]
-    Hactoolnet,
-    Hac2l,
+#[derive(Debug, Args, PartialEq, Eq)]
+#[command(after_help = get_section("Examples", r#"For indexing the Yanu Directory, rescans only read new or changed packages:
+$ yanu-cli library scan
+For printing the index as JSON:
+$ yanu-cli library list --json
+"#, SECTION_PADDING))]
+pub struct Library {
+    #[command(subcommand)]
+    pub command: LibraryCommand,
+}
+
+#[derive(Debug, Subcommand, PartialEq, Eq)]
+pub enum LibraryCommand {
+    /// Identify the packages in a directory and store them in the index
+    #[command(
+        long_about = "Identify the packages in a directory and store them in the index\n\
+        NSP/NSZ/XCI/XCZ packages are indexed, XCI/XCZs aren't paired with updates \
+        unless converted to NSPs first"
+    )]
+    Scan {
+        /// Directory to scan, the Yanu Directory by default
+        #[arg(value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+    /// Print the indexed packages
+    List {
+        /// Print as JSON instead of a table
+        #[arg(long, action)]
+        json: bool,
+    },
 }
 
 #[derive(Debug, Args, Default, PartialEq, Eq)]
@@ -177,10 +389,4 @@ pub struct Config {
         PATH must not contain Unicode characters due to the limitations of backend tools"
     )]
     pub temp_dir: Option<PathBuf>,
-    #[cfg(not(feature = "android-proot"))]
-    #[arg(long, value_enum)]
-    pub nsp_extractor: Option<NspExtractor>,
-    #[cfg(not(feature = "android-proot"))]
-    #[arg(long, value_enum)]
-    pub nca_extractor: Option<NcaExtractor>,
 }
@@ -1,26 +1,41 @@
-use std::{path::PathBuf, sync::mpsc::TryRecvError, thread, time::Instant};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::mpsc::TryRecvError,
+    thread,
+    time::Instant,
+};
 
 use common::{
     defines::{APP_CACHE_DIR, APP_CONFIG_DIR, DEFAULT_PRODKEYS_PATH, SWITCH_DIR},
     format::HumanDuration,
     utils::get_fmt_size,
 };
-use config::{Config, NcaExtractor, NspExtractor};
+use config::Config;
 use eframe::egui;
 use egui::RichText;
+use egui_extras::RetainedImage;
 use egui_modal::Modal;
 use eyre::{bail, Result};
 use fs_err as fs;
 use hac::{
+    keys::Keyset,
     utils::{formatted_nsp_rename, pack::pack_fs_data, unpack::unpack_nsp, update::update_nsp},
-    vfs::{nsp::Nsp, validate_program_id, xci::xci_to_nsps},
+    vfs::{
+        nacp::Icon,
+        ncz::NczOptions,
+        nsp::{nsp_to_nsz, nsz_to_nsp, Nsp},
+        validate_program_id,
+        xci::xci_to_nsps,
+    },
 };
-use tracing::info;
+use tracing::{info, warn};
 
 use super::{cross_centered, increase_font_size_by};
 use crate::{
     utils::{
-        check_keyfile_exists, consume_err, consume_err_or, default_pack_outdir, pick_nsp_file,
+        consume_err, consume_err_or, default_pack_outdir, icon_image, is_package, pick_nsp_file,
+        pick_nsp_files, read_package_icons, validate_keyfile,
     },
     MpscChannel,
 };
@@ -35,10 +50,15 @@ pub struct YanuApp {
     // Update Page
     overwrite_titleid: bool,
     overwrite_titleid_buf: String,
+    dlc_pkg_paths: Vec<PathBuf>,
 
     // Update/Unpack Page
     base_pkg_path_buf: String,
     update_pkg_path_buf: String,
+    base_icon: PackageIcon,
+    update_icon: PackageIcon,
+    update_summary: Option<OutputSummary>,
+    unpack_summary: Option<OutputSummary>,
 
     // Pack Page
     control_nca_path_buf: String,
@@ -65,12 +85,14 @@ enum Page {
 enum ConvertKind {
     #[default]
     Nsp,
+    Nsz,
 }
 
 impl ConvertKind {
     fn reach_from_types(&self) -> &[&'static str] {
         match self {
-            ConvertKind::Nsp => &["xci"],
+            ConvertKind::Nsp => &["xci", "xcz", "nsz"],
+            ConvertKind::Nsz => &["nsp"],
         }
     }
 }
@@ -78,12 +100,100 @@ impl ConvertKind {
 #[derive(Debug)]
 enum Converted {
     Nsp(Vec<Nsp>),
+    Nsz(Nsp),
+}
+
+/// Icon of a picked package, it's read on a worker thread whenever the path changes.
+#[derive(Default)]
+struct PackageIcon {
+    path: String,
+    image: Option<RetainedImage>,
+    channel: MpscChannel<IconMessage>,
+}
+
+/// Icons read from the package at `path`.
+struct IconMessage {
+    path: String,
+    icons: Result<Vec<Icon>>,
+}
+
+impl fmt::Debug for PackageIcon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PackageIcon")
+            .field("path", &self.path)
+            .field("image", &self.image.is_some())
+            .finish()
+    }
+}
+
+impl PackageIcon {
+    fn show(&mut self, ui: &mut egui::Ui, path: &str, tempdir_in: &Path) {
+        // Icons of the paths that were changed since are dropped
+        while let Ok(message) = self.channel.rx.try_recv() {
+            if message.path == self.path {
+                self.image = match message.icons {
+                    Ok(icons) => icon_image(&icons),
+                    Err(err) => {
+                        warn!(%err, "Failed to read icon");
+                        None
+                    }
+                };
+            }
+        }
+        if self.path != path {
+            self.path = path.into();
+            self.image = None;
+            if is_package(Path::new(path)) {
+                let path = self.path.clone();
+                let tempdir_in = tempdir_in.to_owned();
+                let tx = self.channel.tx.clone();
+                let ctx = ui.ctx().clone();
+                thread::spawn(move || {
+                    let icons = read_package_icons(Path::new(&path), &tempdir_in);
+                    if tx.send(IconMessage { path, icons }).is_ok() {
+                        ctx.request_repaint();
+                    }
+                });
+            }
+        }
+        if let Some(image) = &self.image {
+            image.show_max_size(ui, egui::vec2(ICON_SIZE, ICON_SIZE));
+        }
+    }
+}
+
+/// Shown below the page after it's done.
+struct OutputSummary {
+    icon: Option<RetainedImage>,
+    text: String,
+}
+
+impl fmt::Debug for OutputSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutputSummary")
+            .field("icon", &self.icon.is_some())
+            .field("text", &self.text)
+            .finish()
+    }
+}
+
+impl OutputSummary {
+    fn show(&self, ui: &mut egui::Ui) {
+        ui.group(|ui| {
+            ui.with_layout(egui::Layout::left_to_right(egui::Align::Center), |ui| {
+                if let Some(icon) = &self.icon {
+                    icon.show_max_size(ui, egui::vec2(ICON_SIZE * 0.5, ICON_SIZE * 0.5));
+                }
+                ui.label(&self.text);
+            });
+        });
+    }
 }
 
 #[derive(Debug)]
 enum Message {
-    Update(Result<Nsp>),
-    Unpack(Result<PathBuf>),
+    Update(Result<(Nsp, Vec<Icon>)>),
+    Unpack(Result<(PathBuf, Vec<Icon>)>),
     Pack(Result<Nsp>),
     Convert(Result<Converted>),
 }
@@ -114,6 +224,7 @@ impl YanuApp {
 const HEADING_SIZE: f32 = 21.6; // 1.2x of default
 const BODY_SIZE: f32 = 12.5; // 1.2x of default
 const PADDING: f32 = BODY_SIZE * 0.5; // 0.5x of default Body size
+const ICON_SIZE: f32 = 96.;
 
 impl eframe::App for YanuApp {
     /// Called by the frame work to save state before shutdown.
@@ -178,6 +289,7 @@ impl eframe::App for YanuApp {
                                     });
                                 };
                             });
+                            self.base_icon.show(ui, &self.base_pkg_path_buf, &self.config.temp_dir);
 
                             ui.add_space(PADDING);
 
@@ -190,6 +302,27 @@ impl eframe::App for YanuApp {
                                     });
                                 };
                             });
+                            self.update_icon.show(ui, &self.update_pkg_path_buf, &self.config.temp_dir);
+
+                            ui.add_space(PADDING);
+
+                            ui.label("DLC files (optional):");
+                            for path in &self.dlc_pkg_paths {
+                                ui.label(format!(
+                                    "- {}",
+                                    path.file_name().unwrap_or_default().to_string_lossy()
+                                ));
+                            }
+                            ui.with_layout(egui::Layout::left_to_right(egui::Align::TOP), |ui| {
+                                if ui.button("➕ Add").clicked() {
+                                    pick_nsp_files(&dialog_modal, Some("Pick DLC files"), |paths| {
+                                        self.dlc_pkg_paths.extend(paths);
+                                    });
+                                };
+                                if ui.button("🗑 Clear").clicked() {
+                                    self.dlc_pkg_paths.clear();
+                                };
+                            });
 
                             ui.add_space(PADDING);
 
@@ -217,6 +350,11 @@ impl eframe::App for YanuApp {
                             self.do_update(&dialog_modal);
                         };
                     });
+
+                    if let Some(summary) = &self.update_summary {
+                        ui.add_space(PADDING);
+                        summary.show(ui);
+                    }
                 });
             }
             Page::Unpack => {
@@ -232,6 +370,7 @@ impl eframe::App for YanuApp {
                                     });
                                 };
                             });
+                            self.base_icon.show(ui, &self.base_pkg_path_buf, &self.config.temp_dir);
 
                             ui.add_space(PADDING);
 
@@ -244,6 +383,7 @@ impl eframe::App for YanuApp {
                                     });
                                 };
                             });
+                            self.update_icon.show(ui, &self.update_pkg_path_buf, &self.config.temp_dir);
                         });
                     });
 
@@ -257,6 +397,11 @@ impl eframe::App for YanuApp {
                             self.do_unpack(&dialog_modal);
                         };
                     });
+
+                    if let Some(summary) = &self.unpack_summary {
+                        ui.add_space(PADDING);
+                        summary.show(ui);
+                    }
                 });
             }
             Page::Pack => {
@@ -386,6 +531,11 @@ impl eframe::App for YanuApp {
                                             ConvertKind::Nsp,
                                             "Nsp",
                                         );
+                                        ui.selectable_value(
+                                            &mut self.convert_kind,
+                                            ConvertKind::Nsz,
+                                            "Nsz",
+                                        );
                                     });
                             });
                         });
@@ -422,7 +572,14 @@ impl eframe::App for YanuApp {
                                     consume_err(
                                         &dialog_modal,
                                         response,
-                                        |patched| {
+                                        |(patched, icons)| {
+                                            self.update_summary = Some(OutputSummary {
+                                                icon: icon_image(&icons),
+                                                text: format!(
+                                                    "Patched:\n'{}'",
+                                                    patched.path.file_name().unwrap_or_default().to_string_lossy()
+                                                ),
+                                            });
                                             dialog_modal.open_dialog(
                                                 None::<&str>,
                                                 Some(format!(
@@ -442,7 +599,11 @@ impl eframe::App for YanuApp {
                                     consume_err(
                                         &dialog_modal,
                                         response,
-                                        |outdir| {
+                                        |(outdir, icons)| {
+                                            self.unpack_summary = Some(OutputSummary {
+                                                icon: icon_image(&icons),
+                                                text: format!("Unpacked to:\n'{}'", outdir.display()),
+                                            });
                                             dialog_modal.open_dialog(
                                                 None::<&str>,
                                                 Some(format!(
@@ -501,6 +662,16 @@ impl eframe::App for YanuApp {
                                                         Some(egui_modal::Icon::Success),
                                                     );
                                                 },
+                                                Converted::Nsz(nsz) => {
+                                                    dialog_modal.open_dialog(
+                                                        None::<&str>,
+                                                        Some(format!(
+                                                            "Converted NSZ:\n- \"{}\"",
+                                                            nsz.path.file_name().unwrap_or_default().to_string_lossy()
+                                                        )),
+                                                        Some(egui_modal::Icon::Success),
+                                                    );
+                                                },
                                             }
                                         }
                                     );
@@ -545,6 +716,7 @@ fn show_top_bar(
                                         .ok_or_else(|| eyre::eyre!("No Keyfile was picked"))?;
                                     info!(?keyfile_path, "Picked keyfile");
                                     assert!(keyfile_path.is_file());
+                                    Keyset::try_new(&keyfile_path)?;
 
                                     let dest = DEFAULT_PRODKEYS_PATH.as_path();
                                     fs::create_dir_all(
@@ -626,26 +798,6 @@ fn show_top_bar(
                         })
                         .response
                         .on_hover_text("Temp dirs will be created in this folder");
-                        ui.menu_button("NSP Extractor", |ui| {
-                            ui.radio_value(
-                                &mut config.nsp_extractor,
-                                NspExtractor::Hactool,
-                                "Hactool",
-                            );
-                            ui.radio_value(
-                                &mut config.nsp_extractor,
-                                NspExtractor::Hactoolnet,
-                                "Hactoolnet",
-                            );
-                        });
-                        ui.menu_button("NCA Extractor", |ui| {
-                            ui.radio_value(&mut config.nca_extractor, NcaExtractor::Hac2l, "Hac2l");
-                            ui.radio_value(
-                                &mut config.nca_extractor,
-                                NcaExtractor::Hactoolnet,
-                                "Hactoolnet",
-                            );
-                        });
                     });
                 });
             });
@@ -666,7 +818,7 @@ fn show_top_bar(
 impl YanuApp {
     fn do_update(&mut self, dialog_modal: &Modal) {
         if let Err(err) = || -> Result<()> {
-            check_keyfile_exists()?;
+            validate_keyfile()?;
 
             self.config.clone().store()?;
             self.timer = Some(Instant::now());
@@ -684,16 +836,22 @@ impl YanuApp {
 
             let base_pkg_path = self.base_pkg_path_buf.clone();
             let update_pkg_path = self.update_pkg_path_buf.clone();
+            let dlc_pkg_paths = self.dlc_pkg_paths.clone();
 
             let config = self.config.clone();
             let tx = self.channel.tx.clone();
             thread::spawn(move || {
-                tx.send(Message::Update(|| -> Result<Nsp> {
+                tx.send(Message::Update(|| -> Result<(Nsp, Vec<Icon>)> {
                     let (mut patched, nacp_data, program_id) = update_nsp(
                         &mut Nsp::try_new(base_pkg_path)?,
                         &mut Nsp::try_new(update_pkg_path)?,
+                        &mut dlc_pkg_paths
+                            .iter()
+                            .map(Nsp::try_new)
+                            .collect::<Result<Vec<_>>>()?,
                         program_id.as_deref(),
                         default_pack_outdir()?,
+                        None,
                         &config,
                     )?;
                     formatted_nsp_rename(
@@ -702,7 +860,8 @@ impl YanuApp {
                         &program_id,
                         concat!("[yanu-", env!("CARGO_PKG_VERSION"), "-patched]"),
                     )?;
-                    Ok(patched)
+                    let icons = read_icons(&patched, &config.temp_dir);
+                    Ok((patched, icons))
                 }()))
                 .unwrap();
             });
@@ -716,7 +875,7 @@ impl YanuApp {
     }
     fn do_unpack(&mut self, dialog_modal: &Modal) {
         if let Err(err) = || -> Result<()> {
-            check_keyfile_exists()?;
+            validate_keyfile()?;
 
             self.config.clone().store()?;
             self.timer = Some(Instant::now());
@@ -745,14 +904,13 @@ impl YanuApp {
             let config = self.config.clone();
             let tx = self.channel.tx.clone();
             thread::spawn(move || {
-                tx.send(Message::Unpack(|| -> Result<PathBuf> {
-                    unpack_nsp(
-                        &mut Nsp::try_new(base_pkg_path)?,
-                        update_pkg_path.and_then(|f| Nsp::try_new(f).ok()).as_mut(),
-                        &outdir,
-                        &config,
-                    )?;
-                    Ok(outdir)
+                tx.send(Message::Unpack(|| -> Result<(PathBuf, Vec<Icon>)> {
+                    let mut base = Nsp::try_new(base_pkg_path)?;
+                    let mut update = update_pkg_path.and_then(|f| Nsp::try_new(f).ok());
+                    unpack_nsp(&mut base, update.as_mut(), 0, &outdir, &config)?;
+                    // Update has the newer icons
+                    let icons = read_icons(update.as_ref().unwrap_or(&base), &config.temp_dir);
+                    Ok((outdir, icons))
                 }()))
                 .unwrap();
             });
@@ -766,7 +924,7 @@ impl YanuApp {
     }
     fn do_pack(&mut self, dialog_modal: &Modal) {
         if let Err(err) = || -> Result<()> {
-            check_keyfile_exists()?;
+            validate_keyfile()?;
 
             self.config.clone().store()?;
             self.timer = Some(Instant::now());
@@ -797,6 +955,7 @@ impl YanuApp {
                         romfs_dir,
                         exefs_dir,
                         outdir,
+                        None,
                         &config,
                     )?;
                     formatted_nsp_rename(
@@ -819,7 +978,7 @@ impl YanuApp {
     }
     fn do_convert(&mut self, dialog_modal: &Modal) {
         if let Err(err) = || -> Result<()> {
-            check_keyfile_exists()?;
+            validate_keyfile()?;
 
             self.timer = Some(Instant::now());
 
@@ -851,9 +1010,22 @@ impl YanuApp {
                 tx.send(Message::Convert(|| -> Result<Converted> {
                     let converted = match convert_kind {
                         ConvertKind::Nsp => match source_path.extension() {
-                            Some(ext) if ext == "xci" => {
+                            Some(ext) if ext == "xci" || ext == "xcz" => {
                                 Converted::Nsp(xci_to_nsps(source_path, outdir, tempdir_in)?)
                             }
+                            Some(ext) if ext == "nsz" => {
+                                Converted::Nsp(vec![nsz_to_nsp(source_path, outdir, tempdir_in)?])
+                            }
+                            Some(_) => bail!("Need to implement"),
+                            None => bail!("Non Unicode in the path"),
+                        },
+                        ConvertKind::Nsz => match source_path.extension() {
+                            Some(ext) if ext == "nsp" => Converted::Nsz(nsp_to_nsz(
+                                source_path,
+                                outdir,
+                                tempdir_in,
+                                &NczOptions::default(),
+                            )?),
                             Some(_) => bail!("Need to implement"),
                             None => bail!("Non Unicode in the path"),
                         },
@@ -871,3 +1043,14 @@ impl YanuApp {
         }
     }
 }
+
+/// Icons of the `nsp`, these are only for show so errors are just logged.
+fn read_icons(nsp: &Nsp, tempdir_in: &Path) -> Vec<Icon> {
+    match Keyset::from_default_path().and_then(|keyset| nsp.icons(&keyset, tempdir_in)) {
+        Ok(icons) => icons,
+        Err(err) => {
+            warn!(%err, "Failed to read icons");
+            vec![]
+        }
+    }
+}
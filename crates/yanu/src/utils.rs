@@ -1,8 +1,16 @@
-use common::{defines::DEFAULT_PRODKEYS_PATH, utils::get_fmt_size};
+use common::utils::{ext_matches, get_fmt_size};
+use egui_extras::RetainedImage;
 use egui_modal::Modal;
 use eyre::{bail, Result};
-use std::path::PathBuf;
-use tracing::info;
+use hac::{
+    keys::Keyset,
+    vfs::{
+        nacp::{Icon, Language},
+        nsp::Nsp,
+    },
+};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
 
 pub fn default_pack_outdir() -> Result<PathBuf> {
     let outdir: PathBuf = {
@@ -21,12 +29,10 @@ pub fn default_pack_outdir() -> Result<PathBuf> {
     Ok(outdir)
 }
 
-pub fn check_keyfile_exists() -> Result<()> {
-    if DEFAULT_PRODKEYS_PATH.is_file() {
-        Ok(())
-    } else {
-        bail!("'prod.keys' Keyfile not found, it's required")
-    }
+/// Parses the imported keyfile, so any missing/malformed keys are reported upfront.
+pub fn validate_keyfile() -> Result<()> {
+    Keyset::from_default_path()?;
+    Ok(())
 }
 
 /// Consumes the `Err` and shows an Error dialog.
@@ -52,7 +58,7 @@ pub fn consume_err_or<T>(
 }
 
 pub fn pick_nsp_file(dialog_modal: &Modal, title: Option<&str>, on_success: impl FnOnce(PathBuf)) {
-    let mut dialog = rfd::FileDialog::new().add_filter("NSP", &["nsp"]);
+    let mut dialog = rfd::FileDialog::new().add_filter("NSP", &["nsp", "nsz"]);
     if let Some(title) = title {
         dialog = dialog.set_title(title);
     }
@@ -66,3 +72,45 @@ pub fn pick_nsp_file(dialog_modal: &Modal, title: Option<&str>, on_success: impl
         },
     );
 }
+
+pub fn pick_nsp_files(
+    dialog_modal: &Modal,
+    title: Option<&str>,
+    on_success: impl FnOnce(Vec<PathBuf>),
+) {
+    let mut dialog = rfd::FileDialog::new().add_filter("NSP", &["nsp", "nsz"]);
+    if let Some(title) = title {
+        dialog = dialog.set_title(title);
+    }
+    consume_err_or(
+        "No file was picked",
+        dialog_modal,
+        dialog.pick_files(),
+        |paths| {
+            info!(?paths, "Picked files");
+            on_success(paths)
+        },
+    );
+}
+
+/// Whether `path` is a package that icons can be read from.
+pub fn is_package(path: &Path) -> bool {
+    path.is_file() && (ext_matches(path, "nsp") || ext_matches(path, "nsz"))
+}
+
+/// Reads the icons of the package at `path`, this extracts its Control NCA so it's slow.
+pub fn read_package_icons(path: &Path, tempdir_in: &Path) -> Result<Vec<Icon>> {
+    Nsp::try_new(path)?.icons(&Keyset::from_default_path()?, tempdir_in)
+}
+
+/// Decodes the AmericanEnglish icon, or whichever there is.
+pub fn icon_image(icons: &[Icon]) -> Option<RetainedImage> {
+    let icon = Icon::pick(icons, Language::AmericanEnglish)?;
+    match RetainedImage::from_image_bytes(Icon::filename(icon.language), &icon.data) {
+        Ok(image) => Some(image),
+        Err(err) => {
+            warn!(%err, "Failed to decode icon");
+            None
+        }
+    }
+}